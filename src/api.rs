@@ -1,16 +1,24 @@
 use axum::{
-    extract::{Query, State},
-    routing::get,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Json, Router,
 };
 use crate::config::Config;
-use crate::model::StringInterner;
-use rstar::{RTree, AABB, primitives::Line, PointDistance};
+use crate::geometry::WayIndex;
+use crate::model::{unpack_kv, DatasetCounts, RelationTable, StringInterner, WayRelations};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use flatgeobuf::{FgbWriter, GeometryType};
+use futures_util::StreamExt;
+use rstar::{Envelope, RTree, AABB, primitives::Line, PointDistance};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::net::SocketAddr;
 use std::collections::HashMap;
-use tracing::info;
+use rayon::prelude::*;
+use tower_http::compression::{predicate::{DefaultPredicate, Predicate}, CompressionLayer};
+use tracing::{info, warn};
 
 #[derive(Clone)]
 struct TagSetsHandle(Arc<crate::model::FlatTagSets>);
@@ -26,6 +34,21 @@ struct AppState {
     owned_elements: Option<Arc<Vec<crate::model::Element>>>,
     tag_sets: TagSetsHandle,
     interner: Arc<StringInterner>,
+    counts: DatasetCounts,
+    ways: Arc<WayIndex>,
+    relations: Arc<RelationTable>,
+    /// Way id -> parent relation ids (`include_relations`)
+    way_relations: Arc<WayRelations>,
+    /// Dataset version (`X-Dataset-Version`, `ETag`, `since`): `CacheData::source_hash` mixed
+    /// with the `[server]` settings that shape response bodies (see `dataset_version`)
+    version: u64,
+    /// Elements by H3 cell (`[runtime] h3_resolution`)
+    h3: Option<Arc<crate::geometry::H3Index>>,
+    /// `[server] response_tag_allowlist` resolved to key ids at startup
+    tag_allowlist: Option<Arc<std::collections::HashSet<u32>>>,
+    /// `[lat, lon]` bounding box of all elements, computed once at startup (`None` when empty)
+    coverage: Option<AABB<[f32; 2]>>,
+    config: Arc<Config>,
 }
 
 #[derive(Clone)]
@@ -63,16 +86,322 @@ impl rstar::PointDistance for SpatialElement {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 pub struct QueryParams {
     lat: f64,
     lon: f64,
     radius: f64, 
+    /// Optional tag key whose value is reported as `class` on each result (e.g. `amenity`)
+    classify: Option<String>,
+    /// Response encoding: `json` (default), `protobuf` (see `proto/query.proto`), `fgb` (FlatGeobuf)
+    /// `polyline` (`{id, polyline, tags}` with Google encoded polylines; use `mode=ways` for
+    /// full way geometry) or `msgpack` (the JSON response as MessagePack). Without it,
+    /// `Accept: application/msgpack` selects `msgpack`.
+    format: Option<String>,
+    /// `segments` (default) returns every matched segment; `ways` returns one result per way
+    /// with its full stitched geometry and total length
+    mode: Option<String>,
+    /// `centroid` returns one point per way at the mean of its vertices instead of segments
+    representation: Option<String>,
+    /// `mbr` returns one result per way with the bounding rectangle of its matched segments as
+    /// `mbr: [south, west, north, east]` (also the `lat1/lon1`, `lat2/lon2` corners). Combine
+    /// with `expand_ways=true` for the whole way's rectangle. JSON only.
+    geometry: Option<String>,
+    /// Return tags as unresolved `[key_id, value_id]` pairs (resolve via `/api/interner`)
+    #[serde(default)]
+    raw_tags: bool,
+    /// Return `tags` as an ordered `[[key, value], ...]` array in stored order
+    #[serde(default)]
+    tags_as_pairs: bool,
+    /// Return `;`-separated tag values as arrays. Only changes how the response presents
+    /// values; stored data is untouched.
+    #[serde(default)]
+    split_multi: bool,
+    /// Emit each distinct tag map once in a top-level `tag_sets` array and reference it from
+    /// elements by `tag_set_index` (JSON only)
+    #[serde(default)]
+    compact_tags: bool,
+    /// Round reported coordinates to this many decimal places (default: full precision)
+    coord_dp: Option<u32>,
+    /// Output coordinates: `4326` (lat/lon, default) or `3857` (Web Mercator metres; `lat*`
+    /// fields then hold y and `lon*` fields x)
+    srs: Option<String>,
+    /// Truncate each returned tag value to at most this many bytes, marked with a trailing `…`
+    max_value_len: Option<usize>,
+    /// Geometry thresholds evaluated on the stitched way (nodes never match these)
+    min_length_m: Option<f64>,
+    max_length_m: Option<f64>,
+    /// Keep only closed ways (`true`) or only open ways (`false`)
+    is_area: Option<bool>,
+    min_area_m2: Option<f64>,
+    max_area_m2: Option<f64>,
+    /// Numeric range filters: comma-separated `key:min..max` (either bound may be omitted)
+    tag_range: Option<String>,
+    /// Comma-separated keys; keep elements carrying at least one of them
+    has_any: Option<String>,
+    /// Value prefix filters: comma-separated `key:prefix` (case-sensitive)
+    tag_prefix: Option<String>,
+    /// Value substring filters: comma-separated `key:substring` (case-sensitive)
+    tag_contains: Option<String>,
+    /// Also return a value histogram for this key over every match in the radius (JSON only).
+    /// Facets count the full filtered match set, while `elements` may be limited or grouped.
+    with_facets: Option<String>,
+    /// Tag key whose distinct values form groups (used with `nearest_per_group`)
+    group_by: Option<String>,
+    /// Return only the closest element for each distinct `group_by` value
+    #[serde(default)]
+    nearest_per_group: bool,
+    /// Attach the ids of the relations each way belongs to as `relations` (details via
+    /// `/api/relation/:id/members`)
+    #[serde(default)]
+    include_relations: bool,
+    /// Add every segment of each matched way, including those outside the radius. Can grow the
+    /// result set substantially for long ways.
+    #[serde(default)]
+    expand_ways: bool,
+    /// For way results, report where the query point projects onto the matched segment
+    /// (`along`, 0.0-1.0) and along the whole way (`along_way`)
+    #[serde(default)]
+    along: bool,
+    /// Return per-cell clusters of this size in metres (`{center_lat, center_lon, count,
+    /// dominant_tag}`) instead of individual elements (JSON only)
+    cluster: Option<f64>,
+    /// Near-duplicate collapse: results sharing a `collapse_key` value (default: their primary
+    /// `key=value`) within this many metres of a better-ranked one are dropped and counted in
+    /// its `merged_count`
+    collapse_within: Option<f64>,
+    /// Tag key whose value must match for `collapse_within` (elements without it never collapse)
+    collapse_key: Option<String>,
+    /// Return `{"groups": {"highway": [...], "amenity": [...]}}` keyed by each element's first
+    /// primary key instead of one mixed `elements` list (JSON only)
+    #[serde(default)]
+    group_by_primary: bool,
+    /// Ranking weights per element type, comma-separated `type:multiplier` (`node`, `way`). A
+    /// distance is multiplied before sorting, so `node:0.8` ranks a node 100 m away like a way
+    /// 80 m away. Only ordering changes; the radius still uses true distances. Default: no bias.
+    type_bias: Option<String>,
+    /// Rank by a combined score instead of raw distance: `key=value:weight,value:weight,...`
+    /// (e.g. `amenity=restaurant:2,fast_food:1`). The score is the distance in metres (times
+    /// `type_bias`) divided by the weight of the element's value, lower ranks first.
+    score: Option<String>,
+    /// Weight of elements without a weighted value under `score` (default 1.0)
+    score_default: Option<f64>,
+    /// Keep a reproducible pseudo-random fraction (0.0-1.0) of the matched elements; a way's
+    /// segments are kept or dropped together. Facets still count every match.
+    sample: Option<f64>,
+    /// Page size (at least 1); enables cursor pagination ordered by element id instead of distance
+    limit: Option<usize>,
+    /// Opaque `next_cursor` from the previous page
+    cursor: Option<String>,
+    /// Dataset version (`X-Dataset-Version`) the client already has; answered with 304 when current
+    since: Option<String>,
+    /// Force the `rtree` or linear `scan` lookup path (benchmarking; requires `debug_endpoints`)
+    engine: Option<String>,
+}
+
+impl QueryParams {
+    fn has_area_filter(&self) -> bool {
+        self.min_area_m2.is_some() || self.max_area_m2.is_some()
+    }
+
+    fn has_geometry_filter(&self) -> bool {
+        self.min_length_m.is_some() || self.max_length_m.is_some() || self.is_area.is_some() || self.has_area_filter()
+    }
+
+    /// Evaluate length/area thresholds against a way's full geometry.
+    fn geometry_matches(&self, vertices: &[[f32; 2]]) -> bool {
+        let closed = crate::geometry::is_closed(vertices);
+        if let Some(is_area) = self.is_area {
+            if is_area != closed {
+                return false;
+            }
+        }
+        if self.min_length_m.is_some() || self.max_length_m.is_some() {
+            let length = crate::geometry::polyline_length_m(vertices);
+            if self.min_length_m.is_some_and(|min| length < min) || self.max_length_m.is_some_and(|max| length > max) {
+                return false;
+            }
+        }
+        if self.has_area_filter() {
+            // area thresholds only apply to area-classified (closed) ways
+            if !closed {
+                return false;
+            }
+            let area = crate::geometry::ring_area_m2(vertices);
+            if self.min_area_m2.is_some_and(|min| area < min) || self.max_area_m2.is_some_and(|max| area > max) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Query results; `E` is `OverpassElement` under `[server] overpass_compat`
+#[derive(Serialize)]
+pub struct QueryResponse<E = ResultElement> {
+    elements: Vec<E>,
+    /// Pass as `cursor` to fetch the next page (paginated queries; absent on the last page)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    /// `with_facets` histogram: tag value -> number of distinct matching elements
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<HashMap<String, usize>>,
+    /// Distinct tag maps referenced by `tag_set_index` (`compact_tags=true`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag_sets: Option<Vec<HashMap<String, String>>>,
+}
+
+/// Result shape under `[server] overpass_compat`: points (nodes, centroids) carry `lat`/`lon`,
+/// ways a `geometry` array of `{lat, lon}` (the full way with `mode=ways`, else the segment) and
+/// `geometry=mbr` results Overpass-style `bounds`. The other `ResultElement` fields keep their names.
+#[derive(Serialize)]
+pub struct OverpassElement<'a> {
+    #[serde(rename = "type")]
+    element_type: &'a str,
+    id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lon: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geometry: Option<Vec<OverpassPoint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bounds: Option<OverpassBounds>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class: Option<&'a Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length_m: Option<f64>,
+    #[serde(flatten)]
+    hoisted: &'a HashMap<String, String>,
+    #[serde(skip_serializing_if = "no_tags")]
+    tags: &'a HashMap<String, String>,
+    #[serde(rename = "tags", skip_serializing_if = "Option::is_none")]
+    tag_pairs: Option<&'a Vec<[String; 2]>>,
+    #[serde(rename = "tags", skip_serializing_if = "Option::is_none")]
+    multi_tags: Option<&'a HashMap<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_tags: Option<&'a Vec<[u32; 2]>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relations: Option<&'a Vec<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag_set_index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merged_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    along: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    along_way: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct OverpassPoint {
+    lat: f64,
+    lon: f64,
+}
+
+#[derive(Serialize)]
+pub struct OverpassBounds {
+    minlat: f64,
+    minlon: f64,
+    maxlat: f64,
+    maxlon: f64,
+}
+
+fn no_tags(tags: &&HashMap<String, String>) -> bool {
+    tags.is_empty()
+}
+
+impl<'a> From<&'a ResultElement> for OverpassElement<'a> {
+    fn from(r: &'a ResultElement) -> Self {
+        let point = r.mbr.is_none() && r.geometry.is_none() && r.lat1 == r.lat2 && r.lon1 == r.lon2;
+        let geometry = (!point && r.mbr.is_none()).then(|| match &r.geometry {
+            Some(vertices) => vertices.iter().map(|&[lat, lon]| OverpassPoint { lat, lon }).collect(),
+            None => vec![OverpassPoint { lat: r.lat1, lon: r.lon1 }, OverpassPoint { lat: r.lat2, lon: r.lon2 }],
+        });
+        OverpassElement {
+            element_type: &r.element_type,
+            id: r.id,
+            lat: point.then_some(r.lat1),
+            lon: point.then_some(r.lon1),
+            geometry,
+            bounds: r.mbr.map(|[minlat, minlon, maxlat, maxlon]| OverpassBounds { minlat, minlon, maxlat, maxlon }),
+            class: r.class.as_ref(),
+            length_m: r.length_m,
+            hoisted: &r.hoisted,
+            tags: &r.tags,
+            tag_pairs: r.tag_pairs.as_ref(),
+            multi_tags: r.multi_tags.as_ref(),
+            raw_tags: r.raw_tags.as_ref(),
+            relations: r.relations.as_ref(),
+            tag_set_index: r.tag_set_index,
+            merged_count: r.merged_count,
+            along: r.along,
+            along_way: r.along_way,
+            score: r.score,
+        }
+    }
+}
+
+/// `format=polyline` result: the element's geometry as a Google encoded polyline
+#[derive(Serialize)]
+pub struct PolylineElement<'a> {
+    id: u64,
+    polyline: String,
+    tags: &'a HashMap<String, String>,
+}
+
+impl<'a> From<&'a ResultElement> for PolylineElement<'a> {
+    fn from(r: &'a ResultElement) -> Self {
+        let vertices = match &r.geometry {
+            Some(vertices) => vertices.clone(),
+            // nodes (and way centroids) encode a single point
+            None if r.lat1 == r.lat2 && r.lon1 == r.lon2 => vec![[r.lat1, r.lon1]],
+            None => vec![[r.lat1, r.lon1], [r.lat2, r.lon2]],
+        };
+        PolylineElement { id: r.id, polyline: crate::geometry::encode_polyline(&vertices), tags: &r.tags }
+    }
 }
 
 #[derive(Serialize)]
-pub struct QueryResponse {
-    elements: Vec<ResultElement>,
+pub struct StatsResponse {
+    #[serde(flatten)]
+    counts: DatasetCounts,
+    tag_sets: usize,
+    strings: usize,
+    #[serde(flatten)]
+    pools: PoolSizes,
+}
+
+/// Memory held by semantic data rather than geometry
+#[derive(Serialize, Clone, Copy)]
+pub struct PoolSizes {
+    /// Bytes of the interner's concatenated string pool
+    interner_pool_bytes: usize,
+    /// Bytes of `FlatTagSets.data` (packed tag pairs)
+    tag_set_data_bytes: usize,
+}
+
+impl PoolSizes {
+    fn measure(state: &AppState) -> Self {
+        PoolSizes {
+            interner_pool_bytes: state.interner.pool.read().as_str().len(),
+            tag_set_data_bytes: state.tag_sets.0.data.len() * std::mem::size_of::<u64>(),
+        }
+    }
+
+    /// Log both sizes, as a warning when together they exceed `warn_mb` (0 = never)
+    fn log(&self, warn_mb: u64) {
+        const MB: f64 = 1024.0 * 1024.0;
+        let total = (self.interner_pool_bytes + self.tag_set_data_bytes) as u64;
+        if warn_mb > 0 && total > warn_mb * 1024 * 1024 {
+            warn!("!!! Semantic data uses {:.1} MB (interner pool {:.1} MB, tag-set data {:.1} MB), above pool_warn_mb = {} !!!",
+                total as f64 / MB, self.interner_pool_bytes as f64 / MB, self.tag_set_data_bytes as f64 / MB, warn_mb);
+        } else {
+            info!("Interner pool: {:.1} MB, tag-set data: {:.1} MB", self.interner_pool_bytes as f64 / MB, self.tag_set_data_bytes as f64 / MB);
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -84,14 +413,301 @@ pub struct ResultElement {
     lon2: f64,
     #[serde(rename = "type")]
     element_type: String,
+    /// Value of the `classify` tag key; omitted unless `classify` was requested, null if absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    class: Option<Option<String>>,
+    /// Full way geometry as `[lat, lon]` vertices (`mode=ways`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geometry: Option<Vec<[f64; 2]>>,
+    /// Total way length in metres (`mode=ways`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length_m: Option<f64>,
+    /// Tags listed in `[server] hoist_keys`, serialized as top-level fields
+    #[serde(flatten)]
+    hoisted: HashMap<String, String>,
+    /// Resolved tags (omitted when `raw_tags=true`)
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    tags: HashMap<String, String>,
+    /// Resolved tags in stored order (`tags_as_pairs=true`); replaces the `tags` object
+    #[serde(rename = "tags", skip_serializing_if = "Option::is_none")]
+    tag_pairs: Option<Vec<[String; 2]>>,
+    /// Tags with `;`-separated values as arrays (`split_multi=true`); replaces the `tags` object
+    #[serde(rename = "tags", skip_serializing_if = "Option::is_none")]
+    multi_tags: Option<HashMap<String, serde_json::Value>>,
+    /// Packed tag set as `[key_id, value_id]` interner ids (`raw_tags=true`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_tags: Option<Vec<[u32; 2]>>,
+    /// Ids of relations this way is a member of (`include_relations=true`, ways only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relations: Option<Vec<u64>>,
+    /// Index into the response's `tag_sets` (`compact_tags=true`); replaces `tags`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag_set_index: Option<usize>,
+    /// Elements this result stands for, itself included (`collapse_within`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merged_count: Option<usize>,
+    /// Fraction along the matched segment of the query point's projection (`along=true`, ways)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    along: Option<f64>,
+    /// The same position as a fraction of the whole way's length (`along=true`, when the way's
+    /// full geometry is loaded)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    along_way: Option<f64>,
+    /// `[south, west, north, east]` of the way's matched segments (`geometry=mbr`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mbr: Option<[f64; 4]>,
+    /// Combined ranking score of the element's best segment (`score`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f64>,
+    #[serde(skip)]
+    tag_set_id: u32,
+}
+
+impl ResultElement {
+    /// Plain result for a candidate; optional presentation fields start out unset.
+    fn new(c: &Candidate, tags: HashMap<String, String>) -> Self {
+        ResultElement {
+            id: c.id,
+            lat1: c.p1[0] as f64,
+            lon1: c.p1[1] as f64,
+            lat2: c.p2[0] as f64,
+            lon2: c.p2[1] as f64,
+            element_type: if c.p1 == c.p2 { "node" } else { "way" }.to_string(),
+            class: None,
+            geometry: None,
+            length_m: None,
+            hoisted: HashMap::new(),
+            tags,
+            tag_pairs: None,
+            multi_tags: None,
+            raw_tags: None,
+            relations: None,
+            tag_set_index: None,
+            merged_count: None,
+            along: None,
+            along_way: None,
+            mbr: None,
+            score: None,
+            tag_set_id: c.tag_set_id,
+        }
+    }
+
+    /// Replace the segment endpoints with a way's full stitched geometry and total length.
+    fn set_way_geometry(&mut self, vertices: &[[f32; 2]]) {
+        let (first, last) = (vertices[0], vertices[vertices.len() - 1]);
+        self.lat1 = first[0] as f64;
+        self.lon1 = first[1] as f64;
+        self.lat2 = last[0] as f64;
+        self.lon2 = last[1] as f64;
+        self.length_m = Some(crate::geometry::polyline_length_m(vertices));
+        self.geometry = Some(vertices.iter().map(|v| [v[0] as f64, v[1] as f64]).collect());
+    }
+
+    /// Collapse the result onto a single point (`representation=centroid`); the type is kept.
+    fn set_point(&mut self, point: [f32; 2]) {
+        self.lat1 = point[0] as f64;
+        self.lon1 = point[1] as f64;
+        self.lat2 = self.lat1;
+        self.lon2 = self.lon1;
+    }
+
+    /// Span the result over a bounding rectangle (`geometry=mbr`): `lat1/lon1` becomes the
+    /// south-west corner and `lat2/lon2` the north-east corner.
+    fn set_mbr(&mut self, min: [f32; 2], max: [f32; 2]) {
+        self.lat1 = min[0] as f64;
+        self.lon1 = min[1] as f64;
+        self.lat2 = max[0] as f64;
+        self.lon2 = max[1] as f64;
+        self.mbr = Some([self.lat1, self.lon1, self.lat2, self.lon2]);
+    }
+
+    /// Reproject endpoints and geometry to EPSG:3857 (`srs=3857`): `lat*` becomes y, `lon*` x.
+    fn project_web_mercator(&mut self) {
+        let project = |lat: &mut f64, lon: &mut f64| {
+            let (x, y) = crate::geometry::to_web_mercator(*lat, *lon);
+            (*lat, *lon) = (y, x);
+        };
+        project(&mut self.lat1, &mut self.lon1);
+        project(&mut self.lat2, &mut self.lon2);
+        for vertex in self.geometry.iter_mut().flatten() {
+            let [lat, lon] = vertex;
+            project(lat, lon);
+        }
+    }
+
+    /// Round endpoints and geometry to `dp` decimal places (`coord_dp`).
+    fn round_coords(&mut self, dp: Option<u32>) {
+        if dp.is_none() {
+            return;
+        }
+        for v in [&mut self.lat1, &mut self.lon1, &mut self.lat2, &mut self.lon2] {
+            *v = round_dp(*v, dp);
+        }
+        for vertex in self.geometry.iter_mut().flatten() {
+            *vertex = [round_dp(vertex[0], dp), round_dp(vertex[1], dp)];
+        }
+    }
+
+    /// Turn `tags` into `multi_tags`: values holding a `;`-separated list (OSM's multi-value
+    /// convention, e.g. `cuisine=italian;pizza`) become arrays, other values stay strings.
+    fn split_multi_values(&mut self) {
+        let tags = std::mem::take(&mut self.tags);
+        self.multi_tags = Some(tags.into_iter().map(|(k, v)| {
+            let value = if v.contains(';') {
+                serde_json::Value::from(split_multi_value(&v).collect::<Vec<_>>())
+            } else {
+                serde_json::Value::from(v)
+            };
+            (k, value)
+        }).collect());
+    }
+
+    /// Cut every tag value (including hoisted and `class` values) to `max_value_len` bytes.
+    fn truncate_values(&mut self, max: Option<usize>) {
+        let Some(max) = max else { return };
+        let values = self.tags.values_mut()
+            .chain(self.hoisted.values_mut())
+            .chain(self.tag_pairs.iter_mut().flatten().map(|[_, v]| v))
+            .chain(self.class.iter_mut().flatten());
+        for value in values {
+            truncate_value(value, max);
+        }
+    }
+}
+
+/// Items of a `;`-separated OSM multi-value, trimmed, empty items skipped.
+fn split_multi_value(value: &str) -> impl Iterator<Item = &str> {
+    value.split(';').map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// Marker appended to values shortened by `max_value_len`
+const TRUNCATION_MARKER: &str = "…";
+
+/// Truncate to at most `max` bytes on a char boundary, appending `TRUNCATION_MARKER` if cut.
+fn truncate_value(value: &mut String, max: usize) {
+    if value.len() <= max {
+        return;
+    }
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    value.push_str(TRUNCATION_MARKER);
+}
+
+/// Round to `dp` decimal places; `None` keeps full precision.
+fn round_dp(value: f64, dp: Option<u32>) -> f64 {
+    match dp {
+        Some(dp) => {
+            let factor = 10f64.powi(dp.min(15) as i32);
+            (value * factor).round() / factor
+        }
+        None => value,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    south: f64,
+    west: f64,
+    north: f64,
+    east: f64,
+    /// `ndjson` (default), `geojson-seq` or `fgb` (FlatGeobuf, buffered)
+    format: Option<String>,
+    /// Round reported coordinates to this many decimal places (default: full precision)
+    coord_dp: Option<u32>,
+    /// Truncate each returned tag value to at most this many bytes, marked with a trailing `…`
+    max_value_len: Option<usize>,
+    /// Dataset version (`X-Dataset-Version`) the client already has; answered with 304 when current
+    since: Option<String>,
+}
+
+/// Body of `POST /api/query/multi`
+#[derive(Deserialize)]
+pub struct MultiQueryRequest {
+    /// `[south, west, north, east]` boxes; results are unioned
+    bboxes: Vec<[f64; 4]>,
+    /// Exact `key` or `key=value` filters, all of which must match
+    #[serde(default)]
+    tag: Vec<String>,
+    /// Match `key=value` filters against each item of `;`-separated values, and return such
+    /// values as arrays
+    #[serde(default)]
+    split_multi: bool,
+    /// Same syntax as the `/api/query` parameters of the same name
+    tag_range: Option<String>,
+    has_any: Option<String>,
+    tag_prefix: Option<String>,
+    tag_contains: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SnapParams {
+    lat: f64,
+    lon: f64,
+    /// Round the snapped coordinate to this many decimal places (default: full precision)
+    coord_dp: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct SnapResponse {
+    id: u64,
+    snapped_lat: f64,
+    snapped_lon: f64,
+    distance_m: f64,
     tags: HashMap<String, String>,
 }
 
+#[derive(Serialize)]
+pub struct RelationMembersResponse {
+    id: u64,
+    tags: HashMap<String, String>,
+    /// Member ways with their full geometry, in relation order
+    members: Vec<RelationMember>,
+    /// Member ways that are not part of the dataset (filtered out or outside the extract)
+    missing: Vec<u64>,
+}
+
+#[derive(Serialize)]
+pub struct RelationMember {
+    role: String,
+    #[serde(flatten)]
+    element: ResultElement,
+}
+
+#[derive(Deserialize)]
+pub struct WithinParams {
+    /// Closed way or relation id; Overpass-style area ids (2400000000 + way id,
+    /// 3600000000 + relation id) are accepted as well
+    area_id: u64,
+}
+
+#[derive(Deserialize)]
+pub struct HealthParams {
+    /// Also resolve a random sample of tag sets through the interner
+    #[serde(default)]
+    deep: bool,
+}
+
+#[derive(Serialize)]
+pub struct InternerResponse {
+    offsets: Vec<u32>,
+    lengths: Vec<u32>,
+    pool: String,
+}
+
 pub async fn start_server(
     config: Config,
     cache: crate::preprocessor::LoadedCache,
     start_time: std::time::Instant,
 ) -> anyhow::Result<()> {
+    let state = build_state(&config, cache)?;
+    run_server_with_state(config, state, start_time).await
+}
+
+/// Build the r-tree and the other in-memory indexes served by the API from a loaded cache.
+fn build_state(config: &Config, cache: crate::preprocessor::LoadedCache) -> anyhow::Result<AppState> {
     // build spatial elements + tag_sets handle + interner from the Owned cache
     // (runtime.build_rtree option has been removed; we always build the in-memory RTree at startup)
 
@@ -114,13 +730,36 @@ pub async fn start_server(
     };
 
     match cache {
-        crate::preprocessor::LoadedCache::Owned { elements, tag_sets, interner } => {
+        crate::preprocessor::LoadedCache::Owned { elements, tag_sets, interner, relations, h3_cells, source_hash } => {
             let interner_arc = Arc::new(interner);
+            let counts = DatasetCounts::from_elements(&elements);
+            let ways = Arc::new(WayIndex::build(&elements));
             let tag_sets_handle = TagSetsHandle(Arc::new(tag_sets));
 
-            info!("Building in-memory RTree for {} elements (this may use a lot of RAM)...", elements.len());
+            // bulk_load consumes the leaf vec and builds its own nodes, so both are briefly alive
+            let estimated_mb = (elements.len() * (std::mem::size_of::<SpatialElement>() + std::mem::size_of::<rstar::RTreeNode<SpatialElement>>())) as u64 / (1024 * 1024);
+            let limit_mb = config.runtime.rtree_memory_limit_mb;
+            if limit_mb > 0 && estimated_mb > limit_mb {
+                anyhow::bail!(
+                    "Building the RTree for {} elements needs an estimated {} MB, above rtree_memory_limit_mb = {}. \
+                     Reduce the dataset (fewer primary/attribute keys, clip_polygon, simplify_tolerance_m) or raise the limit.",
+                    elements.len(), estimated_mb, limit_mb
+                );
+            }
+            info!("Building in-memory RTree for {} elements (estimated peak {} MB)...", elements.len(), estimated_mb);
             if let Some(rss) = get_rss_mb() { info!("RSS before building RTree: {} MB", rss); }
 
+            // debug builds of the API keep the flat element list so `engine=scan` can be compared
+            // against the r-tree on the same data
+            let owned_elements = config.server.debug_endpoints.then(|| Arc::new(elements.clone()));
+            let h3 = match config.runtime.h3_resolution {
+                Some(resolution) if h3_cells.len() == elements.len() => {
+                    Some(Arc::new(crate::geometry::H3Index::build(&elements, &h3_cells, resolution)))
+                }
+                _ => None,
+            };
+            drop(h3_cells);
+
             let ses = elements.into_iter().map(|e| SpatialElement {
                 id: e.id,
                 tag_set_id: e.tag_set_id,
@@ -130,18 +769,95 @@ pub async fn start_server(
             if let Some(rss) = get_rss_mb() { info!("RSS after preparing SpatialElement vec: {} MB", rss); }
             let rtree = RTree::bulk_load(ses);
             if let Some(rss) = get_rss_mb() { info!("RSS after RTree::bulk_load: {} MB", rss); }
+            let coverage = (rtree.size() > 0).then(|| rtree.root().envelope());
 
-            let state = AppState { rtree: Some(Arc::new(rtree)), owned_elements: None, tag_sets: tag_sets_handle, interner: interner_arc };
-
-            run_server_with_state(config, state, start_time).await
+            // allowlisted keys missing from the string table can never appear in a tag set
+            let tag_allowlist = config.server.response_tag_allowlist.as_ref().map(|keys| {
+                Arc::new(keys.iter().filter_map(|k| interner_arc.find(k)).collect())
+            });
+            Ok(AppState { rtree: Some(Arc::new(rtree)), owned_elements, tag_sets: tag_sets_handle, interner: interner_arc, counts, ways, way_relations: Arc::new(WayRelations::build(&relations)), relations: Arc::new(relations), version: dataset_version(source_hash, &config.server), h3, tag_allowlist, coverage, config: Arc::new(config.clone()) })
         }
     }
 }
 
 async fn run_server_with_state(config: Config, state: AppState, start_time: std::time::Instant) -> anyhow::Result<()> {
-    let app = Router::new()
+    // listed by the JSON 404 fallback; keep in sync with the routes below
+    let mut routes = vec![
+        "/api/query", "/api/query/multi", "/api/query/polygon", "/api/stats", "/api/export", "/api/snap",
+        "/api/relation/:id/members", "/api/within", "/api/coverage", "/health",
+    ];
+    let mut app = Router::new()
         .route("/api/query", get(handle_query))
-        .with_state(state);
+        .route("/api/query/multi", post(handle_query_multi))
+        .route("/api/query/polygon", post(handle_query_polygon))
+        .route("/api/stats", get(handle_stats))
+        .route("/api/export", get(handle_export))
+        .route("/api/snap", get(handle_snap))
+        .route("/api/relation/:id/members", get(handle_relation_members))
+        .route("/api/within", get(handle_within))
+        .route("/api/coverage", get(handle_coverage))
+        .route("/health", get(handle_health));
+
+    if let Some(resolution) = config.runtime.h3_resolution {
+        info!("H3 endpoint enabled (/api/h3/:cell, resolution {})", resolution);
+        app = app.route("/api/h3/:cell", get(handle_h3));
+        routes.push("/api/h3/:cell");
+    }
+
+    if config.server.density_endpoint {
+        app = app.route("/api/density/:z/:x/:y", get(handle_density));
+        routes.push("/api/density/:z/:x/:y");
+    }
+
+    if config.server.debug_endpoints {
+        info!("Debug endpoints enabled (/api/interner, /api/config)");
+        app = app.route("/api/interner", get(handle_interner))
+            .route("/api/config", get(handle_config));
+        routes.extend(["/api/interner", "/api/config"]);
+    }
+
+    let routes = Arc::new(routes);
+    let app = app.fallback(move || {
+        let routes = routes.clone();
+        async move { not_found(&routes) }
+    });
+
+    let state_for_preload = state.clone();
+    let mut app = app.with_state(state)
+        .layer(axum::extract::DefaultBodyLimit::max(config.server.max_body_bytes))
+        .layer(axum::middleware::from_fn_with_state(Arc::new(config.server.clone()), cache_headers));
+
+    let idle_shutdown_secs = config.server.idle_shutdown_secs;
+    let activity = (idle_shutdown_secs > 0).then(|| Arc::new(Activity::new()));
+    if let Some(activity) = &activity {
+        app = app.layer(axum::middleware::from_fn_with_state(activity.clone(), track_activity));
+        info!("Idle shutdown after {} s without requests", idle_shutdown_secs);
+    }
+
+    if config.server.compression {
+        // JSON compresses well; already-compact binary formats are excluded via config
+        let excluded = Arc::new(config.server.compression_exclude_types.clone());
+        let predicate = DefaultPredicate::new().and(move |_: StatusCode, _: axum::http::Version, headers: &axum::http::HeaderMap, _: &axum::http::Extensions| {
+            let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+            !excluded.iter().any(|t| content_type.starts_with(t.as_str()))
+        });
+        app = app.layer(CompressionLayer::new().compress_when(predicate));
+        info!("Response compression enabled (excluding {:?})", config.server.compression_exclude_types);
+    }
+
+    preload_regions(&state_for_preload, &config.runtime.preload_regions);
+
+    let warn_mb = config.runtime.pool_warn_mb;
+    PoolSizes::measure(&state_for_preload).log(warn_mb);
+    if config.runtime.pool_log_interval_secs > 0 {
+        let interval = std::time::Duration::from_secs(config.runtime.pool_log_interval_secs);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                PoolSizes::measure(&state_for_preload).log(warn_mb);
+            }
+        });
+    }
 
     let addr_str = format!("{}:{}", config.server.host, config.server.port);
     let addr: SocketAddr = addr_str.parse()?;
@@ -153,66 +869,126 @@ async fn run_server_with_state(config: Config, state: AppState, start_time: std:
     info!("Server listening on {}", addr);
     info!("Total startup time: {:.2?}", elapsed);
 
-    axum::serve(listener, app).await?;
+    let shutdown = async move {
+        match activity {
+            Some(activity) => activity.wait_idle(idle_shutdown_secs).await,
+            None => std::future::pending().await,
+        }
+    };
+    axum::serve(listener, app).with_graceful_shutdown(shutdown).await?;
 
     Ok(())
 }
 
+/// Time of the last request, for `[server] idle_shutdown_secs`.
+struct Activity {
+    started: std::time::Instant,
+    /// Seconds since `started` at the last request
+    last_secs: std::sync::atomic::AtomicU64,
+}
 
-async fn handle_query(
-    State(state): State<AppState>,
-    Query(params): Query<QueryParams>,
-) -> Json<QueryResponse> {
-    let radius_deg = params.radius / 111320.0; 
-    let radius_deg_f32 = radius_deg as f32;
-    let query_point = [params.lat as f32, params.lon as f32];
+impl Activity {
+    fn new() -> Self {
+        Activity { started: std::time::Instant::now(), last_secs: std::sync::atomic::AtomicU64::new(0) }
+    }
 
-    // helper: squared distance from point to segment
-    fn point_segment_distance2(px: f32, py: f32, x1: f32, y1: f32, x2: f32, y2: f32) -> f32 {
-        let vx = x2 - x1;
-        let vy = y2 - y1;
-        let wx = px - x1;
-        let wy = py - y1;
-        let c1 = vx * wx + vy * wy;
-        if c1 <= 0.0 { return (px - x1).powi(2) + (py - y1).powi(2); }
-        let c2 = vx * vx + vy * vy;
-        if c2 <= c1 { return (px - x2).powi(2) + (py - y2).powi(2); }
-        let t = c1 / c2;
-        let cx = x1 + t * vx;
-        let cy = y1 + t * vy;
-        (px - cx).powi(2) + (py - cy).powi(2)
+    fn touch(&self) {
+        self.last_secs.store(self.started.elapsed().as_secs(), std::sync::atomic::Ordering::Relaxed);
     }
 
-    let mut response_elements = Vec::new();
+    /// Resolve once no request has arrived for `idle_secs`.
+    async fn wait_idle(&self, idle_secs: u64) {
+        loop {
+            let idle = self.started.elapsed().as_secs().saturating_sub(self.last_secs.load(std::sync::atomic::Ordering::Relaxed));
+            if idle >= idle_secs {
+                info!("Shutting down: no requests for {} s (idle_shutdown_secs = {})", idle, idle_secs);
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(idle_secs - idle)).await;
+        }
+    }
+}
 
-    if let Some(rtree) = &state.rtree {
-        // fast path: in-memory RTree
-        let results = rtree.locate_within_distance(query_point, radius_deg_f32 * radius_deg_f32);
-        for se in results {
-            let mut tags = HashMap::new();
-            if let Some(packed_slice) = state.tag_sets.get(se.tag_set_id as usize) {
-                for &packed in packed_slice {
-                    let kid = (packed >> 32) as u32;
-                    let vid = (packed & 0xFFFF_FFFF) as u32;
-                    if let (Some(k), Some(v)) = (state.interner.lookup(kid), state.interner.lookup(vid)) {
-                        tags.insert(k, v);
-                    }
-                }
+/// Middleware: record request activity for idle shutdown. Health probes don't count, so an
+/// orchestrator polling `/health` does not keep an otherwise idle server alive.
+async fn track_activity(State(activity): State<Arc<Activity>>, request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    if request.uri().path() != "/health" {
+        activity.touch();
+    }
+    next.run(request).await
+}
+
+
+/// A spatial match before tag resolution: squared distance (deg²), id, endpoints and tag-set id.
+struct Candidate {
+    dist2: f32,
+    id: u64,
+    p1: [f32; 2],
+    p2: [f32; 2],
+    tag_set_id: u32,
+}
+
+/// Project a point onto a segment: returns the clamped fraction `t` along the segment (0..=1)
+/// and the foot of the perpendicular (the closest point on the segment).
+fn project_onto_segment(px: f32, py: f32, x1: f32, y1: f32, x2: f32, y2: f32) -> (f32, [f32; 2]) {
+    let vx = x2 - x1;
+    let vy = y2 - y1;
+    let wx = px - x1;
+    let wy = py - y1;
+    let c1 = vx * wx + vy * wy;
+    if c1 <= 0.0 { return (0.0, [x1, y1]); }
+    let c2 = vx * vx + vy * vy;
+    if c2 <= c1 { return (1.0, [x2, y2]); }
+    let t = c1 / c2;
+    (t, [x1 + t * vx, y1 + t * vy])
+}
+
+// helper: squared distance from point to segment
+fn point_segment_distance2(px: f32, py: f32, x1: f32, y1: f32, x2: f32, y2: f32) -> f32 {
+    let (_, [cx, cy]) = project_onto_segment(px, py, x1, y1, x2, y2);
+    (px - cx).powi(2) + (py - cy).powi(2)
+}
+
+impl AppState {
+    /// Whether a packed `key<<32|value` pair may be returned under `response_tag_allowlist`.
+    fn tag_visible(&self, packed: u64) -> bool {
+        self.tag_allowlist.as_ref().is_none_or(|allowed| allowed.contains(&unpack_kv(packed).0))
+    }
+
+    /// Interner id of a key clients filter, rank, facet or group on. Keys outside
+    /// `response_tag_allowlist` are unknown here, so hidden values can't be probed via predicates.
+    fn find_key(&self, key: &str) -> Option<u32> {
+        self.interner.find(key).filter(|id| self.tag_allowlist.as_ref().is_none_or(|allowed| allowed.contains(id)))
+    }
+}
+
+/// Resolve a tag-set id into its key/value strings via the interner.
+fn resolve_tags(state: &AppState, tag_set_id: u32) -> HashMap<String, String> {
+    resolve_tag_pairs(state, tag_set_id).into_iter().map(|[k, v]| (k, v)).collect()
+}
+
+/// Resolve a tag-set id into key/value pairs, preserving the stored order.
+fn resolve_tag_pairs(state: &AppState, tag_set_id: u32) -> Vec<[String; 2]> {
+    let mut tags = Vec::new();
+    if let Some(packed_slice) = state.tag_sets.get(tag_set_id as usize) {
+        for &packed in packed_slice.iter().filter(|&&packed| state.tag_visible(packed)) {
+            let (kid, vid) = unpack_kv(packed);
+            if let (Some(k), Some(v)) = (state.interner.lookup(kid), state.interner.lookup(vid)) {
+                tags.push([k, v]);
             }
+        }
+    }
+    tags
+}
 
+/// Collect every element within `radius_deg` of `query_point` (r-tree fast path, linear scan fallback).
+fn collect_candidates(state: &AppState, query_point: [f32; 2], radius_deg: f32) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    if let Some(rtree) = &state.rtree {
+        // fast path: in-memory RTree
+        for se in rtree.locate_within_distance(query_point, radius_deg * radius_deg) {
             let (p1, p2) = se.endpoints();
-            let element_type = if p1 == p2 { "node" } else { "way" }.to_string();
-            let dist_deg_sq = se.distance_2(&query_point);
-
-            response_elements.push((dist_deg_sq, ResultElement {
-                id: se.id,
-                lat1: p1[0] as f64,
-                lon1: p1[1] as f64,
-                lat2: p2[0] as f64,
-                lon2: p2[1] as f64,
-                element_type,
-                tags,
-            }));
+            candidates.push(Candidate { dist2: se.distance_2(&query_point), id: se.id, p1, p2, tag_set_id: se.tag_set_id });
         }
     } else if let Some(owned) = &state.owned_elements {
         // fallback for Owned cache when RTree was skipped
@@ -220,26 +996,1852 @@ async fn handle_query(
             let p1 = e.coordinates[0];
             let p2 = e.coordinates[1];
             let dist2 = point_segment_distance2(query_point[0], query_point[1], p1[0], p1[1], p2[0], p2[1]);
-            if dist2 <= radius_deg_f32 * radius_deg_f32 {
-                let mut tags = HashMap::new();
-                if let Some(packed_slice) = state.tag_sets.get(e.tag_set_id as usize) {
-                    for &packed in packed_slice {
-                        let kid = (packed >> 32) as u32;
-                        let vid = (packed & 0xFFFF_FFFF) as u32;
-                        if let (Some(k), Some(v)) = (state.interner.lookup(kid), state.interner.lookup(vid)) {
-                            tags.insert(k, v);
-                        }
-                    }
-                }
-                let element_type = if p1 == p2 { "node" } else { "way" }.to_string();
-                response_elements.push((dist2, ResultElement { id: e.id, lat1: p1[0] as f64, lon1: p1[1] as f64, lat2: p2[0] as f64, lon2: p2[1] as f64, element_type, tags }));
+            if dist2 <= radius_deg * radius_deg {
+                candidates.push(Candidate { dist2, id: e.id, p1, p2, tag_set_id: e.tag_set_id });
+            }
+        }
+    }
+    candidates
+}
+
+/// One `cluster` grid cell
+#[derive(Serialize)]
+pub struct Cluster {
+    center_lat: f64,
+    center_lon: f64,
+    /// Distinct elements whose midpoint lies in the cell
+    count: usize,
+    /// Most frequent primary `key=value` in the cell (ties broken by the smaller string)
+    dominant_tag: Option<String>,
+}
+
+/// Bucket candidates into a `grid_m` grid by segment midpoint, each element counted once per
+/// cell. Longitude cells are sized at `ref_lat` (the query centre), which is accurate enough
+/// within a query radius.
+fn cluster_candidates(state: &AppState, candidates: &[Candidate], grid_m: f64, ref_lat: f64) -> Vec<Cluster> {
+    #[derive(Default)]
+    struct Cell {
+        lat_sum: f64,
+        lon_sum: f64,
+        ids: std::collections::HashSet<u64>,
+        primaries: HashMap<u64, usize>,
+    }
+    let primary_ids: std::collections::HashSet<u32> = state.config.filters.canonical_keys(&state.config.filters.primary_keys).into_iter()
+        .filter_map(|k| state.interner.find(k))
+        .collect();
+    let cell_lat = grid_m / 111_320.0;
+    let cell_lon = grid_m / (111_320.0 * ref_lat.to_radians().cos().max(1e-6));
+
+    let mut cells: HashMap<(i64, i64), Cell> = HashMap::new();
+    for c in candidates {
+        let lat = (c.p1[0] as f64 + c.p2[0] as f64) / 2.0;
+        let lon = (c.p1[1] as f64 + c.p2[1] as f64) / 2.0;
+        let cell = cells.entry(((lat / cell_lat).floor() as i64, (lon / cell_lon).floor() as i64)).or_default();
+        if !cell.ids.insert(c.id) {
+            continue;
+        }
+        cell.lat_sum += lat;
+        cell.lon_sum += lon;
+        let pairs = state.tag_sets.get(c.tag_set_id as usize).unwrap_or(&[]);
+        if let Some(&packed) = pairs.iter().find(|&&p| primary_ids.contains(&unpack_kv(p).0) && state.tag_visible(p)) {
+            *cell.primaries.entry(packed).or_default() += 1;
+        }
+    }
+
+    cells.into_values().map(|cell| {
+        let count = cell.ids.len();
+        let resolve = |packed: u64| {
+            let key = state.interner.lookup(unpack_kv(packed).0).unwrap_or_default();
+            let value = state.interner.lookup(unpack_kv(packed).1).unwrap_or_default();
+            format!("{}={}", key, value)
+        };
+        let dominant_tag = cell.primaries.into_iter()
+            .map(|(packed, n)| (n, resolve(packed)))
+            .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(&a.1)))
+            .map(|(_, tag)| tag);
+        Cluster { center_lat: cell.lat_sum / count as f64, center_lon: cell.lon_sum / count as f64, count, dominant_tag }
+    }).collect()
+}
+
+/// Drop near-duplicates (`collapse_within`): a candidate whose group value (the `key` value, or
+/// its primary `key=value`) matches an already kept element within `tolerance_m` of its midpoint
+/// is dropped together with its other segments. Candidates must be ranked, so each cluster keeps
+/// its best element. A grid of `tolerance_m` cells limits the search to 3x3 neighbouring cells.
+/// Returns kept id -> cluster size for clusters with more than one element.
+fn collapse_candidates(state: &AppState, candidates: &mut Vec<Candidate>, tolerance_m: f64, key: Option<&str>, ref_lat: f64) -> HashMap<u64, usize> {
+    let key_id = key.map(|k| state.find_key(k));
+    let primary_ids: std::collections::HashSet<u32> = state.config.filters.canonical_keys(&state.config.filters.primary_keys).into_iter()
+        .filter_map(|k| state.interner.find(k))
+        .collect();
+    let group_of = |tag_set_id: u32| -> Option<u64> {
+        let pairs = state.tag_sets.get(tag_set_id as usize).unwrap_or(&[]);
+        match key_id {
+            Some(kid) => {
+                let kid = kid?;
+                pairs.iter().find(|&&p| unpack_kv(p).0 == kid).map(|&p| u64::from(unpack_kv(p).1))
+            }
+            None => pairs.iter().find(|&&p| primary_ids.contains(&unpack_kv(p).0) && state.tag_visible(p)).copied(),
+        }
+    };
+    let lon_scale = ref_lat.to_radians().cos().max(1e-6);
+
+    let mut kept: HashMap<u64, bool> = HashMap::new();
+    // (group, row, col) -> kept (id, lat, lon) midpoints
+    type Cell = Vec<(u64, f64, f64)>;
+    let mut grid: HashMap<(u64, i64, i64), Cell> = HashMap::new();
+    let mut merged: HashMap<u64, usize> = HashMap::new();
+    candidates.retain(|c| {
+        if let Some(&keep) = kept.get(&c.id) {
+            return keep;
+        }
+        let Some(group) = group_of(c.tag_set_id) else {
+            kept.insert(c.id, true);
+            return true;
+        };
+        let y = (c.p1[0] as f64 + c.p2[0] as f64) / 2.0 * 111_320.0;
+        let x = (c.p1[1] as f64 + c.p2[1] as f64) / 2.0 * 111_320.0 * lon_scale;
+        let (cy, cx) = ((y / tolerance_m).floor() as i64, (x / tolerance_m).floor() as i64);
+        let near = (-1..=1).flat_map(|dy| (-1..=1).map(move |dx| (cy + dy, cx + dx)))
+            .filter_map(|(gy, gx)| grid.get(&(group, gy, gx)))
+            .flatten()
+            .find(|&&(_, ky, kx)| (ky - y).hypot(kx - x) <= tolerance_m)
+            .map(|&(id, _, _)| id);
+        let keep = match near {
+            Some(representative) => {
+                *merged.entry(representative).or_insert(1) += 1;
+                false
+            }
+            None => {
+                grid.entry((group, cy, cx)).or_default().push((c.id, y, x));
+                true
+            }
+        };
+        kept.insert(c.id, keep);
+        keep
+    });
+    merged
+}
+
+/// Bucket results by the first primary key in their tag set (`group_by_primary`), keeping rank
+/// order within each group. Elements without a visible primary key go to `other`.
+fn group_by_primary(state: &AppState, elements: Vec<ResultElement>) -> HashMap<String, Vec<ResultElement>> {
+    let primary_ids: std::collections::HashSet<u32> = state.config.filters.canonical_keys(&state.config.filters.primary_keys).into_iter()
+        .filter_map(|k| state.interner.find(k))
+        .collect();
+    let mut names: HashMap<u32, String> = HashMap::new();
+    let mut groups: HashMap<String, Vec<ResultElement>> = HashMap::new();
+    for e in elements {
+        let key_id = state.tag_sets.get(e.tag_set_id as usize).unwrap_or(&[]).iter()
+            .find(|&&p| primary_ids.contains(&unpack_kv(p).0) && state.tag_visible(p))
+            .map(|&p| unpack_kv(p).0);
+        let group = match key_id {
+            Some(id) => names.entry(id).or_insert_with(|| state.interner.lookup(id).unwrap_or_default()).clone(),
+            None => "other".to_string(),
+        };
+        groups.entry(group).or_default().push(e);
+    }
+    groups
+}
+
+/// Move each element's `tags` into a shared table, one entry per distinct tag set, and point the
+/// element at it (`compact_tags`). Elements with the same tag-set id resolve to identical maps.
+fn compact_tag_sets(elements: &mut [ResultElement]) -> Vec<HashMap<String, String>> {
+    let mut tag_sets = Vec::new();
+    let mut index_of: HashMap<u32, usize> = HashMap::new();
+    for e in elements {
+        let tags = std::mem::take(&mut e.tags);
+        let index = *index_of.entry(e.tag_set_id).or_insert_with(|| {
+            tag_sets.push(tags);
+            tag_sets.len() - 1
+        });
+        e.tag_set_index = Some(index);
+    }
+    tag_sets
+}
+
+/// Parse `type_bias` into `(node, way)` distance multipliers (1.0 = neutral).
+fn parse_type_bias(spec: Option<&str>) -> Result<(f32, f32), String> {
+    let (mut node, mut way) = (1.0f32, 1.0f32);
+    for part in spec.iter().flat_map(|s| s.split(',')).filter(|s| !s.is_empty()) {
+        let malformed = || format!("malformed type_bias '{}' (expected node:<multiplier> or way:<multiplier>)", part);
+        let (kind, multiplier) = part.split_once(':').ok_or_else(malformed)?;
+        let multiplier: f32 = multiplier.parse().map_err(|_| malformed())?;
+        if !(multiplier.is_finite() && multiplier > 0.0) {
+            return Err(format!("type_bias multiplier must be positive, got {}", multiplier));
+        }
+        match kind {
+            "node" => node = multiplier,
+            "way" => way = multiplier,
+            _ => return Err(malformed()),
+        }
+    }
+    Ok((node, way))
+}
+
+/// Parsed `score` weights: value id -> weight for one key.
+struct ScoreWeights {
+    key_id: Option<u32>,
+    weights: HashMap<u32, f64>,
+    default: f64,
+}
+
+impl ScoreWeights {
+    /// Parse `key=value:weight,...`; values missing from the dataset can never match and are ignored.
+    fn parse(state: &AppState, spec: &str, default: f64) -> Result<Self, String> {
+        if !(default.is_finite() && default > 0.0) {
+            return Err(format!("score_default must be positive, got {}", default));
+        }
+        let malformed = || format!("malformed score '{}' (expected key=value:weight,value:weight,...)", spec);
+        let (key, values) = spec.split_once('=').ok_or_else(malformed)?;
+        let mut weights = HashMap::new();
+        for part in values.split(',').filter(|s| !s.is_empty()) {
+            let (value, weight) = part.rsplit_once(':').ok_or_else(malformed)?;
+            let weight: f64 = weight.parse().map_err(|_| malformed())?;
+            if !(weight.is_finite() && weight > 0.0) {
+                return Err(format!("score weight must be positive, got {}", weight));
+            }
+            if let Some(value_id) = state.interner.find(value) {
+                weights.insert(value_id, weight);
+            }
+        }
+        Ok(ScoreWeights { key_id: state.find_key(key), weights, default })
+    }
+
+    fn weight(&self, state: &AppState, tag_set_id: u32) -> f64 {
+        let Some(key_id) = self.key_id else { return self.default };
+        state.tag_sets.get(tag_set_id as usize).unwrap_or(&[]).iter()
+            .find(|&&packed| unpack_kv(packed).0 == key_id)
+            .and_then(|&packed| self.weights.get(&unpack_kv(packed).1).copied())
+            .unwrap_or(self.default)
+    }
+}
+
+/// Fixed seed for `sample`, so the same query always returns the same subset
+const SAMPLE_SEED: u64 = 0x5eed_0f05_a3b1_e5d1;
+
+/// Whether element `id` is in the `sample` subset: the id is mixed with `SAMPLE_SEED` through
+/// SplitMix64 (stable across builds and platforms) and kept when the hash falls below `fraction`.
+fn sample_keeps(id: u64, fraction: f64) -> bool {
+    let mut z = id ^ SAMPLE_SEED;
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    ((z >> 11) as f64 / (1u64 << 53) as f64) < fraction
+}
+
+/// Histogram of `key`'s values over the candidates, counting each element id once.
+fn facet_counts(state: &AppState, candidates: &[Candidate], key: &str) -> HashMap<String, usize> {
+    let Some(key_id) = state.find_key(key) else {
+        return HashMap::new();
+    };
+    let mut seen = std::collections::HashSet::new();
+    let mut by_value: HashMap<u32, usize> = HashMap::new();
+    for c in candidates {
+        if !seen.insert(c.id) {
+            continue;
+        }
+        let pairs = state.tag_sets.get(c.tag_set_id as usize).unwrap_or(&[]);
+        if let Some(&packed) = pairs.iter().find(|&&packed| unpack_kv(packed).0 == key_id && state.tag_visible(packed)) {
+            *by_value.entry(unpack_kv(packed).1).or_default() += 1;
+        }
+    }
+    by_value.into_iter()
+        .filter_map(|(value_id, count)| state.interner.lookup(value_id).map(|v| (v, count)))
+        .collect()
+}
+
+/// Append the segments of every matched way that are not already candidates (`expand_ways`),
+/// taken from the way index.
+fn expand_way_segments(state: &AppState, candidates: &mut Vec<Candidate>, query_point: [f32; 2]) {
+    let key = |id: u64, p1: [f32; 2], p2: [f32; 2]| (id, p1[0].to_bits(), p1[1].to_bits(), p2[0].to_bits(), p2[1].to_bits());
+    let mut present: std::collections::HashSet<_> = candidates.iter().map(|c| key(c.id, c.p1, c.p2)).collect();
+    let way_ids: std::collections::BTreeSet<u64> = candidates.iter().filter(|c| c.p1 != c.p2).map(|c| c.id).collect();
+    for id in way_ids {
+        let Some((vertices, tag_set_id)) = state.ways.get_with_tags(id) else { continue };
+        for w in vertices.windows(2) {
+            let (p1, p2) = (w[0], w[1]);
+            if present.insert(key(id, p1, p2)) {
+                let dist2 = point_segment_distance2(query_point[0], query_point[1], p1[0], p1[1], p2[0], p2[1]);
+                candidates.push(Candidate { dist2, id, p1, p2, tag_set_id });
             }
         }
     }
+}
 
-    // Sort by distance (ASC)
-    response_elements.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
-    let final_elements: Vec<ResultElement> = response_elements.into_iter().map(|(_, e)| e).collect();
+/// Collect every element whose segment envelope intersects the `[lat, lon]` box `min..max`.
+fn collect_in_bbox(state: &AppState, min: [f32; 2], max: [f32; 2]) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    if !state.coverage.is_some_and(|c| c.intersects(&AABB::from_corners(min, max))) {
+        return candidates;
+    }
+    if let Some(rtree) = &state.rtree {
+        for se in rtree.locate_in_envelope_intersecting(&AABB::from_corners(min, max)) {
+            let (p1, p2) = se.endpoints();
+            candidates.push(Candidate { dist2: 0.0, id: se.id, p1, p2, tag_set_id: se.tag_set_id });
+        }
+    } else if let Some(owned) = &state.owned_elements {
+        for e in owned.iter() {
+            let (p1, p2) = (e.coordinates[0], e.coordinates[1]);
+            let intersects = p1[0].min(p2[0]) <= max[0] && p1[0].max(p2[0]) >= min[0]
+                && p1[1].min(p2[1]) <= max[1] && p1[1].max(p2[1]) >= min[1];
+            if intersects {
+                candidates.push(Candidate { dist2: 0.0, id: e.id, p1, p2, tag_set_id: e.tag_set_id });
+            }
+        }
+    }
+    candidates
+}
 
-    Json(QueryResponse { elements: final_elements })
+/// Query each `[runtime] preload_regions` bbox once and resolve the matches' tags, so the r-tree
+/// nodes, tag sets and string pool behind hot areas are paged in before the first request.
+fn preload_regions(state: &AppState, regions: &[[f64; 4]]) {
+    for &[south, west, north, east] in regions {
+        let started = std::time::Instant::now();
+        let candidates = collect_in_bbox(state, [south as f32, west as f32], [north as f32, east as f32]);
+        let tag_pairs: usize = candidates.iter().map(|c| resolve_tag_pairs(state, c.tag_set_id).len()).sum();
+        info!("Preloaded region [{}, {}, {}, {}]: {} elements, {} tags in {:.2?}", south, west, north, east, candidates.len(), tag_pairs, started.elapsed());
+    }
+}
+
+/// Number of stored elements (nodes and way segments) intersecting the bbox, without building
+/// candidates or resolving tags.
+fn count_in_bbox(state: &AppState, min: [f32; 2], max: [f32; 2]) -> usize {
+    let envelope = AABB::from_corners(min, max);
+    if !state.coverage.is_some_and(|c| c.intersects(&envelope)) {
+        return 0;
+    }
+    if let Some(rtree) = &state.rtree {
+        return rtree.locate_in_envelope_intersecting(&envelope).count();
+    }
+    state.owned_elements.as_ref().map_or(0, |owned| owned.iter().filter(|e| {
+        let (p1, p2) = (e.coordinates[0], e.coordinates[1]);
+        p1[0].min(p2[0]) <= max[0] && p1[0].max(p2[0]) >= min[0] && p1[1].min(p2[1]) <= max[1] && p1[1].max(p2[1]) >= min[1]
+    }).count())
+}
+
+/// Element density of one XYZ tile: `{z, x, y, bbox: [south, west, north, east], elements}`.
+/// Way segments count individually, like `DatasetCounts::elements`.
+async fn handle_density(State(state): State<AppState>, Path((z, x, y)): Path<(u8, u32, u32)>) -> Response {
+    let Some((min, max)) = crate::geometry::tile_bounds(z, x, y) else {
+        return error_response(StatusCode::BAD_REQUEST, format!("invalid tile {}/{}/{} (zoom 0-{}, x and y below 2^zoom)", z, x, y, crate::geometry::MAX_TILE_ZOOM));
+    };
+    let elements = count_in_bbox(&state, [min[0] as f32, min[1] as f32], [max[0] as f32, max[1] as f32]);
+    with_dataset_version(&state, Json(serde_json::json!({
+        "z": z, "x": x, "y": y,
+        "bbox": [min[0], min[1], max[0], max[1]],
+        "elements": elements,
+    })).into_response())
+}
+
+/// `source_hash` combined with the `[server]` settings that change response bodies for the same
+/// URL, so a config change plus restart invalidates `ETag`s and `since` versions as well.
+fn dataset_version(source_hash: u64, server: &crate::config::Server) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut s = std::collections::hash_map::DefaultHasher::new();
+    source_hash.hash(&mut s);
+    server.overpass_compat.hash(&mut s);
+    server.response_tag_allowlist.hash(&mut s);
+    server.hoist_keys.hash(&mut s);
+    server.hoist_keep_in_tags.hash(&mut s);
+    s.finish()
+}
+
+/// `304 Not Modified` (with the version header) when `since` names the current dataset version.
+/// Only the unchanged case short-circuits; any other value gets the full result.
+fn unchanged_since(state: &AppState, since: Option<&str>) -> Option<Response> {
+    let since = u64::from_str_radix(since?.trim(), 16).ok()?;
+    (since == state.version).then(|| with_dataset_version(state, StatusCode::NOT_MODIFIED.into_response()))
+}
+
+/// Tag a data response with `X-Dataset-Version` so clients can detect dataset changes.
+/// The version doubles as a strong `ETag`: the dataset is immutable for the life of the process,
+/// so the same URL always yields the same body.
+fn with_dataset_version(state: &AppState, mut response: Response) -> Response {
+    if let Ok(value) = header::HeaderValue::from_str(&format!("{:016x}", state.version)) {
+        response.headers_mut().insert("x-dataset-version", value);
+    }
+    if let Ok(value) = header::HeaderValue::from_str(&format!("\"{:016x}\"", state.version)) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    response
+}
+
+/// Middleware: answer `If-None-Match` revalidations with 304 and set `Cache-Control` on 2xx/304
+/// responses from `[server] cache_control`, using the longest matching `cache_control_routes`
+/// prefix override.
+async fn cache_headers(State(server): State<Arc<crate::config::Server>>, request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    let path = request.uri().path().to_string();
+    let if_none_match = request.headers().get(header::IF_NONE_MATCH).cloned();
+    let mut response = next.run(request).await;
+
+    if response.status() == StatusCode::OK {
+        let etag = response.headers().get(header::ETAG).cloned();
+        if let (Some(etag), Some(candidates)) = (etag, if_none_match.as_ref().and_then(|v| v.to_str().ok())) {
+            if candidates.split(',').any(|c| c.trim() == "*" || c.trim().as_bytes() == etag.as_bytes()) {
+                let mut not_modified = StatusCode::NOT_MODIFIED.into_response();
+                for name in [header::ETAG, header::HeaderName::from_static("x-dataset-version")] {
+                    if let Some(value) = response.headers().get(&name) {
+                        not_modified.headers_mut().insert(name.clone(), value.clone());
+                    }
+                }
+                response = not_modified;
+            }
+        }
+    }
+
+    // errors must not be cached for `max-age`
+    let status = response.status();
+    if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+        return response;
+    }
+    let route_override = server.cache_control_routes.iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, value)| value);
+    if let Some(value) = route_override.or(server.cache_control.as_ref()) {
+        if let Ok(value) = header::HeaderValue::from_str(value) {
+            response.headers_mut().insert(header::CACHE_CONTROL, value);
+        }
+    }
+    response
+}
+
+/// JSON error body used for rejected requests.
+fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, Json(serde_json::json!({ "error": message.into() }))).into_response()
+}
+
+/// Fallback for unmatched paths: a JSON 404 listing the registered routes.
+fn not_found(routes: &[&str]) -> Response {
+    (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "not found", "available": routes }))).into_response()
+}
+
+/// Encode results as `QueryResponse` from `proto/query.proto`, using a response-wide string table.
+fn encode_protobuf(elements: &[ResultElement], next_cursor: Option<&str>) -> Vec<u8> {
+    let mut strings: Vec<&str> = Vec::new();
+    let mut string_ids: HashMap<&str, u32> = HashMap::new();
+
+    let mut encoded_elements = Vec::with_capacity(elements.len());
+    for e in elements {
+        let mut msg = crate::proto::Encoder::new();
+        msg.uint64(1, e.id);
+        msg.double(2, e.lat1);
+        msg.double(3, e.lon1);
+        msg.double(4, e.lat2);
+        msg.double(5, e.lon2);
+        msg.string(6, &e.element_type);
+        let mut keys = Vec::with_capacity(e.tags.len());
+        let mut values = Vec::with_capacity(e.tags.len());
+        let pairs = e.tag_pairs.iter().flatten().map(|[k, v]| (k, v));
+        for (k, v) in e.tags.iter().chain(pairs) {
+            for (s, out) in [(k.as_str(), &mut keys), (v.as_str(), &mut values)] {
+                let id = *string_ids.entry(s).or_insert_with(|| {
+                    strings.push(s);
+                    (strings.len() - 1) as u32
+                });
+                out.push(id);
+            }
+        }
+        msg.packed_uint32(7, &keys);
+        msg.packed_uint32(8, &values);
+        encoded_elements.push(msg);
+    }
+
+    let mut out = crate::proto::Encoder::new();
+    for s in &strings {
+        out.string(1, s);
+    }
+    for msg in encoded_elements {
+        out.message(2, msg);
+    }
+    if let Some(cursor) = next_cursor {
+        out.string(3, cursor);
+    }
+    out.into_bytes()
+}
+
+/// Page size used when only `cursor` is given
+const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// Position of the last returned candidate: element id and segment index within that id
+/// (segments ordered by their endpoints).
+type CursorPos = (u64, u32);
+
+fn encode_cursor((id, segment): CursorPos) -> String {
+    let mut bytes = [0u8; 12];
+    bytes[..8].copy_from_slice(&id.to_le_bytes());
+    bytes[8..].copy_from_slice(&segment.to_le_bytes());
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn decode_cursor(cursor: &str) -> Option<CursorPos> {
+    let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    let bytes: [u8; 12] = bytes.try_into().ok()?;
+    Some((u64::from_le_bytes(bytes[..8].try_into().ok()?), u32::from_le_bytes(bytes[8..].try_into().ok()?)))
+}
+
+/// Order candidates deterministically (id, then endpoints), skip everything up to `after`
+/// and return one page plus the cursor for the next one. With `one_per_id` (mode=ways) only
+/// the first segment of each id is kept so a way never spans two pages.
+fn paginate(mut candidates: Vec<Candidate>, one_per_id: bool, after: Option<CursorPos>, limit: usize) -> (Vec<Candidate>, Option<String>) {
+    let key = |c: &Candidate| (c.id, c.p1[0].to_bits(), c.p1[1].to_bits(), c.p2[0].to_bits(), c.p2[1].to_bits());
+    candidates.sort_by_key(key);
+
+    let mut page = Vec::new();
+    let mut last = None;
+    let mut segment = 0u32;
+    let mut prev_id = None;
+    for c in candidates {
+        segment = if prev_id == Some(c.id) { segment + 1 } else { 0 };
+        prev_id = Some(c.id);
+        if (one_per_id && segment > 0) || after.is_some_and(|a| (c.id, segment) <= a) {
+            continue;
+        }
+        if page.len() == limit {
+            return (page, last.map(encode_cursor));
+        }
+        last = Some((c.id, segment));
+        page.push(c);
+    }
+    (page, None)
+}
+
+/// Server-side tag predicates parsed from the query parameters. Every predicate must match (AND).
+/// Keys are resolved to interner ids once; a key missing from the interner (`None`) matches nothing.
+#[derive(Default)]
+struct TagPredicates {
+    /// `(key id, min, max)` numeric ranges, inclusive
+    ranges: Vec<(Option<u32>, Option<f64>, Option<f64>)>,
+    /// `has_any` key ids (keys unknown to the interner are dropped, so an empty list matches nothing)
+    any_keys: Option<Vec<u32>>,
+    /// `tag_prefix` / `tag_contains` as `(key id, match, text)`; checked last since they
+    /// resolve value strings
+    text: Vec<(Option<u32>, TextMatch, String)>,
+    /// Exact `key` / `key=value` filters as `(key id, value id)`
+    exact: Vec<(u32, Option<u32>)>,
+    /// `key=value` filters with `split_multi`: `(key id, item)` tested against each `;` item
+    members: Vec<(u32, String)>,
+    /// An exact filter names a string missing from the interner, so nothing can match
+    unmatchable: bool,
+}
+
+/// Tag filter parameters shared by `/api/query` and `/api/query/multi`.
+struct FilterSpec<'a> {
+    tag_range: Option<&'a str>,
+    has_any: Option<&'a str>,
+    tag_prefix: Option<&'a str>,
+    tag_contains: Option<&'a str>,
+    /// Exact `key` or `key=value` filters (`/api/query/multi` only)
+    tags: &'a [String],
+    /// `key=value` also matches a `;`-separated value containing `value`
+    split_multi: bool,
+}
+
+impl QueryParams {
+    fn filter_spec(&self) -> FilterSpec<'_> {
+        FilterSpec {
+            tag_range: self.tag_range.as_deref(),
+            has_any: self.has_any.as_deref(),
+            tag_prefix: self.tag_prefix.as_deref(),
+            tag_contains: self.tag_contains.as_deref(),
+            tags: &[],
+            split_multi: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TextMatch {
+    Prefix,
+    Contains,
+}
+
+impl TagPredicates {
+    fn parse(state: &AppState, params: &FilterSpec) -> Result<Self, String> {
+        let mut predicates = TagPredicates::default();
+        for spec in params.tags {
+            let (key, value) = match spec.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (spec.as_str(), None),
+            };
+            if key.is_empty() {
+                return Err(format!("malformed tag '{}' (expected key or key=value)", spec));
+            }
+            let key_id = state.find_key(key);
+            if let (true, Some(k), Some(v)) = (params.split_multi, key_id, value) {
+                // the item alone need not be interned when it only occurs inside lists
+                predicates.members.push((k, v.to_string()));
+                continue;
+            }
+            let value_id = value.map(|v| state.interner.find(v));
+            match (key_id, value_id) {
+                (Some(k), None) => predicates.exact.push((k, None)),
+                (Some(k), Some(Some(v))) => predicates.exact.push((k, Some(v))),
+                _ => predicates.unmatchable = true,
+            }
+        }
+        for spec in params.tag_range.iter().flat_map(|s| s.split(',')).filter(|s| !s.is_empty()) {
+            let malformed = || format!("malformed tag_range '{}' (expected key:min..max)", spec);
+            let (key, range) = spec.split_once(':').ok_or_else(malformed)?;
+            let (min, max) = range.split_once("..").ok_or_else(malformed)?;
+            let parse_bound = |b: &str| -> Result<Option<f64>, String> {
+                if b.is_empty() { Ok(None) } else { b.parse().map(Some).map_err(|_| malformed()) }
+            };
+            predicates.ranges.push((state.find_key(key), parse_bound(min)?, parse_bound(max)?));
+        }
+        for (param, spec, kind) in [("tag_prefix", params.tag_prefix, TextMatch::Prefix), ("tag_contains", params.tag_contains, TextMatch::Contains)] {
+            for spec in spec.iter().flat_map(|s| s.split(',')).filter(|s| !s.is_empty()) {
+                let (key, text) = spec.split_once(':')
+                    .filter(|(key, text)| !key.is_empty() && !text.is_empty())
+                    .ok_or_else(|| format!("malformed {} '{}' (expected key:text)", param, spec))?;
+                predicates.text.push((state.find_key(key), kind, text.to_string()));
+            }
+        }
+        if let Some(keys) = params.has_any {
+            predicates.any_keys = Some(keys.split(',').map(str::trim).filter(|k| !k.is_empty())
+                .filter_map(|k| state.find_key(k))
+                .collect());
+        }
+        Ok(predicates)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ranges.is_empty() && self.any_keys.is_none() && self.text.is_empty() && self.exact.is_empty() && self.members.is_empty() && !self.unmatchable
+    }
+
+    fn matches(&self, state: &AppState, tag_set_id: u32) -> bool {
+        let pairs = state.tag_sets.get(tag_set_id as usize).unwrap_or(&[]);
+        let value_of = |kid: u32| -> Option<String> {
+            pairs.iter()
+                .find(|&&packed| unpack_kv(packed).0 == kid)
+                .and_then(|&packed| state.interner.lookup(unpack_kv(packed).1))
+        };
+        if self.unmatchable {
+            return false;
+        }
+        let has_exact = |&(kid, vid): &(u32, Option<u32>)| pairs.iter().any(|&packed| {
+            unpack_kv(packed).0 == kid && vid.is_none_or(|v| unpack_kv(packed).1 == v)
+        });
+        if !self.exact.iter().all(has_exact) {
+            return false;
+        }
+        if let Some(any_keys) = &self.any_keys {
+            if !pairs.iter().any(|&packed| any_keys.contains(&unpack_kv(packed).0)) {
+                return false;
+            }
+        }
+        let in_ranges = self.ranges.iter().all(|&(kid, min, max)| {
+            let Some(value) = kid.and_then(value_of).and_then(|v| parse_numeric(&v)) else {
+                return false;
+            };
+            min.is_none_or(|m| value >= m) && max.is_none_or(|m| value <= m)
+        });
+        let in_members = self.members.iter().all(|(kid, item)| {
+            value_of(*kid).is_some_and(|value| split_multi_value(&value).any(|v| v == item.as_str()))
+        });
+        in_ranges && in_members && self.text.iter().all(|(kid, kind, text)| {
+            kid.and_then(value_of).is_some_and(|value| match kind {
+                TextMatch::Prefix => value.starts_with(text.as_str()),
+                TextMatch::Contains => value.contains(text.as_str()),
+            })
+        })
+    }
+}
+
+/// Parse the leading number of a tag value, ignoring unit suffixes (`50 km/h`, `3.5m`).
+fn parse_numeric(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let end = value.char_indices()
+        .find(|&(i, ch)| !(ch.is_ascii_digit() || ch == '.' || (i == 0 && (ch == '-' || ch == '+'))))
+        .map_or(value.len(), |(i, _)| i);
+    value[..end].parse().ok()
+}
+
+/// Turn a candidate into a response element (tag resolution, hoisting, classification).
+fn build_result(state: &AppState, params: &QueryParams, c: &Candidate) -> ResultElement {
+    let raw_tags = params.raw_tags.then(|| {
+        state.tag_sets.get(c.tag_set_id as usize).unwrap_or(&[]).iter()
+            .filter(|&&packed| state.tag_visible(packed))
+            .map(|&packed| <[u32; 2]>::from(unpack_kv(packed)))
+            .collect::<Vec<_>>()
+    });
+    let mut tags = if params.raw_tags { HashMap::new() } else { resolve_tags(state, c.tag_set_id) };
+    let mut hoisted = HashMap::new();
+    for key in &state.config.server.hoist_keys {
+        let value = if state.config.server.hoist_keep_in_tags { tags.get(key).cloned() } else { tags.remove(key) };
+        if let Some(v) = value {
+            hoisted.insert(key.clone(), v);
+        }
+    }
+    let class = params.classify.as_ref().map(|k| tags.get(k).cloned());
+    let tag_pairs = (params.tags_as_pairs && !params.raw_tags).then(|| {
+        let mut pairs = resolve_tag_pairs(state, c.tag_set_id);
+        if !state.config.server.hoist_keep_in_tags {
+            pairs.retain(|[k, _]| !hoisted.contains_key(k));
+        }
+        pairs
+    });
+    if tag_pairs.is_some() {
+        tags.clear();
+    }
+    let mut result = ResultElement::new(c, tags);
+    result.class = class;
+    result.hoisted = hoisted;
+    result.tag_pairs = tag_pairs;
+    result.raw_tags = raw_tags;
+    if params.include_relations && c.p1 != c.p2 {
+        result.relations = Some(state.way_relations.get(c.id).to_vec());
+    }
+    if params.along && c.p1 != c.p2 {
+        let (t, _) = project_onto_segment(params.lat as f32, params.lon as f32, c.p1[0], c.p1[1], c.p2[0], c.p2[1]);
+        result.along = Some(t as f64);
+        result.along_way = state.ways.get(c.id).and_then(|vertices| crate::geometry::fraction_along(vertices, c.p1, c.p2, t as f64));
+    }
+    result
+}
+
+async fn handle_query(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
+    Query(params): Query<QueryParams>,
+) -> Response {
+    let started = std::time::Instant::now();
+    if let Some(response) = unchanged_since(&state, params.since.as_deref()) {
+        return response;
+    }
+    let accepts_msgpack = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.split(',').any(|t| t.split(';').next().unwrap_or("").trim() == MSGPACK_CONTENT_TYPE));
+    let format = params.format.as_deref().unwrap_or(if accepts_msgpack { "msgpack" } else { "json" });
+    if !["json", "protobuf", "fgb", "polyline", "msgpack"].contains(&format) {
+        return error_response(StatusCode::BAD_REQUEST, format!("unsupported format '{}' (expected json, protobuf, fgb, polyline or msgpack)", format));
+    }
+
+    let mode = params.mode.as_deref().unwrap_or("segments");
+    if mode != "segments" && mode != "ways" {
+        return error_response(StatusCode::BAD_REQUEST, format!("unsupported mode '{}' (expected segments or ways)", mode));
+    }
+    let web_mercator = match params.srs.as_deref() {
+        None | Some("4326") => false,
+        Some("3857") if format == "polyline" => return error_response(StatusCode::BAD_REQUEST, "format=polyline encodes lat/lon and cannot be combined with srs=3857"),
+        Some("3857") => true,
+        Some(other) => return error_response(StatusCode::BAD_REQUEST, format!("unsupported srs '{}' (expected 4326 or 3857)", other)),
+    };
+    let centroids = match params.representation.as_deref() {
+        None | Some("segments") => false,
+        Some("centroid") if mode == "ways" => return error_response(StatusCode::BAD_REQUEST, "representation=centroid cannot be combined with mode=ways"),
+        Some("centroid") => true,
+        Some(other) => return error_response(StatusCode::BAD_REQUEST, format!("unsupported representation '{}' (expected segments or centroid)", other)),
+    };
+
+    let mbr = match params.geometry.as_deref() {
+        None => false,
+        Some("mbr") if mode == "ways" || centroids => return error_response(StatusCode::BAD_REQUEST, "geometry=mbr cannot be combined with mode=ways or representation=centroid"),
+        Some("mbr") if format != "json" => return error_response(StatusCode::BAD_REQUEST, "geometry=mbr requires format=json"),
+        Some("mbr") => true,
+        Some(other) => return error_response(StatusCode::BAD_REQUEST, format!("unsupported geometry '{}' (expected mbr)", other)),
+    };
+
+    let min_area = state.config.server.min_query_area_m2;
+    let query_area = std::f64::consts::PI * params.radius * params.radius;
+    if min_area > 0.0 && (query_area.is_nan() || query_area < min_area) {
+        return error_response(StatusCode::BAD_REQUEST, format!("query area {:.1} m² (radius {} m) is below min_query_area_m2 {}", query_area, params.radius, min_area));
+    }
+
+    let radius_deg = params.radius / 111320.0; 
+    let radius_deg_f32 = radius_deg as f32;
+    let query_point = [params.lat as f32, params.lon as f32];
+
+    if params.has_area_filter() && (state.ways.is_empty() || params.is_area == Some(false)) {
+        return error_response(StatusCode::BAD_REQUEST, "area filters require area classification (closed way geometry) which is not available for this query");
+    }
+
+    let predicates = match TagPredicates::parse(&state, &params.filter_spec()) {
+        Ok(p) => p,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    if params.compact_tags && (format != "json" || params.tags_as_pairs || params.raw_tags || params.split_multi) {
+        return error_response(StatusCode::BAD_REQUEST, "compact_tags requires format=json and cannot be combined with tags_as_pairs, raw_tags or split_multi");
+    }
+
+    let (node_bias, way_bias) = match parse_type_bias(params.type_bias.as_deref()) {
+        Ok(bias) => bias,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let score_weights = match params.score.as_deref().map(|spec| ScoreWeights::parse(&state, spec, params.score_default.unwrap_or(1.0))) {
+        Some(Ok(weights)) => Some(weights),
+        Some(Err(e)) => return error_response(StatusCode::BAD_REQUEST, e),
+        None => None,
+    };
+
+    if let Some(grid_m) = params.cluster {
+        if !(grid_m.is_finite() && grid_m > 0.0) || format != "json" {
+            return error_response(StatusCode::BAD_REQUEST, "cluster must be a positive cell size in metres and requires format=json");
+        }
+    }
+
+    if params.collapse_within.is_some_and(|m| !(m.is_finite() && m > 0.0)) {
+        return error_response(StatusCode::BAD_REQUEST, "collapse_within must be a positive distance in metres");
+    }
+
+    if params.group_by_primary && format != "json" {
+        return error_response(StatusCode::BAD_REQUEST, "group_by_primary requires format=json");
+    }
+
+    if params.with_facets.is_some() && format != "json" {
+        return error_response(StatusCode::BAD_REQUEST, "with_facets requires format=json");
+    }
+
+    if params.sample.is_some_and(|f| !(0.0..=1.0).contains(&f)) {
+        return error_response(StatusCode::BAD_REQUEST, "sample must be a fraction between 0.0 and 1.0");
+    }
+
+    if params.nearest_per_group != params.group_by.is_some() {
+        return error_response(StatusCode::BAD_REQUEST, "group_by and nearest_per_group=true must be used together");
+    }
+
+    if params.limit == Some(0) {
+        return error_response(StatusCode::BAD_REQUEST, "limit must be at least 1");
+    }
+
+    let mut candidates = match params.engine.as_deref() {
+        None => collect_candidates(&state, query_point, radius_deg_f32),
+        Some(engine) => {
+            if !state.config.server.debug_endpoints {
+                return error_response(StatusCode::BAD_REQUEST, "engine selection requires [server] debug_endpoints");
+            }
+            // restrict a state copy to a single lookup path
+            let mut forced = state.clone();
+            match engine {
+                "rtree" => forced.owned_elements = None,
+                "scan" => forced.rtree = None,
+                other => return error_response(StatusCode::BAD_REQUEST, format!("unsupported engine '{}' (expected rtree or scan)", other)),
+            }
+            let lookup_started = std::time::Instant::now();
+            let candidates = collect_candidates(&forced, query_point, radius_deg_f32);
+            info!("engine={}: {} candidates in {:.2?}", engine, candidates.len(), lookup_started.elapsed());
+            candidates
+        }
+    };
+    let candidate_count = candidates.len();
+
+    if !predicates.is_empty() {
+        candidates.retain(|c| predicates.matches(&state, c.tag_set_id));
+    }
+
+    if params.has_geometry_filter() {
+        let mut verdicts: HashMap<u64, bool> = HashMap::new();
+        candidates.retain(|c| {
+            if c.p1 == c.p2 {
+                return false;
+            }
+            *verdicts.entry(c.id).or_insert_with(|| state.ways.get(c.id).is_some_and(|v| params.geometry_matches(v)))
+        });
+    }
+
+    let facets = params.with_facets.as_ref().map(|key| facet_counts(&state, &candidates, key));
+
+    if let Some(fraction) = params.sample {
+        candidates.retain(|c| sample_keeps(c.id, fraction));
+    }
+
+    if let Some(grid_m) = params.cluster {
+        let clusters = cluster_candidates(&state, &candidates, grid_m, params.lat);
+        return with_dataset_version(&state, Json(serde_json::json!({ "clusters": clusters, "facets": facets })).into_response());
+    }
+
+    if params.expand_ways {
+        expand_way_segments(&state, &mut candidates, query_point);
+    }
+
+    let mut scores: Option<HashMap<u64, f64>> = None;
+    if let Some(weights) = &score_weights {
+        // true distance to the closest point (dist2 is in unscaled degrees), biased and weighted
+        let score_of = |c: &Candidate| {
+            let (_, foot) = project_onto_segment(query_point[0], query_point[1], c.p1[0], c.p1[1], c.p2[0], c.p2[1]);
+            let bias = f64::from(if c.p1 == c.p2 { node_bias } else { way_bias });
+            crate::geometry::haversine_m(query_point, foot) * bias / weights.weight(&state, c.tag_set_id)
+        };
+        let mut scored: Vec<(f64, Candidate)> = candidates.into_iter().map(|c| (score_of(&c), c)).collect();
+        scored.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let mut best: HashMap<u64, f64> = HashMap::new();
+        for (score, c) in &scored {
+            best.entry(c.id).or_insert(*score);
+        }
+        candidates = scored.into_iter().map(|(_, c)| c).collect();
+        scores = Some(best);
+    } else {
+        // Sort by distance (ASC), scaled by `type_bias` (dist2 is squared, so is the multiplier)
+        let ranked = |c: &Candidate| c.dist2 * if c.p1 == c.p2 { node_bias * node_bias } else { way_bias * way_bias };
+        candidates.sort_by(|a, b| ranked(a).partial_cmp(&ranked(b)).unwrap_or(std::cmp::Ordering::Equal));
+    }
+
+    if let Some(key) = &params.group_by {
+        // candidates are sorted, so the first one seen per value id is the group's nearest
+        let key_id = state.find_key(key);
+        let mut seen_values = std::collections::HashSet::new();
+        candidates.retain(|c| {
+            let value_id = key_id.and_then(|kid| {
+                state.tag_sets.get(c.tag_set_id as usize).unwrap_or(&[]).iter()
+                    .find(|&&packed| unpack_kv(packed).0 == kid)
+                    .map(|&packed| unpack_kv(packed).1)
+            });
+            value_id.is_some_and(|v| seen_values.insert(v))
+        });
+    }
+
+    let merged = params.collapse_within
+        .map(|tolerance_m| collapse_candidates(&state, &mut candidates, tolerance_m, params.collapse_key.as_deref(), params.lat));
+
+    // geometry=mbr: bounds of every matched segment per way, taken before pagination keeps only
+    // one segment per way
+    let mut mbr_bounds: HashMap<u64, ([f32; 2], [f32; 2])> = HashMap::new();
+    if mbr {
+        for c in candidates.iter().filter(|c| c.p1 != c.p2) {
+            let (min, max) = mbr_bounds.entry(c.id).or_insert((c.p1, c.p1));
+            for p in [c.p1, c.p2] {
+                *min = [min[0].min(p[0]), min[1].min(p[1])];
+                *max = [max[0].max(p[0]), max[1].max(p[1])];
+            }
+        }
+    }
+
+    let mut next_cursor = None;
+    if params.limit.is_some() || params.cursor.is_some() {
+        let after = match params.cursor.as_deref().map(decode_cursor) {
+            Some(Some(after)) => Some(after),
+            Some(None) => return error_response(StatusCode::BAD_REQUEST, "malformed cursor"),
+            None => None,
+        };
+        let (page, cursor) = paginate(candidates, mode == "ways" || centroids || mbr, after, params.limit.unwrap_or(DEFAULT_PAGE_SIZE));
+        candidates = page;
+        next_cursor = cursor;
+    }
+
+    let mut final_elements: Vec<ResultElement> = match mode {
+        _ if centroids => {
+            // one point per way (ranked by its closest segment), carrying the way's tags
+            let mut seen_ways = std::collections::HashSet::new();
+            candidates.into_iter().filter_map(|c| {
+                if c.p1 == c.p2 {
+                    return Some(build_result(&state, &params, &c));
+                }
+                if !seen_ways.insert(c.id) {
+                    return None;
+                }
+                let mut result = build_result(&state, &params, &c);
+                let center = state.ways.get(c.id)
+                    .map(crate::geometry::centroid)
+                    .unwrap_or_else(|| crate::geometry::centroid(&[c.p1, c.p2]));
+                result.set_point(center);
+                Some(result)
+            }).collect()
+        }
+        _ if mbr => {
+            // one result per way ranked by its closest segment
+            let mut seen_ways = std::collections::HashSet::new();
+            candidates.into_iter().filter_map(|c| {
+                if c.p1 == c.p2 {
+                    return Some(build_result(&state, &params, &c));
+                }
+                if !seen_ways.insert(c.id) {
+                    return None;
+                }
+                let mut result = build_result(&state, &params, &c);
+                if let Some(&(min, max)) = mbr_bounds.get(&c.id) {
+                    result.set_mbr(min, max);
+                }
+                Some(result)
+            }).collect()
+        }
+        "ways" => {
+            // one result per way (its closest segment decides the rank), full geometry + length
+            let mut seen_ways = std::collections::HashSet::new();
+            candidates.into_iter().filter_map(|c| {
+                if c.p1 == c.p2 {
+                    return Some(build_result(&state, &params, &c));
+                }
+                if !seen_ways.insert(c.id) {
+                    return None;
+                }
+                let mut result = build_result(&state, &params, &c);
+                if let Some(vertices) = state.ways.get(c.id) {
+                    result.set_way_geometry(vertices);
+                }
+                Some(result)
+            }).collect()
+        }
+        _ => candidates.iter().map(|c| build_result(&state, &params, c)).collect(),
+    };
+    for e in &mut final_elements {
+        if let Some(merged) = &merged {
+            e.merged_count = Some(merged.get(&e.id).copied().unwrap_or(1));
+        }
+        if let Some(scores) = &scores {
+            e.score = scores.get(&e.id).copied();
+        }
+        if web_mercator {
+            e.project_web_mercator();
+        }
+        e.round_coords(params.coord_dp);
+        if e.mbr.is_some() {
+            // follow the corners through reprojection and rounding
+            e.mbr = Some([e.lat1, e.lon1, e.lat2, e.lon2]);
+        }
+        e.truncate_values(params.max_value_len);
+        if params.split_multi {
+            e.split_multi_values();
+        }
+    }
+    let tag_sets = params.compact_tags.then(|| compact_tag_sets(&mut final_elements));
+
+    let result_count = final_elements.len();
+    let response = if format == "fgb" {
+        flatgeobuf_response(&final_elements)
+    } else if format == "protobuf" {
+        ([(header::CONTENT_TYPE, "application/x-protobuf")], encode_protobuf(&final_elements, next_cursor.as_deref())).into_response()
+    } else if format == "polyline" {
+        let elements: Vec<PolylineElement> = final_elements.iter().map(PolylineElement::from).collect();
+        Json(serde_json::json!({ "elements": elements, "next_cursor": next_cursor })).into_response()
+    } else if format == "msgpack" {
+        if state.config.server.overpass_compat {
+            let elements: Vec<OverpassElement> = final_elements.iter().map(OverpassElement::from).collect();
+            msgpack_response(&QueryResponse { elements, next_cursor, facets, tag_sets })
+        } else {
+            msgpack_response(&QueryResponse { elements: final_elements, next_cursor, facets, tag_sets })
+        }
+    } else if params.group_by_primary {
+        let groups = group_by_primary(&state, final_elements);
+        if state.config.server.overpass_compat {
+            let groups: HashMap<&str, Vec<OverpassElement>> = groups.iter()
+                .map(|(key, elements)| (key.as_str(), elements.iter().map(OverpassElement::from).collect()))
+                .collect();
+            Json(serde_json::json!({ "groups": groups, "next_cursor": next_cursor, "facets": facets, "tag_sets": tag_sets })).into_response()
+        } else {
+            Json(serde_json::json!({ "groups": groups, "next_cursor": next_cursor, "facets": facets, "tag_sets": tag_sets })).into_response()
+        }
+    } else {
+        query_json_response(&state, QueryResponse { elements: final_elements, next_cursor, facets, tag_sets })
+    };
+
+    // after encoding, which dominates for large result sets
+    let slow_query_ms = state.config.server.slow_query_ms;
+    if slow_query_ms > 0 && started.elapsed().as_millis() > slow_query_ms as u128 {
+        warn!("slow query: {:.2?} for {:?} ({} candidates, {} results)", started.elapsed(), params, candidate_count, result_count);
+    }
+    with_dataset_version(&state, response)
+}
+
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// `format=msgpack`: the JSON response's structure as MessagePack, with named fields (structs
+/// become maps, so `#[serde(flatten)]` fields encode the same way as in JSON).
+fn msgpack_response<T: Serialize>(value: &T) -> Response {
+    match rmp_serde::to_vec_named(value) {
+        Ok(bytes) => ([(header::CONTENT_TYPE, MSGPACK_CONTENT_TYPE)], bytes).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, format!("failed to encode MessagePack: {}", e)),
+    }
+}
+
+/// Results per chunk on the parallel JSON path
+const JSON_CHUNK_ELEMENTS: usize = 4096;
+
+/// Encode a `QueryResponse` as JSON, in the `OverpassElement` shape under `[server] overpass_compat`.
+fn query_json_response(state: &AppState, response: QueryResponse) -> Response {
+    if state.config.server.overpass_compat {
+        let QueryResponse { elements, next_cursor, facets, tag_sets } = response;
+        let elements = elements.iter().map(OverpassElement::from).collect();
+        return encode_query_json(state, QueryResponse { elements, next_cursor, facets, tag_sets });
+    }
+    encode_query_json(state, response)
+}
+
+/// From `[server] parallel_json_min_elements` results up, the `elements` array is serialized in
+/// chunks on rayon (see `parallel_query_json`).
+fn encode_query_json<E: Serialize + Sync>(state: &AppState, mut response: QueryResponse<E>) -> Response {
+    let threshold = state.config.server.parallel_json_min_elements;
+    if threshold == 0 || response.elements.len() < threshold {
+        return Json(response).into_response();
+    }
+    match parallel_query_json(&mut response, JSON_CHUNK_ELEMENTS) {
+        Some(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        None => Json(response).into_response(),
+    }
+}
+
+/// `response` as JSON with `elements` encoded in chunks of `chunk_elements` on rayon and spliced
+/// into the rest of the response; the bytes are identical to `serde_json::to_vec(response)`.
+/// `None` when encoding fails (`response` is left intact for the serial path).
+fn parallel_query_json<E: Serialize + Sync>(response: &mut QueryResponse<E>, chunk_elements: usize) -> Option<Vec<u8>> {
+    let chunks: Result<Vec<Vec<u8>>, serde_json::Error> = response.elements
+        .par_chunks(chunk_elements)
+        .map(serde_json::to_vec)
+        .collect();
+    let elements = std::mem::take(&mut response.elements);
+    let envelope = serde_json::to_vec(&*response);
+    response.elements = elements;
+    let (Ok(chunks), Ok(envelope)) = (chunks, envelope) else {
+        return None;
+    };
+
+    // `elements` is the first field, so the envelope starts with `{"elements":[]`
+    const EMPTY_ELEMENTS: &[u8] = br#"{"elements":[]"#;
+    let mut body = Vec::with_capacity(chunks.iter().map(Vec::len).sum::<usize>() + envelope.len());
+    body.extend_from_slice(&EMPTY_ELEMENTS[..EMPTY_ELEMENTS.len() - 1]);
+    for (i, chunk) in chunks.iter().enumerate() {
+        if i > 0 {
+            body.push(b',');
+        }
+        // each chunk is a non-empty `[...]` array; keep only its items
+        body.extend_from_slice(&chunk[1..chunk.len() - 1]);
+    }
+    body.push(b']');
+    body.extend_from_slice(&envelope[EMPTY_ELEMENTS.len()..]);
+    Some(body)
+}
+
+async fn handle_stats(State(state): State<AppState>) -> Json<StatsResponse> {
+    Json(StatsResponse {
+        counts: state.counts,
+        tag_sets: state.tag_sets.0.offsets.len(),
+        strings: state.interner.offsets.read().len(),
+        pools: PoolSizes::measure(&state),
+    })
+}
+
+/// Every element whose midpoint lies in the H3 cell (hex id, e.g. `8928308280fffff`). The cell
+/// must have the configured `h3_resolution`.
+async fn handle_h3(State(state): State<AppState>, Path(cell): Path<String>) -> Response {
+    let Some(index) = &state.h3 else {
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "no H3 cells in the loaded cache (re-preprocess with h3_resolution set)");
+    };
+    let cell = match cell.parse::<h3o::CellIndex>() {
+        Ok(cell) => cell,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, format!("invalid H3 cell '{}': {}", cell, e)),
+    };
+    if u8::from(cell.resolution()) != index.resolution {
+        return error_response(StatusCode::BAD_REQUEST, format!("cell resolution {} does not match h3_resolution {}", u8::from(cell.resolution()), index.resolution));
+    }
+    let elements: Vec<ResultElement> = index.get(u64::from(cell)).iter().map(|e| {
+        let c = Candidate { dist2: 0.0, id: e.id, p1: e.coordinates[0], p2: e.coordinates[1], tag_set_id: e.tag_set_id };
+        ResultElement::new(&c, resolve_tags(&state, e.tag_set_id))
+    }).collect();
+    with_dataset_version(&state, Json(serde_json::json!({ "cell": cell.to_string(), "elements": elements })).into_response())
+}
+
+/// Spatial extent of the dataset as a GeoJSON Feature: the bbox polygon, or a `null` geometry
+/// when the dataset is empty.
+async fn handle_coverage(State(state): State<AppState>) -> Response {
+    let geometry = state.coverage.map(|c| {
+        let ([south, west], [north, east]) = (c.lower(), c.upper());
+        serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [[[west, south], [east, south], [east, north], [west, north], [west, south]]],
+        })
+    });
+    let feature = serde_json::json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": { "elements": state.counts.elements },
+    });
+    with_dataset_version(&state, Json(feature).into_response())
+}
+
+/// Effective configuration (after env overrides) with sensitive values redacted.
+/// Only routed when `[server] debug_endpoints` is enabled.
+async fn handle_config(State(state): State<AppState>) -> Response {
+    match state.config.redacted_json() {
+        Ok(value) => Json(value).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, format!("failed to serialize config: {}", e)),
+    }
+}
+
+/// Dump the full string table so clients using `raw_tags=true` can resolve ids themselves.
+/// Only routed when `[server] debug_endpoints` is enabled.
+async fn handle_interner(State(state): State<AppState>) -> Json<InternerResponse> {
+    Json(InternerResponse {
+        offsets: state.interner.offsets.read().clone(),
+        lengths: state.interner.lengths.read().clone(),
+        pool: state.interner.pool.read().as_str().to_string(),
+    })
+}
+
+/// Number of random tag sets resolved by `/health?deep=true`
+const HEALTH_SAMPLE_SIZE: usize = 16;
+
+/// Shallow by default (cheap enough for frequent probes). With `deep=true`, resolve a random
+/// sample of tag sets and verify every id stays within the pool on a char boundary, returning
+/// 503 when the cache looks corrupt.
+async fn handle_health(State(state): State<AppState>, Query(params): Query<HealthParams>) -> Response {
+    if !params.deep {
+        return Json(serde_json::json!({ "status": "ok" })).into_response();
+    }
+
+    let tag_set_count = state.tag_sets.0.offsets.len();
+    let offsets = state.interner.offsets.read();
+    let lengths = state.interner.lengths.read();
+    let pool = state.interner.pool.read();
+    let check_id = |id: u32| -> Result<(), String> {
+        let idx = id as usize;
+        let (start, len) = match (offsets.get(idx), lengths.get(idx)) {
+            (Some(&start), Some(&len)) => (start as usize, len as usize),
+            _ => return Err(format!("string id {} out of range", id)),
+        };
+        match pool.as_str().get(start..start + len) {
+            Some(_) => Ok(()),
+            None => Err(format!("string id {} points outside the pool or splits a UTF-8 sequence", id)),
+        }
+    };
+
+    let mut seed = {
+        use std::hash::{BuildHasher, Hasher};
+        std::collections::hash_map::RandomState::new().build_hasher().finish()
+    };
+    let mut checked = 0;
+    for _ in 0..HEALTH_SAMPLE_SIZE.min(tag_set_count) {
+        // xorshift64: good enough to spread samples across the tag-set table
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        let tag_set_id = (seed % tag_set_count as u64) as usize;
+        let pairs = match state.tag_sets.get(tag_set_id) {
+            Some(pairs) => pairs,
+            None => return error_response(StatusCode::SERVICE_UNAVAILABLE, format!("tag set {} out of range", tag_set_id)),
+        };
+        for &packed in pairs {
+            let (key_id, value_id) = unpack_kv(packed);
+            for id in [key_id, value_id] {
+                if let Err(e) = check_id(id) {
+                    return error_response(StatusCode::SERVICE_UNAVAILABLE, format!("tag set {}: {}", tag_set_id, e));
+                }
+            }
+        }
+        checked += 1;
+    }
+
+    Json(serde_json::json!({ "status": "ok", "tag_sets_checked": checked })).into_response()
+}
+
+/// Elements serialized per streamed body chunk in `/api/export`
+const EXPORT_CHUNK_SIZE: usize = 1024;
+
+/// One GeoJSON Feature (RFC 7946 `[lon, lat]` order) for a node point or way segment.
+fn geojson_feature(r: &ResultElement) -> serde_json::Value {
+    // nodes and way centroids are points
+    let geometry = if r.geometry.is_none() && r.lat1 == r.lat2 && r.lon1 == r.lon2 {
+        serde_json::json!({ "type": "Point", "coordinates": [r.lon1, r.lat1] })
+    } else if let Some(vertices) = &r.geometry {
+        let coordinates: Vec<[f64; 2]> = vertices.iter().map(|v| [v[1], v[0]]).collect();
+        serde_json::json!({ "type": "LineString", "coordinates": coordinates })
+    } else {
+        serde_json::json!({ "type": "LineString", "coordinates": [[r.lon1, r.lat1], [r.lon2, r.lat2]] })
+    };
+    serde_json::json!({
+        "type": "Feature",
+        "id": r.id,
+        "geometry": geometry,
+        "properties": { "type": r.element_type, "tags": r.tags },
+    })
+}
+
+/// FlatGeobuf (`format=fgb`): nodes as points, ways as linestrings. Tag keys differ per element
+/// while FlatGeobuf columns are fixed, so tags are stored as one JSON-encoded `tags` column.
+fn encode_flatgeobuf(elements: &[ResultElement]) -> anyhow::Result<Vec<u8>> {
+    let mut fgb = FgbWriter::create("elements", GeometryType::Unknown)?;
+    for r in elements {
+        let mut feature = geojson_feature(r);
+        feature["properties"] = serde_json::json!({
+            "id": r.id,
+            "type": r.element_type,
+            "tags": serde_json::to_string(&r.tags)?,
+        });
+        fgb.add_feature(geozero::geojson::GeoJson(&feature.to_string()))?;
+    }
+    let mut out = Vec::new();
+    fgb.write(&mut out)?;
+    Ok(out)
+}
+
+/// Wrap FlatGeobuf output (or its encoding error) in a response.
+fn flatgeobuf_response(elements: &[ResultElement]) -> Response {
+    match encode_flatgeobuf(elements) {
+        Ok(bytes) => ([(header::CONTENT_TYPE, "application/flatgeobuf")], bytes).into_response(),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, format!("failed to encode flatgeobuf: {:#}", e)),
+    }
+}
+
+/// Bulk-download every element intersecting a bbox (no radius, no limit), streamed in chunks
+/// as newline-delimited JSON or RFC 8142 GeoJSON text sequences. FlatGeobuf is also accepted;
+/// its spatial index needs every feature up front, so it is built in memory before sending.
+async fn handle_export(State(state): State<AppState>, Query(params): Query<ExportParams>) -> Response {
+    if let Some(response) = unchanged_since(&state, params.since.as_deref()) {
+        return response;
+    }
+    let format = params.format.clone().unwrap_or_else(|| "ndjson".to_string());
+    let content_type = match format.as_str() {
+        "ndjson" => "application/x-ndjson",
+        "geojson-seq" => "application/geo+json-seq",
+        "fgb" => "application/flatgeobuf",
+        "geojson" => return error_response(StatusCode::BAD_REQUEST, "export requires a streaming format: use geojson-seq or ndjson"),
+        other => return error_response(StatusCode::BAD_REQUEST, format!("unsupported export format '{}' (expected ndjson, geojson-seq or fgb)", other)),
+    };
+
+    if params.south > params.north || params.west > params.east {
+        return error_response(StatusCode::BAD_REQUEST, "invalid bbox: south/west must not exceed north/east");
+    }
+    let area = (params.north - params.south) * (params.east - params.west);
+    if area > state.config.server.max_export_bbox_area {
+        return error_response(StatusCode::BAD_REQUEST, format!("bbox area {:.4} deg² exceeds max_export_bbox_area {}", area, state.config.server.max_export_bbox_area));
+    }
+
+    let min_area = state.config.server.min_query_area_m2;
+    if min_area > 0.0 {
+        let mid_lat = ((params.north + params.south) / 2.0).to_radians();
+        let area_m2 = (params.north - params.south) * 111320.0 * (params.east - params.west) * 111320.0 * mid_lat.cos();
+        if area_m2.is_nan() || area_m2 < min_area {
+            return error_response(StatusCode::BAD_REQUEST, format!("bbox area {:.1} m² is below min_query_area_m2 {}", area_m2, min_area));
+        }
+    }
+
+    let version_state = state.clone();
+    let (coord_dp, max_value_len) = (params.coord_dp, params.max_value_len);
+    let candidates = collect_in_bbox(&state, [params.south as f32, params.west as f32], [params.north as f32, params.east as f32]);
+    if format == "fgb" {
+        let elements: Vec<ResultElement> = candidates.iter().map(|c| {
+            let mut result = ResultElement::new(c, resolve_tags(&state, c.tag_set_id));
+            result.round_coords(coord_dp);
+            result.truncate_values(max_value_len);
+            result
+        }).collect();
+        return with_dataset_version(&state, flatgeobuf_response(&elements));
+    }
+    let candidates = Arc::new(candidates);
+    let chunk_starts = (0..candidates.len()).step_by(EXPORT_CHUNK_SIZE);
+    let stream = futures_util::stream::iter(chunk_starts).map(move |start| {
+        let end = (start + EXPORT_CHUNK_SIZE).min(candidates.len());
+        let mut chunk = String::new();
+        for c in &candidates[start..end] {
+            let mut result = ResultElement::new(c, resolve_tags(&state, c.tag_set_id));
+            result.round_coords(coord_dp);
+            result.truncate_values(max_value_len);
+            if format == "geojson-seq" {
+                chunk.push('\u{1e}');
+                chunk.push_str(&geojson_feature(&result).to_string());
+            } else {
+                chunk.push_str(&serde_json::to_string(&result).unwrap_or_default());
+            }
+            chunk.push('\n');
+        }
+        Ok::<_, std::convert::Infallible>(chunk)
+    });
+
+    let response = ([(header::CONTENT_TYPE, content_type)], axum::body::Body::from_stream(stream)).into_response();
+    with_dataset_version(&version_state, response)
+}
+
+/// Union of several bbox queries in one round-trip, sharing one set of tag filters. Each box
+/// is validated like `/api/export`; a segment matched by overlapping boxes is returned once.
+async fn handle_query_multi(State(state): State<AppState>, Json(request): Json<MultiQueryRequest>) -> Response {
+    if request.bboxes.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "bboxes must not be empty");
+    }
+    for &[south, west, north, east] in &request.bboxes {
+        if south > north || west > east {
+            return error_response(StatusCode::BAD_REQUEST, format!("invalid bbox [{}, {}, {}, {}]: south/west must not exceed north/east", south, west, north, east));
+        }
+        let area = (north - south) * (east - west);
+        if area > state.config.server.max_export_bbox_area {
+            return error_response(StatusCode::BAD_REQUEST, format!("bbox area {:.4} deg² exceeds max_export_bbox_area {}", area, state.config.server.max_export_bbox_area));
+        }
+    }
+    let spec = FilterSpec {
+        tag_range: request.tag_range.as_deref(),
+        has_any: request.has_any.as_deref(),
+        tag_prefix: request.tag_prefix.as_deref(),
+        tag_contains: request.tag_contains.as_deref(),
+        tags: &request.tag,
+        split_multi: request.split_multi,
+    };
+    let predicates = match TagPredicates::parse(&state, &spec) {
+        Ok(p) => p,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e),
+    };
+
+    let key = |c: &Candidate| (c.id, c.p1[0].to_bits(), c.p1[1].to_bits(), c.p2[0].to_bits(), c.p2[1].to_bits());
+    let mut seen = std::collections::HashSet::new();
+    let mut candidates = Vec::new();
+    for &[south, west, north, east] in &request.bboxes {
+        for c in collect_in_bbox(&state, [south as f32, west as f32], [north as f32, east as f32]) {
+            if seen.insert(key(&c)) && (predicates.is_empty() || predicates.matches(&state, c.tag_set_id)) {
+                candidates.push(c);
+            }
+        }
+    }
+    candidates.sort_by_key(key);
+
+    let elements = candidates.iter().map(|c| {
+        let mut result = ResultElement::new(c, resolve_tags(&state, c.tag_set_id));
+        if request.split_multi {
+            result.split_multi_values();
+        }
+        result
+    }).collect();
+    with_dataset_version(&state, query_json_response(&state, QueryResponse { elements, next_cursor: None, facets: None, tag_sets: None }))
+}
+
+/// Elements inside or crossing a GeoJSON Polygon/MultiPolygon body (Feature and
+/// FeatureCollection wrappers are accepted). The r-tree prunes by the polygon's envelope, which
+/// is capped like `/api/query/multi` boxes; each candidate segment is then tested exactly.
+async fn handle_query_polygon(State(state): State<AppState>, Json(body): Json<serde_json::Value>) -> Response {
+    let polygon = match crate::geometry::ClipPolygon::from_geojson(&body) {
+        Ok(polygon) => polygon,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, format!("invalid polygon: {}", e)),
+    };
+    let [south, west, north, east] = polygon.bbox();
+    let area = (north - south) as f64 * (east - west) as f64;
+    if area > state.config.server.max_export_bbox_area {
+        return error_response(StatusCode::BAD_REQUEST, format!("polygon envelope area {:.4} deg² exceeds max_export_bbox_area {}", area, state.config.server.max_export_bbox_area));
+    }
+
+    let elements: Vec<ResultElement> = collect_in_bbox(&state, [south, west], [north, east]).into_iter()
+        .filter(|c| polygon.intersects_segment(c.p1, c.p2))
+        .map(|c| ResultElement::new(&c, resolve_tags(&state, c.tag_set_id)))
+        .collect();
+    with_dataset_version(&state, query_json_response(&state, QueryResponse { elements, next_cursor: None, facets: None, tag_sets: None }))
+}
+
+/// Find the way segment nearest to `query_point` (nodes are ignored).
+fn nearest_way_segment(state: &AppState, query_point: [f32; 2]) -> Option<Candidate> {
+    if let Some(rtree) = &state.rtree {
+        return rtree.nearest_neighbor_iter_with_distance_2(&query_point)
+            .find(|(se, _)| { let (p1, p2) = se.endpoints(); p1 != p2 })
+            .map(|(se, dist2)| {
+                let (p1, p2) = se.endpoints();
+                Candidate { dist2, id: se.id, p1, p2, tag_set_id: se.tag_set_id }
+            });
+    }
+    state.owned_elements.as_ref()?.iter()
+        .filter(|e| e.coordinates[0] != e.coordinates[1])
+        .map(|e| {
+            let (p1, p2) = (e.coordinates[0], e.coordinates[1]);
+            let dist2 = point_segment_distance2(query_point[0], query_point[1], p1[0], p1[1], p2[0], p2[1]);
+            Candidate { dist2, id: e.id, p1, p2, tag_set_id: e.tag_set_id }
+        })
+        .min_by(|a, b| a.dist2.partial_cmp(&b.dist2).unwrap_or(std::cmp::Ordering::Equal))
+}
+
+/// Snap a point onto the closest point of the nearest way (map-matching building block).
+async fn handle_snap(State(state): State<AppState>, Query(params): Query<SnapParams>) -> Response {
+    let query_point = [params.lat as f32, params.lon as f32];
+    let Some(c) = nearest_way_segment(&state, query_point) else {
+        return error_response(StatusCode::NOT_FOUND, "no way found to snap to");
+    };
+    let (_, foot) = project_onto_segment(query_point[0], query_point[1], c.p1[0], c.p1[1], c.p2[0], c.p2[1]);
+    Json(SnapResponse {
+        id: c.id,
+        snapped_lat: round_dp(foot[0] as f64, params.coord_dp),
+        snapped_lon: round_dp(foot[1] as f64, params.coord_dp),
+        distance_m: crate::geometry::haversine_m(query_point, foot),
+        tags: resolve_tags(&state, c.tag_set_id),
+    }).into_response()
+}
+
+/// Member ways of a preprocessed relation (see `[filters] relation_types`), reassembled from
+/// their segments.
+async fn handle_relation_members(State(state): State<AppState>, Path(id): Path<u64>) -> Response {
+    let Some(idx) = state.relations.find(id) else {
+        return error_response(StatusCode::NOT_FOUND, format!("relation {} not found", id));
+    };
+    let (way_ids, roles) = state.relations.members(idx);
+    let mut members = Vec::new();
+    let mut missing = Vec::new();
+    for (&way_id, &role) in way_ids.iter().zip(roles) {
+        let Some((vertices, tag_set_id)) = state.ways.get_with_tags(way_id) else {
+            missing.push(way_id);
+            continue;
+        };
+        let c = Candidate { dist2: 0.0, id: way_id, p1: vertices[0], p2: vertices[vertices.len() - 1], tag_set_id };
+        let mut element = ResultElement::new(&c, resolve_tags(&state, tag_set_id));
+        element.set_way_geometry(vertices);
+        members.push(RelationMember { role: state.interner.lookup(role).unwrap_or_default(), element });
+    }
+    let response = Json(RelationMembersResponse {
+        id,
+        tags: resolve_tags(&state, state.relations.tag_set_ids[idx]),
+        members,
+        missing,
+    }).into_response();
+    with_dataset_version(&state, response)
+}
+
+/// Overpass area id offsets for ways and relations
+const WAY_AREA_OFFSET: u64 = 2_400_000_000;
+const RELATION_AREA_OFFSET: u64 = 3_600_000_000;
+
+/// Closed `[lat, lon]` ring
+type Ring = Vec<[f32; 2]>;
+
+/// Outer and inner rings of an area: a closed way, or the member ways of a relation assembled
+/// into closed rings (members with role `inner` are holes; pieces that don't close are ignored).
+fn area_rings(state: &AppState, area_id: u64) -> Option<(Vec<Ring>, Vec<Ring>)> {
+    let (way_id, relation_id) = if area_id >= RELATION_AREA_OFFSET {
+        (None, Some(area_id - RELATION_AREA_OFFSET))
+    } else if area_id >= WAY_AREA_OFFSET {
+        (Some(area_id - WAY_AREA_OFFSET), None)
+    } else {
+        (Some(area_id), Some(area_id))
+    };
+
+    if let Some(vertices) = way_id.and_then(|id| state.ways.get(id)) {
+        if crate::geometry::is_closed(vertices) {
+            return Some((vec![vertices.to_vec()], Vec::new()));
+        }
+    }
+
+    let idx = relation_id.and_then(|id| state.relations.find(id))?;
+    let (way_ids, roles) = state.relations.members(idx);
+    let (mut outer, mut inner) = (Vec::new(), Vec::new());
+    for (&way_id, &role) in way_ids.iter().zip(roles) {
+        let Some(vertices) = state.ways.get(way_id) else {
+            continue;
+        };
+        if state.interner.lookup(role).as_deref() == Some("inner") {
+            inner.push(vertices);
+        } else {
+            outer.push(vertices);
+        }
+    }
+    let outer = crate::geometry::assemble_rings(&outer);
+    (!outer.is_empty()).then(|| (outer, crate::geometry::assemble_rings(&inner)))
+}
+
+/// Elements lying entirely inside an area (both segment endpoints inside an outer ring and
+/// outside every inner ring). 404 when the id does not resolve to closed area geometry.
+async fn handle_within(State(state): State<AppState>, Query(params): Query<WithinParams>) -> Response {
+    let Some((outer, inner)) = area_rings(&state, params.area_id) else {
+        return error_response(StatusCode::NOT_FOUND, format!("area {} not found or not a closed geometry", params.area_id));
+    };
+
+    let mut min = [f32::MAX, f32::MAX];
+    let mut max = [f32::MIN, f32::MIN];
+    for v in outer.iter().flat_map(|ring| ring.iter()) {
+        min = [min[0].min(v[0]), min[1].min(v[1])];
+        max = [max[0].max(v[0]), max[1].max(v[1])];
+    }
+
+    let inside = |p: [f32; 2]| {
+        outer.iter().any(|ring| crate::geometry::point_in_ring(p, ring))
+            && !inner.iter().any(|ring| crate::geometry::point_in_ring(p, ring))
+    };
+    let elements: Vec<ResultElement> = collect_in_bbox(&state, min, max).into_iter()
+        .filter(|c| inside(c.p1) && inside(c.p2))
+        .map(|c| ResultElement::new(&c, resolve_tags(&state, c.tag_set_id)))
+        .collect();
+    with_dataset_version(&state, query_json_response(&state, QueryResponse { elements, next_cursor: None, facets: None, tag_sets: None }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn msgpack_round_trip() {
+        let node = Candidate { dist2: 0.0, id: 7, p1: [52.5, 13.4], p2: [52.5, 13.4], tag_set_id: 0 };
+        let way = Candidate { dist2: 0.0, id: 9, p1: [52.5, 13.4], p2: [52.75, 13.5], tag_set_id: 1 };
+        let tags = HashMap::from([("amenity".to_string(), "cafe".to_string()), ("name".to_string(), "Café".to_string())]);
+        let elements = vec![ResultElement::new(&node, tags.clone()), ResultElement::new(&way, HashMap::new())];
+        let response = msgpack_response(&QueryResponse { elements, next_cursor: Some("abc".to_string()), facets: None, tag_sets: None });
+        assert_eq!(response.headers()[header::CONTENT_TYPE], MSGPACK_CONTENT_TYPE);
+
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded["next_cursor"], "abc");
+        let elements = decoded["elements"].as_array().unwrap();
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements[0]["id"], 7);
+        assert_eq!(elements[0]["type"], "node");
+        assert_eq!(elements[0]["lat1"], 52.5);
+        let decoded_tags: HashMap<String, String> = serde_json::from_value(elements[0]["tags"].clone()).unwrap();
+        assert_eq!(decoded_tags, tags);
+        assert_eq!(elements[1]["id"], 9);
+        assert_eq!(elements[1]["type"], "way");
+        assert_eq!(elements[1]["lat2"], 52.75);
+        assert!(elements[1].get("tags").is_none());
+    }
+
+    #[test]
+    fn dataset_version_tracks_response_settings() {
+        let server = |extra: &str| -> crate::config::Server {
+            toml::from_str(&format!("host = \"127.0.0.1\"\nport = 0\n{}", extra)).unwrap()
+        };
+        let base = dataset_version(1, &server(""));
+        assert_eq!(base, dataset_version(1, &server("slow_query_ms = 500")));
+        assert_ne!(base, dataset_version(2, &server("")));
+        for setting in ["overpass_compat = true", "response_tag_allowlist = [\"name\"]", "hoist_keys = [\"name\"]", "hoist_keep_in_tags = false"] {
+            assert_ne!(base, dataset_version(1, &server(setting)), "{}", setting);
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        for pos in [(0, 0), (1, 2), (u32::MAX as u64 + 1, 7), (u64::MAX, u32::MAX)] {
+            assert_eq!(decode_cursor(&encode_cursor(pos)), Some(pos));
+        }
+    }
+
+    #[test]
+    fn malformed_or_foreign_cursor_is_rejected() {
+        let standard = base64::engine::general_purpose::STANDARD.encode([0xfb; 12]);
+        for cursor in ["", "not a cursor", "AAAA", standard.as_str(), &URL_SAFE_NO_PAD.encode([1; 8]), &URL_SAFE_NO_PAD.encode([1; 16])] {
+            assert_eq!(decode_cursor(cursor), None, "{:?}", cursor);
+        }
+    }
+
+    /// Three ways with several segments and two nodes, in no particular order
+    fn pagination_candidates() -> Vec<Candidate> {
+        let segment = |id: u64, i: u32| Candidate { dist2: 0.0, id, p1: [i as f32, 0.0], p2: [i as f32 + 1.0, 0.0], tag_set_id: 0 };
+        vec![segment(30, 2), segment(5, 0), segment(30, 0), segment(12, 1), segment(7, 0), segment(12, 0), segment(30, 1), segment(12, 2)]
+    }
+
+    /// Follow `next_cursor` from the first page to the last, reordering the candidates before every
+    /// request like a fresh spatial query would
+    fn all_pages(one_per_id: bool, limit: usize) -> Vec<(u64, [f32; 2])> {
+        let mut seen = Vec::new();
+        let mut after = None;
+        for request in 0.. {
+            let mut candidates = pagination_candidates();
+            let shift = request % candidates.len();
+            candidates.rotate_left(shift);
+            let (page, next) = paginate(candidates, one_per_id, after, limit);
+            assert!(page.len() <= limit);
+            seen.extend(page.iter().map(|c| (c.id, c.p1)));
+            match next {
+                Some(cursor) => after = Some(decode_cursor(&cursor).unwrap()),
+                None => break,
+            }
+        }
+        seen
+    }
+
+    #[test]
+    fn pagination_resumes_without_duplicates_or_gaps() {
+        let mut expected: Vec<(u64, [f32; 2])> = pagination_candidates().iter().map(|c| (c.id, c.p1)).collect();
+        expected.sort_by(|a, b| (a.0, a.1[0]).partial_cmp(&(b.0, b.1[0])).unwrap());
+        for limit in [1, 2, 3, 7, 8, 100] {
+            assert_eq!(all_pages(false, limit), expected, "limit={}", limit);
+        }
+    }
+
+    #[test]
+    fn pagination_one_per_id_returns_each_id_once() {
+        let expected = vec![(5, [0.0, 0.0]), (7, [0.0, 0.0]), (12, [0.0, 0.0]), (30, [0.0, 0.0])];
+        for limit in [1, 2, 3, 4, 100] {
+            assert_eq!(all_pages(true, limit), expected, "limit={}", limit);
+        }
+    }
+
+    #[test]
+    fn parallel_json_matches_serial_encoding() {
+        let candidate = |id: u64| Candidate { dist2: 0.0, id, p1: [52.5, 13.4], p2: [52.5 + id as f32 * 1e-4, 13.4], tag_set_id: 0 };
+        let element = |id: u64| {
+            let tags = HashMap::from([("name".to_string(), format!("\"Straße\" {}\n\\", id))]);
+            ResultElement::new(&candidate(id), if id.is_multiple_of(3) { HashMap::new() } else { tags })
+        };
+        for count in [0, 1, 2, 3, 4, 6, 7, 10] {
+            for (next_cursor, facets) in [(None, None), (Some("abc".to_string()), Some(HashMap::from([("cafe".to_string(), 2)])))] {
+                let mut response = QueryResponse { elements: (1..=count).map(element).collect(), next_cursor, facets, tag_sets: None };
+                let serial = serde_json::to_vec(&response).unwrap();
+                for chunk_elements in [1, 3, 4, 16] {
+                    let parallel = parallel_query_json(&mut response, chunk_elements).unwrap();
+                    assert_eq!(String::from_utf8(parallel).unwrap(), String::from_utf8(serial.clone()).unwrap(), "{} elements in chunks of {}", count, chunk_elements);
+                    assert_eq!(response.elements.len(), count as usize);
+                }
+            }
+        }
+    }
+
+    /// Features for `test_state`: `(id, [[lat1, lon1], [lat2, lon2]], tags)`
+    type Feature<'a> = (u64, [[f32; 2]; 2], &'a [(&'a str, &'a str)]);
+
+    /// An `AppState` over `features`, each with its own tag set; `server` is appended to `[server]`.
+    fn test_state(server: &str, features: &[Feature]) -> AppState {
+        use crate::model::{pack_kv, Element, FlatTagSets};
+        let config: Config = toml::from_str(&format!(
+            "[filters]\nprimary_keys = [\"highway\", \"amenity\"]\n[storage]\ncache_dir = \"/tmp\"\n[server]\nhost = \"127.0.0.1\"\nport = 0\n{}",
+            server
+        )).unwrap();
+        let interner = StringInterner::default();
+        let mut tag_sets = FlatTagSets::default();
+        let mut elements = Vec::new();
+        for (i, &(id, coordinates, tags)) in features.iter().enumerate() {
+            tag_sets.offsets.push(tag_sets.data.len() as u32);
+            tag_sets.lengths.push(tags.len() as u32);
+            tag_sets.data.extend(tags.iter().map(|&(k, v)| pack_kv(interner.get_or_intern(k), interner.get_or_intern(v))));
+            elements.push(Element { id, coordinates, tag_set_id: i as u32 });
+        }
+        let cache = crate::preprocessor::LoadedCache::Owned { elements, tag_sets, interner, relations: RelationTable::default(), h3_cells: Vec::new(), source_hash: 0 };
+        build_state(&config, cache).unwrap()
+    }
+
+    fn point(lat: f32, lon: f32) -> [[f32; 2]; 2] {
+        [[lat, lon], [lat, lon]]
+    }
+
+    fn filter_spec(tag_range: Option<&str>) -> FilterSpec<'_> {
+        FilterSpec { tag_range, has_any: None, tag_prefix: None, tag_contains: None, tags: &[], split_multi: false }
+    }
+
+    #[test]
+    fn parse_numeric_ignores_units() {
+        for (value, expected) in [("50", Some(50.0)), (" 50 km/h", Some(50.0)), ("3.5m", Some(3.5)), ("-2", Some(-2.0)), ("+4", Some(4.0)), ("0.5 t", Some(0.5)), ("5-7", Some(5.0))] {
+            assert_eq!(parse_numeric(value), expected, "{:?}", value);
+        }
+        for value in ["", "none", "km 5", "-", "1.2.3"] {
+            assert_eq!(parse_numeric(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn tag_range_bounds() {
+        let features: &[Feature] = &[
+            (1, point(0.0, 0.0), &[("highway", "primary"), ("maxspeed", "30")]),
+            (2, point(0.0, 0.0), &[("highway", "primary"), ("maxspeed", "50 km/h")]),
+            (3, point(0.0, 0.0), &[("highway", "primary"), ("maxspeed", "signals")]),
+            (4, point(0.0, 0.0), &[("highway", "primary")]),
+        ];
+        let state = test_state("", features);
+        let matching = |spec: &str| -> Vec<u64> {
+            let predicates = TagPredicates::parse(&state, &filter_spec(Some(spec))).unwrap();
+            features.iter().enumerate().filter(|&(i, _)| predicates.matches(&state, i as u32)).map(|(_, f)| f.0).collect()
+        };
+        assert_eq!(matching("maxspeed:30..50"), [1, 2]);
+        assert_eq!(matching("maxspeed:..40"), [1]);
+        assert_eq!(matching("maxspeed:40.."), [2]);
+        // open on both ends: any numeric value, but never a missing or non-numeric one
+        assert_eq!(matching("maxspeed:.."), [1, 2]);
+        assert_eq!(matching("maxspeed:50..30"), Vec::<u64>::new());
+        assert_eq!(matching("width:..10"), Vec::<u64>::new());
+
+        for spec in ["maxspeed", "maxspeed:30", "maxspeed:a..b", "maxspeed:30..fast"] {
+            assert!(TagPredicates::parse(&state, &filter_spec(Some(spec))).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn type_bias_parsing() {
+        assert_eq!(parse_type_bias(None), Ok((1.0, 1.0)));
+        assert_eq!(parse_type_bias(Some("")), Ok((1.0, 1.0)));
+        assert_eq!(parse_type_bias(Some("node:2,way:0.5,")), Ok((2.0, 0.5)));
+        assert_eq!(parse_type_bias(Some("way:3")), Ok((1.0, 3.0)));
+        for spec in ["node", "node:", "node:x", "node:0", "way:-1", "way:inf", "way:NaN", "area:2"] {
+            assert!(parse_type_bias(Some(spec)).is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn score_weights() {
+        let features: &[Feature] = &[
+            (1, point(0.0, 0.0), &[("amenity", "cafe"), ("cuisine", "pizza")]),
+            (2, point(0.0, 0.0), &[("amenity", "bench")]),
+            (3, point(0.0, 0.0), &[("highway", "primary")]),
+        ];
+        let state = test_state("", features);
+        let weights = ScoreWeights::parse(&state, "amenity=cafe:3,bench:0.5,unknown:9", 1.5).unwrap();
+        assert_eq!([0, 1, 2].map(|i| weights.weight(&state, i)), [3.0, 0.5, 1.5]);
+        let weights = ScoreWeights::parse(&state, "missing=cafe:3", 2.0).unwrap();
+        assert_eq!(weights.weight(&state, 0), 2.0);
+
+        for (spec, default) in [("amenity", 1.0), ("amenity=cafe", 1.0), ("amenity=cafe:x", 1.0), ("amenity=cafe:0", 1.0), ("amenity=cafe:-1", 1.0), ("amenity=cafe:2", 0.0), ("amenity=cafe:2", f64::NAN)] {
+            assert!(ScoreWeights::parse(&state, spec, default).is_err(), "{:?} default {}", spec, default);
+        }
+
+        // keys outside the allowlist are unknown, so they never change the ranking
+        let state = test_state("response_tag_allowlist = [\"amenity\"]", features);
+        let weights = ScoreWeights::parse(&state, "cuisine=pizza:5", 1.0).unwrap();
+        assert_eq!(weights.weight(&state, 0), 1.0);
+    }
+
+    #[test]
+    fn sample_is_deterministic() {
+        let ids = 0..20_000u64;
+        let kept = |fraction: f64| -> Vec<u64> { ids.clone().filter(|&id| sample_keeps(id, fraction)).collect() };
+        assert!(kept(0.0).is_empty());
+        assert_eq!(kept(1.0).len(), 20_000);
+        let quarter = kept(0.25);
+        assert_eq!(quarter, kept(0.25));
+        assert!((4_500..5_500).contains(&quarter.len()), "{}", quarter.len());
+        // a larger fraction keeps a superset
+        let half = kept(0.5);
+        assert!(quarter.iter().all(|id| half.binary_search(id).is_ok()));
+        assert_eq!(sample_keeps(123_456_789, 0.3), sample_keeps(123_456_789, 0.3));
+    }
+
+    #[test]
+    fn collapse_keeps_the_best_of_each_cluster() {
+        // ~5 m apart at 52.5° N: 1 and 2 collapse, 3 is another value, 4 is ~110 m away;
+        // way 5 lies next to way 6 with the same value, so all of 6's segments go
+        let features: &[Feature] = &[
+            (1, point(52.5, 13.4), &[("amenity", "cafe"), ("name", "A")]),
+            (2, point(52.50004, 13.4), &[("amenity", "cafe"), ("name", "B")]),
+            (3, point(52.50002, 13.4), &[("amenity", "bench"), ("name", "A")]),
+            (4, point(52.501, 13.4), &[("amenity", "cafe"), ("name", "A")]),
+            (5, [[52.6, 13.4], [52.6, 13.4001]], &[("highway", "footway")]),
+            (6, [[52.60003, 13.4], [52.60003, 13.4001]], &[("highway", "footway")]),
+            (7, point(52.6, 13.4), &[("name", "untagged primary")]),
+        ];
+        let state = test_state("", features);
+        let candidates = || -> Vec<Candidate> {
+            let mut candidates: Vec<Candidate> = features.iter().enumerate()
+                .map(|(i, &(id, [p1, p2], _))| Candidate { dist2: 0.0, id, p1, p2, tag_set_id: i as u32 })
+                .collect();
+            // a second segment of way 6 further along
+            candidates.push(Candidate { dist2: 0.0, id: 6, p1: [52.60003, 13.4001], p2: [52.60003, 13.4002], tag_set_id: 5 });
+            candidates
+        };
+        let ids = |candidates: &[Candidate]| candidates.iter().map(|c| c.id).collect::<Vec<_>>();
+
+        let mut collapsed = candidates();
+        let merged = collapse_candidates(&state, &mut collapsed, 10.0, None, 52.5);
+        assert_eq!(ids(&collapsed), [1, 3, 4, 5, 7]);
+        assert_eq!(merged, HashMap::from([(1, 2), (5, 2)]));
+
+        // same input, same result; ranking decides which element of a cluster survives
+        let mut again = candidates();
+        assert_eq!(collapse_candidates(&state, &mut again, 10.0, None, 52.5), merged);
+        assert_eq!(ids(&again), ids(&collapsed));
+        let mut reversed = candidates();
+        reversed.swap(0, 1);
+        collapse_candidates(&state, &mut reversed, 10.0, None, 52.5);
+        assert_eq!(ids(&reversed), [2, 3, 4, 5, 7]);
+
+        // grouping by name: 1 and 3 share "A" and are close, 2 is "B"
+        let mut by_name = candidates();
+        collapse_candidates(&state, &mut by_name, 10.0, Some("name"), 52.5);
+        assert_eq!(ids(&by_name), [1, 2, 4, 5, 6, 7, 6]);
+
+        // a key outside the allowlist is unknown: nothing collapses
+        let state = test_state("response_tag_allowlist = [\"amenity\", \"highway\"]", features);
+        let mut hidden = candidates();
+        assert!(collapse_candidates(&state, &mut hidden, 10.0, Some("name"), 52.5).is_empty());
+        assert_eq!(hidden.len(), candidates().len());
+    }
+
+    #[test]
+    fn truncate_value_on_char_boundary() {
+        let truncated = |value: &str, max: usize| {
+            let mut value = value.to_string();
+            truncate_value(&mut value, max);
+            value
+        };
+        assert_eq!(truncated("café", 5), "café");
+        // 'é' spans bytes 3..5
+        assert_eq!(truncated("café", 4), "caf…");
+        assert_eq!(truncated("café", 3), "caf…");
+        assert_eq!(truncated("日本語", 4), "日…");
+        assert_eq!(truncated("日本語", 2), "…");
+        assert_eq!(truncated("abc", 0), "…");
+        assert_eq!(truncated("", 0), "");
+    }
 }
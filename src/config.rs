@@ -1,50 +1,308 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 use anyhow::{Context, Result};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub filters: Filters,
     pub storage: Storage,
     pub server: Server,
+    #[serde(default)]
     pub profiling: Profiling,
     #[serde(default)]
     pub runtime: Runtime,
 }
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct Runtime {
     /// If true, drop the internal `HashMap<String,u32>` after loading the cache to save RAM.
     /// The `pool` + `offsets`/`lengths` are kept so `lookup(id)` still works.
     #[serde(default = "default_drop_interner_map")]
     pub drop_interner_map: bool,
+    /// With the map dropped, build a sorted id index (4 bytes per string) so string -> id
+    /// lookups for query filters use binary search instead of a linear pool scan.
+    #[serde(default = "default_string_index")]
+    pub string_index: bool,
+    /// Douglas-Peucker tolerance (metres) applied to way geometry in pass 3 (0 = disabled).
+    /// Reduces segment count at the cost of geometry precision; changes require re-preprocessing.
+    #[serde(default)]
+    pub simplify_tolerance_m: f64,
+    /// Size of the dedicated preprocessing thread pool (0 = available parallelism)
+    #[serde(default)]
+    pub preprocess_threads: usize,
+    /// Threads for pass 2 (coordinate collection, memory-bound); 0 = use `preprocess_threads`
+    #[serde(default)]
+    pub pass2_threads: usize,
+    /// Threads for pass 3 (tag extraction, CPU-bound); 0 = use `preprocess_threads`
+    #[serde(default)]
+    pub pass3_threads: usize,
+    /// Start with an empty dataset (instead of exiting) when no `--input` is given, or when there
+    /// is no cache and a local input does not exist yet. Lets orchestration bring the service up
+    /// before data is staged; every other load error (corrupt cache, failed download, abort
+    /// policies) still fails startup.
+    #[serde(default)]
+    pub allow_empty: bool,
+    /// Store element ids in the cache as dense u32 indices plus an id table. Only shrinks the
+    /// cache file: ids are expanded back to u64 on load, so server memory is unchanged. Responses
+    /// still report the original OSM ids. Requires re-preprocessing.
+    #[serde(default)]
+    pub compact_ids: bool,
+    /// Log per-pass throughput every N seconds during preprocessing (0 = off)
+    #[serde(default)]
+    pub progress_interval_secs: u64,
+    /// Store each element's H3 cell (of its midpoint) at this resolution (0-15) and serve
+    /// `/api/h3/:cell`. Requires re-preprocessing; `None` = disabled.
+    #[serde(default)]
+    pub h3_resolution: Option<u8>,
+    /// Refuse to start when the estimated r-tree size exceeds this many MB (0 = no limit)
+    #[serde(default)]
+    pub rtree_memory_limit_mb: u64,
+    /// Free the pass-2 coordinate store (and required-node set, input mappings) as soon as pass 3
+    /// ends instead of keeping them alive through serialization
+    #[serde(default)]
+    pub free_coords_after_pass3: bool,
+    /// Bboxes (`[south, west, north, east]`) queried once at startup to warm hot areas
+    #[serde(default)]
+    pub preload_regions: Vec<[f64; 4]>,
+    /// Warn loudly when the interner string pool plus the packed tag-set data exceed this many MB
+    /// (0 = no warning)
+    #[serde(default)]
+    pub pool_warn_mb: u64,
+    /// Re-log the pool sizes every N seconds while serving (0 = only at startup)
+    #[serde(default)]
+    pub pool_log_interval_secs: u64,
 }
 
 fn default_drop_interner_map() -> bool { true }
+fn default_string_index() -> bool { true }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Filters {
     pub primary_keys: Vec<String>,
+    /// Extra keys stored on elements that matched a primary key; `["*"]` keeps every tag
+    #[serde(default)]
     pub attribute_keys: Vec<String>,
+    /// Drop elements whose extracted tag set has fewer than this many pairs (0 = keep all).
+    /// Note: this changes element counts and requires re-preprocessing.
+    #[serde(default)]
+    pub min_tag_count: usize,
+    /// Keep at most this many tag pairs per element, primaries first (0 = unlimited)
+    #[serde(default)]
+    pub max_tags_per_element: usize,
+    /// Which occurrence to keep when one element carries the same key twice
+    #[serde(default)]
+    pub on_duplicate_key: DuplicateKeyPolicy,
+    /// Relation `type` values to keep with their way members (e.g. `route`, `multipolygon`).
+    /// Empty = relations are ignored.
+    #[serde(default)]
+    pub relation_types: Vec<String>,
+    /// Cap on distinct tag sets created in pass 3 (0 = unlimited); see `on_tag_set_overflow`
+    #[serde(default)]
+    pub max_tag_sets: usize,
+    #[serde(default)]
+    pub on_tag_set_overflow: TagSetOverflowPolicy,
+    /// Tag strings that are not valid UTF-8: skip the tag, replace bad bytes with U+FFFD, or abort
+    #[serde(default)]
+    pub on_invalid_utf8: InvalidUtf8Policy,
+    /// Pass 1 also requires the nodes of ways matching `attribute_keys`, not only `primary_keys`
+    /// (more complete geometry, more stored coordinates)
+    #[serde(default)]
+    pub require_attribute_way_nodes: bool,
+    /// Nodes outside ±90° lat / ±180° lon: skip them, clamp them into range, or abort
+    #[serde(default)]
+    pub on_bad_coords: BadCoordsPolicy,
+    /// Abort preprocessing (instead of warning) when pass 3 matches no element at all
+    #[serde(default)]
+    pub error_on_empty: bool,
+    /// GeoJSON (Multi)Polygon file: keep only nodes inside it and ways with any node inside it
+    #[serde(default)]
+    pub clip_polygon: Option<PathBuf>,
+    /// Keep only nodes within any of these circles and ways with any node within one; combined
+    /// with `clip_polygon`, a point inside either is kept
+    #[serde(default)]
+    pub include_circles: Vec<IncludeCircle>,
+    /// Synonymous keys interned under one canonical name in pass 3 (alias -> canonical, e.g.
+    /// `"contact:phone" = "phone"`); also applied to `primary_keys`/`attribute_keys`
+    #[serde(default)]
+    pub key_aliases: BTreeMap<String, String>,
+}
+
+/// `[filters] include_circles` entry
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct IncludeCircle {
+    pub lat: f64,
+    pub lon: f64,
+    pub radius_m: f64,
+}
+
+impl Filters {
+    /// `key` after `key_aliases`: its canonical name, or the key itself.
+    pub fn canonical_key<'a>(&'a self, key: &'a str) -> &'a str {
+        self.key_aliases.get(key).map_or(key, |k| k.as_str())
+    }
+
+    /// A configured key list (`primary_keys`, `attribute_keys`) with aliases resolved.
+    pub fn canonical_keys<'a>(&'a self, keys: &'a [String]) -> HashSet<&'a str> {
+        keys.iter().map(|k| self.canonical_key(k)).collect()
+    }
+}
+
+/// What happens to nodes with out-of-range coordinates.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum BadCoordsPolicy {
+    #[default]
+    Skip,
+    Clamp,
+    Error,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// What happens to tags whose key or value is not valid UTF-8.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidUtf8Policy {
+    #[default]
+    Skip,
+    Replace,
+    Error,
+}
+
+/// What happens to elements whose tag set would exceed `max_tag_sets`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum TagSetOverflowPolicy {
+    /// Assign them a shared empty "misc" tag set
+    #[default]
+    Misc,
+    /// Abort preprocessing
+    Error,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKeyPolicy {
+    First,
+    /// Matches the previous behavior, where the last value won in the response map
+    #[default]
+    Last,
+    /// Abort preprocessing when any element has duplicate keys
+    Error,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Storage {
     pub cache_dir: PathBuf,
     /// zstd compression level used when writing the cache (0-22). Default = 3 (fast).
     #[serde(default = "default_zstd_level")]
     pub zstd_level: u32,
+    /// Connect/read timeout for `http(s)://` inputs, in seconds
+    #[serde(default = "default_download_timeout_secs")]
+    pub download_timeout_secs: u64,
+    /// Extra attempts (with exponential backoff and range resume) after a failed download
+    #[serde(default = "default_download_retries")]
+    pub download_retries: u32,
+    /// Keep pass-2 node coordinates in a memory-mapped file in `cache_dir` instead of RAM
+    #[serde(default)]
+    pub coords_spill: bool,
+    /// Only spill when the in-memory coordinate map is estimated to exceed this many MB
+    #[serde(default)]
+    pub coords_spill_min_mb: u64,
+    /// Memory-map each input PBF once and share the mapping across all preprocessing passes
+    #[serde(default)]
+    pub mmap_input: bool,
+    /// Also write the extraction as Parquet (`elements`, `tag_sets`, `strings`) into this directory
+    #[serde(default)]
+    pub emit_parquet: Option<PathBuf>,
+    /// Sort elements and renumber strings/tag sets canonically so the cache is bit-reproducible
+    #[serde(default)]
+    pub deterministic: bool,
+    /// Store elements grouped by grid cell and write a `bbox_index.bin` sidecar with each cell's
+    /// element and byte range
+    #[serde(default)]
+    pub bbox_index: bool,
+    /// Grid cell size of `bbox_index`, in degrees
+    #[serde(default = "default_bbox_index_cell_deg")]
+    pub bbox_index_cell_deg: f64,
 }
 
 fn default_zstd_level() -> u32 { 3 }
+fn default_bbox_index_cell_deg() -> f64 { 1.0 }
+fn default_download_timeout_secs() -> u64 { 60 }
+fn default_download_retries() -> u32 { 3 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Server {
     pub host: String,
     pub port: u16,
+    /// Tag keys emitted as top-level result fields (for consumers that expect fixed columns)
+    #[serde(default)]
+    pub hoist_keys: Vec<String>,
+    /// If false, hoisted keys are removed from the nested `tags` map instead of duplicated
+    #[serde(default = "default_hoist_keep_in_tags")]
+    pub hoist_keep_in_tags: bool,
+    /// Only these tag keys are ever returned (tags, raw tags, hoisted fields, facets) or usable in
+    /// filters, scoring and grouping; unset = all
+    #[serde(default)]
+    pub response_tag_allowlist: Option<Vec<String>>,
+    /// Expose debug/admin endpoints such as `/api/interner` and the `engine` query parameter
+    /// (off by default; keeps an extra flat copy of the elements for `engine=scan`)
+    #[serde(default)]
+    pub debug_endpoints: bool,
+    /// Serve `/api/density/:z/:x/:y` (element count per XYZ tile, for coverage heatmaps)
+    #[serde(default)]
+    pub density_endpoint: bool,
+    /// Largest bbox (in square degrees) accepted by the `/api/export` bulk download; also caps
+    /// `/api/query/multi` boxes and the `/api/query/polygon` envelope
+    #[serde(default = "default_max_export_bbox_area")]
+    pub max_export_bbox_area: f64,
+    /// Reject `/api/query` circles and `/api/export` boxes smaller than this many m² (0 = off)
+    #[serde(default)]
+    pub min_query_area_m2: f64,
+    /// Largest accepted request body (POST routes); larger bodies get 413
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Shut down gracefully after this many seconds without requests (`/health` excluded; 0 = never)
+    #[serde(default)]
+    pub idle_shutdown_secs: u64,
+    /// Log a warning for `/api/query` requests taking longer than this many ms (0 = off)
+    #[serde(default)]
+    pub slow_query_ms: u64,
+    /// Encode `/api/query` JSON `elements` in parallel chunks from this many results up (0 = never)
+    #[serde(default = "default_parallel_json_min_elements")]
+    pub parallel_json_min_elements: usize,
+    /// Overpass-style JSON results: `lat`/`lon` for points, a `geometry` array for ways
+    #[serde(default)]
+    pub overpass_compat: bool,
+    /// Compress responses (gzip) for clients that accept it
+    #[serde(default)]
+    pub compression: bool,
+    /// Content types never compressed, matched by prefix (already compact formats)
+    #[serde(default = "default_compression_exclude_types")]
+    pub compression_exclude_types: Vec<String>,
+    /// `Cache-Control` value for responses (e.g. `public, max-age=3600`); unset = no header
+    #[serde(default)]
+    pub cache_control: Option<String>,
+    /// Per-route `Cache-Control` overrides keyed by path prefix (longest prefix wins)
+    #[serde(default = "default_cache_control_routes")]
+    pub cache_control_routes: HashMap<String, String>,
+}
+
+fn default_hoist_keep_in_tags() -> bool { true }
+fn default_max_export_bbox_area() -> f64 { 1.0 }
+fn default_parallel_json_min_elements() -> usize { 20_000 }
+fn default_max_body_bytes() -> usize { 1024 * 1024 }
+fn default_cache_control_routes() -> HashMap<String, String> {
+    HashMap::from([
+        ("/health".to_string(), "no-store".to_string()),
+        ("/api/stats".to_string(), "no-cache".to_string()),
+    ])
+}
+fn default_compression_exclude_types() -> Vec<String> {
+    vec!["application/x-protobuf".into(), "application/vnd.mapbox-vector-tile".into()]
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Profiling {
     /// Enable/disable CPU sampling profiler (writes a flamegraph when enabled)
     #[serde(default = "default_profiling_enabled")]
@@ -57,16 +315,215 @@ pub struct Profiling {
     pub frequency: u64,
 }
 
+impl Default for Profiling {
+    fn default() -> Self {
+        Profiling { enabled: default_profiling_enabled(), out: default_profiling_out(), frequency: default_profiling_freq() }
+    }
+}
+
 fn default_profiling_enabled() -> bool { false }
 fn default_profiling_out() -> String { "profile.svg".into() }
 fn default_profiling_freq() -> u64 { 100 }
 
+/// Config file read when no `--config` is given
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Prefix of environment overrides: `OVERPASS__<SECTION>__<KEY>=<value>`, e.g.
+/// `OVERPASS__SERVER__PORT=8080` or `OVERPASS__FILTERS__PRIMARY_KEYS='["highway"]'`.
+const ENV_PREFIX: &str = "OVERPASS__";
+
+/// Settings without defaults; an env-only configuration must provide all of them.
+const REQUIRED_FIELDS: [(&str, &str); 4] = [
+    ("filters", "primary_keys"),
+    ("storage", "cache_dir"),
+    ("server", "host"),
+    ("server", "port"),
+];
+
+/// Substrings of field names whose values `redacted_json` hides
+const SENSITIVE_FIELD_MARKERS: [&str; 4] = ["token", "secret", "password", "key_path"];
+
 impl Config {
-    pub fn from_file(path: &PathBuf) -> Result<Self> {
-        let content = std::fs::read_to_string(path)
-            .with_context(|| format!("Failed to read config file: {:?}", path))?;
-        let config: Config = toml::from_str(&content)
-            .with_context(|| "Failed to parse config file (TOML)")?;
+    /// The effective config as JSON (after env overrides), with sensitive values replaced by
+    /// `"<redacted>"`.
+    pub fn redacted_json(&self) -> Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        redact(&mut value);
+        Ok(value)
+    }
+
+    /// Load and merge one or more TOML files. Later files override earlier ones; tables are
+    /// deep-merged so an override file only needs to set the keys it changes. `OVERPASS__*`
+    /// environment variables are applied last. Without explicit paths, `DEFAULT_CONFIG_PATH` is
+    /// read if it exists and the configuration is otherwise built from the environment alone;
+    /// an explicitly given file that is missing is an error.
+    pub fn from_files(paths: &[PathBuf]) -> Result<Self> {
+        let mut merged = toml::Value::Table(toml::map::Map::new());
+        let default_paths = [PathBuf::from(DEFAULT_CONFIG_PATH)];
+        let paths: &[PathBuf] = match paths {
+            [] if default_paths[0].exists() => &default_paths[..],
+            [] => {
+                tracing::info!("No {} found; reading configuration from {}* environment variables", DEFAULT_CONFIG_PATH, ENV_PREFIX);
+                &[]
+            }
+            explicit => explicit,
+        };
+        for path in paths {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config file: {:?}", path))?;
+            let value: toml::Value = toml::from_str(&content)
+                .with_context(|| format!("Failed to parse config file (TOML): {:?}", path))?;
+            merge_values(&mut merged, value);
+        }
+        merge_values(&mut merged, env_overrides(std::env::vars()));
+
+        let missing: Vec<String> = REQUIRED_FIELDS.iter()
+            .filter(|(section, key)| merged.get(section).and_then(|t| t.get(key)).is_none())
+            .map(|(section, key)| format!("{}.{} ({}{}__{})", section, key, ENV_PREFIX, section.to_uppercase(), key.to_uppercase()))
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!("missing required configuration: {}", missing.join(", "));
+        }
+
+        let config: Config = merged.try_into()
+            .with_context(|| "Failed to parse merged configuration")?;
         Ok(config)
     }
 }
+
+/// Replace the value of every non-null field whose name contains a `SENSITIVE_FIELD_MARKERS`
+/// entry, at any depth.
+fn redact(value: &mut serde_json::Value) {
+    if let serde_json::Value::Object(map) = value {
+        for (name, field) in map.iter_mut() {
+            if SENSITIVE_FIELD_MARKERS.iter().any(|m| name.contains(m)) && !field.is_null() {
+                *field = serde_json::Value::from("<redacted>");
+            } else {
+                redact(field);
+            }
+        }
+    }
+}
+
+/// Collect `OVERPASS__SECTION__KEY` variables into a TOML table. Values are parsed as TOML
+/// (numbers, booleans, arrays) and fall back to a plain string.
+fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> toml::Value {
+    let mut root = toml::map::Map::new();
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else { continue };
+        let Some((section, key)) = path.split_once("__") else { continue };
+        let value = toml::from_str::<toml::Table>(&format!("v = {}", raw)).ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or(toml::Value::String(raw));
+        let table = root.entry(section.to_lowercase())
+            .or_insert_with(|| toml::Value::Table(toml::map::Map::new()));
+        if let toml::Value::Table(table) = table {
+            table.insert(key.to_lowercase(), value);
+        }
+    }
+    toml::Value::Table(root)
+}
+
+/// Deep-merge `overlay` into `base`: tables are merged key by key, any other value replaces.
+fn merge_values(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base_tbl), toml::Value::Table(overlay_tbl)) => {
+            for (k, v) in overlay_tbl {
+                match base_tbl.get_mut(&k) {
+                    Some(existing) => merge_values(existing, v),
+                    None => { base_tbl.insert(k, v); }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toml_value(content: &str) -> toml::Value {
+        toml::from_str(content).unwrap()
+    }
+
+    #[test]
+    fn merge_values_deep_merges_in_order() {
+        let mut merged = toml_value("[server]\nhost = \"127.0.0.1\"\nport = 3000\nhoist_keys = [\"name\", \"ref\"]\n[server.cache_control_routes]\n\"/health\" = \"no-store\"\n[storage]\ncache_dir = \"/cache\"");
+        merge_values(&mut merged, toml_value("[server]\nport = 8080\nhoist_keys = [\"surface\"]\n[server.cache_control_routes]\n\"/api/stats\" = \"no-cache\""));
+        merge_values(&mut merged, toml_value("[server]\nport = 9090\n[filters]\nprimary_keys = [\"highway\"]"));
+
+        let server = &merged["server"];
+        assert_eq!(server["port"].as_integer(), Some(9090));
+        assert_eq!(server["host"].as_str(), Some("127.0.0.1"));
+        // arrays are replaced, not concatenated
+        assert_eq!(server["hoist_keys"], toml_value("v = [\"surface\"]")["v"]);
+        assert_eq!(server["cache_control_routes"]["/health"].as_str(), Some("no-store"));
+        assert_eq!(server["cache_control_routes"]["/api/stats"].as_str(), Some("no-cache"));
+        assert_eq!(merged["storage"]["cache_dir"].as_str(), Some("/cache"));
+        assert_eq!(merged["filters"]["primary_keys"][0].as_str(), Some("highway"));
+    }
+
+    #[test]
+    fn env_overrides_coerce_types() {
+        let vars = [
+            ("OVERPASS__SERVER__PORT", "8080"),
+            ("OVERPASS__SERVER__OVERPASS_COMPAT", "true"),
+            ("OVERPASS__SERVER__HOST", "0.0.0.0"),
+            ("OVERPASS__SERVER__MAX_EXPORT_BBOX_AREA", "2.5"),
+            ("OVERPASS__FILTERS__PRIMARY_KEYS", "[\"highway\", \"amenity\"]"),
+            ("OVERPASS__STORAGE__CACHE_DIR", "/data/cache"),
+            ("OVERPASS__NO_SECTION", "1"),
+            ("HOME", "/root"),
+        ];
+        let overrides = env_overrides(vars.iter().map(|&(k, v)| (k.to_string(), v.to_string())));
+        assert_eq!(overrides["server"]["port"].as_integer(), Some(8080));
+        assert_eq!(overrides["server"]["overpass_compat"].as_bool(), Some(true));
+        // not valid TOML, kept as a string
+        assert_eq!(overrides["server"]["host"].as_str(), Some("0.0.0.0"));
+        assert_eq!(overrides["server"]["max_export_bbox_area"].as_float(), Some(2.5));
+        assert_eq!(overrides.as_table().unwrap().len(), 3);
+
+        let config: Config = overrides.try_into().unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert!(config.server.overpass_compat);
+        assert_eq!(config.filters.primary_keys, ["highway", "amenity"]);
+        assert_eq!(config.storage.cache_dir, PathBuf::from("/data/cache"));
+    }
+
+    #[test]
+    fn from_files_later_files_override_earlier_ones() {
+        let dir = std::env::temp_dir().join(format!("overpass-minimal-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("base.toml");
+        let local = dir.join("local.toml");
+        std::fs::write(&base, "[filters]\nprimary_keys = [\"highway\"]\n[storage]\ncache_dir = \"/cache\"\n[server]\nhost = \"127.0.0.1\"\nport = 3000\n").unwrap();
+        std::fs::write(&local, "[server]\nport = 4000\n").unwrap();
+
+        let config = Config::from_files(&[base.clone(), local.clone()]).unwrap();
+        assert_eq!((config.server.host.as_str(), config.server.port), ("127.0.0.1", 4000));
+        let config = Config::from_files(&[local.clone(), base.clone()]).unwrap();
+        assert_eq!(config.server.port, 3000);
+        assert!(Config::from_files(&[base, dir.join("missing.toml")]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn redact_hides_every_marker() {
+        let mut value = serde_json::json!({ "server": { "host": "127.0.0.1", "nested": {} }, "upstream": { "enabled": true } });
+        for marker in SENSITIVE_FIELD_MARKERS {
+            value["server"]["nested"][format!("api_{}", marker)] = serde_json::json!("secret value");
+            value["upstream"][marker] = serde_json::json!(["secret", "list"]);
+        }
+        value["upstream"]["unset_token"] = serde_json::Value::Null;
+        redact(&mut value);
+
+        for marker in SENSITIVE_FIELD_MARKERS {
+            assert_eq!(value["server"]["nested"][format!("api_{}", marker)], "<redacted>", "{}", marker);
+            assert_eq!(value["upstream"][marker], "<redacted>", "{}", marker);
+        }
+        assert_eq!(value["server"]["host"], "127.0.0.1");
+        assert_eq!(value["upstream"]["enabled"], true);
+        assert!(value["upstream"]["unset_token"].is_null());
+    }
+}
@@ -0,0 +1,137 @@
+//! Node coordinate storage shared by preprocessing passes 2 and 3.
+//!
+//! By default coordinates live in memory (a `DashMap` while pass 2 fills it, compacted into an
+//! `FxHashMap` for pass 3). With `[storage] coords_spill` they go to a memory-mapped file with
+//! one 8-byte slot per required node instead, so the OS can page them out.
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use memmap2::MmapMut;
+use roaring::RoaringTreemap;
+use rustc_hash::FxHashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub enum NodeCoords<'a> {
+    /// Pass 2, in memory
+    Collecting(DashMap<u64, (f32, f32)>),
+    /// Pass 3, in memory (read-only)
+    Compact(FxHashMap<u64, (f32, f32)>),
+    Spilled(SpilledCoords<'a>),
+}
+
+impl<'a> NodeCoords<'a> {
+    pub fn in_memory(capacity: usize) -> Self {
+        NodeCoords::Collecting(DashMap::with_capacity(capacity))
+    }
+
+    pub fn spilled(path: &Path, required: &'a RoaringTreemap) -> Result<Self> {
+        Ok(NodeCoords::Spilled(SpilledCoords::create(path, required)?))
+    }
+
+    pub fn insert(&self, id: u64, coord: (f32, f32)) {
+        match self {
+            NodeCoords::Collecting(map) => { map.insert(id, coord); }
+            NodeCoords::Spilled(spilled) => spilled.insert(id, coord),
+            NodeCoords::Compact(_) => debug_assert!(false, "insert after compaction"),
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<(f32, f32)> {
+        match self {
+            NodeCoords::Collecting(map) => map.get(&id).map(|c| *c),
+            NodeCoords::Compact(map) => map.get(&id).copied(),
+            NodeCoords::Spilled(spilled) => spilled.get(id),
+        }
+    }
+
+    /// Number of distinct coordinates stored.
+    pub fn count(&self) -> usize {
+        match self {
+            NodeCoords::Collecting(map) => map.len(),
+            NodeCoords::Compact(map) => map.len(),
+            NodeCoords::Spilled(spilled) => spilled.count(),
+        }
+    }
+
+    /// Switch the in-memory map to its compact read-only form for pass 3.
+    pub fn compact(self) -> Self {
+        match self {
+            NodeCoords::Collecting(map) => {
+                let mut compact = FxHashMap::default();
+                compact.reserve(map.len());
+                for entry in map.iter() {
+                    compact.insert(*entry.key(), *entry.value());
+                }
+                NodeCoords::Compact(compact)
+            }
+            other => other,
+        }
+    }
+}
+
+/// One slot per required node, addressed by the node's rank in the required set. Slots hold
+/// `(lat_bits << 32 | lon_bits) ^ u64::MAX`, so an untouched (zero) slot means "missing".
+pub struct SpilledCoords<'a> {
+    required: &'a RoaringTreemap,
+    /// Keeps the mapping alive; accessed only through `slots`
+    _map: MmapMut,
+    slots: *const AtomicU64,
+    len: usize,
+    path: PathBuf,
+}
+
+// SAFETY: `slots` points into the owned mapping and is only accessed through atomics
+unsafe impl Send for SpilledCoords<'_> {}
+unsafe impl Sync for SpilledCoords<'_> {}
+
+impl<'a> SpilledCoords<'a> {
+    fn create(path: &Path, required: &'a RoaringTreemap) -> Result<Self> {
+        let len = required.len() as usize;
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)
+            .with_context(|| format!("Failed to create coordinate spill file: {:?}", path))?;
+        file.set_len((len.max(1) * 8) as u64)?;
+        // SAFETY: the file was just created by us and is removed on drop; nothing else maps it
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let slots = map.as_mut_ptr() as *const AtomicU64;
+        Ok(SpilledCoords { required, _map: map, slots, len, path: path.to_path_buf() })
+    }
+
+    fn slots(&self) -> &[AtomicU64] {
+        // SAFETY: the mapping is page-aligned, holds at least `len` u64 slots and lives as long
+        // as `self`; all access goes through atomics, so concurrent inserts are sound
+        unsafe { std::slice::from_raw_parts(self.slots, self.len) }
+    }
+
+    fn slot(&self, id: u64) -> Option<&AtomicU64> {
+        if !self.required.contains(id) {
+            return None;
+        }
+        self.slots().get(self.required.rank(id) as usize - 1)
+    }
+
+    fn insert(&self, id: u64, (lat, lon): (f32, f32)) {
+        if let Some(slot) = self.slot(id) {
+            let packed = ((lat.to_bits() as u64) << 32) | lon.to_bits() as u64;
+            slot.store(packed ^ u64::MAX, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self, id: u64) -> Option<(f32, f32)> {
+        let packed = self.slot(id)?.load(Ordering::Relaxed);
+        if packed == 0 {
+            return None;
+        }
+        let packed = packed ^ u64::MAX;
+        Some((f32::from_bits((packed >> 32) as u32), f32::from_bits(packed as u32)))
+    }
+
+    fn count(&self) -> usize {
+        self.slots().iter().filter(|s| s.load(Ordering::Relaxed) != 0).count()
+    }
+}
+
+impl Drop for SpilledCoords<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,447 @@
+//! Way geometry reconstruction and small geodesic helpers used by the query API.
+use crate::config::{Filters, IncludeCircle};
+use crate::model::Element;
+
+/// Mean earth radius in metres (spherical approximation)
+pub const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// Per-way vertex sequences, reassembled from the exploded segments at startup.
+///
+/// Pass 3 emits a way's segments consecutively, so consecutive elements sharing an id are
+/// stitched back into one polyline. Ways are stored sorted by id for binary search.
+#[derive(Debug, Default)]
+pub struct WayIndex {
+    ids: Vec<u64>,
+    tag_set_ids: Vec<u32>,
+    offsets: Vec<u32>,
+    lengths: Vec<u32>,
+    vertices: Vec<[f32; 2]>,
+}
+
+impl WayIndex {
+    pub fn build(elements: &[Element]) -> Self {
+        // runs of consecutive way segments sharing an id: (id, start, end)
+        let mut runs: Vec<(u64, usize, usize)> = Vec::new();
+        let mut i = 0;
+        while i < elements.len() {
+            let e = &elements[i];
+            if e.coordinates[0] == e.coordinates[1] {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < elements.len() && elements[i].id == e.id && elements[i].coordinates[0] != elements[i].coordinates[1] {
+                i += 1;
+            }
+            runs.push((e.id, start, i));
+        }
+        // Overlapping inputs can repeat a way: keep the first run per id
+        runs.sort_by_key(|r| r.0);
+        runs.dedup_by_key(|r| r.0);
+
+        let mut index = WayIndex::default();
+        index.ids.reserve(runs.len());
+        for (id, start, end) in runs {
+            let offset = index.vertices.len();
+            for e in &elements[start..end] {
+                if index.vertices.len() == offset || *index.vertices.last().unwrap() != e.coordinates[0] {
+                    index.vertices.push(e.coordinates[0]);
+                }
+                index.vertices.push(e.coordinates[1]);
+            }
+            index.ids.push(id);
+            index.tag_set_ids.push(elements[start].tag_set_id);
+            index.offsets.push(offset as u32);
+            index.lengths.push((index.vertices.len() - offset) as u32);
+        }
+        index.vertices.shrink_to_fit();
+        index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Vertex sequence of the way with the given id.
+    pub fn get(&self, id: u64) -> Option<&[[f32; 2]]> {
+        self.get_with_tags(id).map(|(vertices, _)| vertices)
+    }
+
+    /// Vertex sequence and tag-set id of the way with the given id.
+    pub fn get_with_tags(&self, id: u64) -> Option<(&[[f32; 2]], u32)> {
+        let idx = self.ids.binary_search(&id).ok()?;
+        let off = self.offsets[idx] as usize;
+        let len = self.lengths[idx] as usize;
+        Some((self.vertices.get(off..off + len)?, self.tag_set_ids[idx]))
+    }
+}
+
+/// Elements grouped by the H3 cell stored for them in the cache (`[runtime] h3_resolution`),
+/// sorted by cell so one cell's elements are a contiguous run.
+#[derive(Debug, Default)]
+pub struct H3Index {
+    pub resolution: u8,
+    cells: Vec<u64>,
+    elements: Vec<Element>,
+}
+
+impl H3Index {
+    /// `cells` runs parallel to `elements`.
+    pub fn build(elements: &[Element], cells: &[u64], resolution: u8) -> Self {
+        let mut pairs: Vec<(u64, Element)> = cells.iter().copied().zip(elements.iter().cloned()).collect();
+        pairs.sort_by_key(|(cell, _)| *cell);
+        let (cells, elements) = pairs.into_iter().unzip();
+        H3Index { resolution, cells, elements }
+    }
+
+    /// All elements whose midpoint lies in `cell`.
+    pub fn get(&self, cell: u64) -> &[Element] {
+        let start = self.cells.partition_point(|&c| c < cell);
+        let end = self.cells.partition_point(|&c| c <= cell);
+        &self.elements[start..end]
+    }
+}
+
+/// Great-circle distance between two `[lat, lon]` points in metres.
+pub fn haversine_m(a: [f32; 2], b: [f32; 2]) -> f64 {
+    let (lat1, lon1) = ((a[0] as f64).to_radians(), (a[1] as f64).to_radians());
+    let (lat2, lon2) = ((b[0] as f64).to_radians(), (b[1] as f64).to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = lon2 - lon1;
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// WGS84 semi-major axis used by spherical (Web) Mercator
+const WEB_MERCATOR_RADIUS_M: f64 = 6378137.0;
+/// Latitude where Web Mercator's square world ends
+const WEB_MERCATOR_MAX_LAT: f64 = 85.051_128_78;
+
+/// Project `lat`/`lon` degrees to EPSG:3857 `(x, y)` metres; latitudes are clamped to the
+/// projection's valid range.
+pub fn to_web_mercator(lat: f64, lon: f64) -> (f64, f64) {
+    let lat = lat.clamp(-WEB_MERCATOR_MAX_LAT, WEB_MERCATOR_MAX_LAT).to_radians();
+    let x = WEB_MERCATOR_RADIUS_M * lon.to_radians();
+    let y = WEB_MERCATOR_RADIUS_M * (std::f64::consts::FRAC_PI_4 + lat / 2.0).tan().ln();
+    (x, y)
+}
+
+/// Highest XYZ zoom accepted by `tile_bounds`
+pub const MAX_TILE_ZOOM: u8 = 30;
+
+/// Bounds of the XYZ (slippy map) tile `z/x/y` as `([south, west], [north, east])` degrees;
+/// `None` when `z` exceeds `MAX_TILE_ZOOM` or `x`/`y` are outside the zoom level.
+pub fn tile_bounds(z: u8, x: u32, y: u32) -> Option<([f64; 2], [f64; 2])> {
+    if z > MAX_TILE_ZOOM || u64::from(x) >= 1u64 << z || u64::from(y) >= 1u64 << z {
+        return None;
+    }
+    let n = (1u64 << z) as f64;
+    let lon = |x: f64| x / n * 360.0 - 180.0;
+    let lat = |y: f64| (std::f64::consts::PI * (1.0 - 2.0 * y / n)).sinh().atan().to_degrees();
+    Some(([lat(y as f64 + 1.0), lon(x as f64)], [lat(y as f64), lon(x as f64 + 1.0)]))
+}
+
+/// Total length of a polyline in metres.
+pub fn polyline_length_m(vertices: &[[f32; 2]]) -> f64 {
+    vertices.windows(2).map(|w| haversine_m(w[0], w[1])).sum()
+}
+
+/// Linear reference: fraction (0-1) of the way's length up to fraction `t` of its segment
+/// `p1 -> p2`. `None` when the segment is not in `vertices` or the way has no length.
+pub fn fraction_along(vertices: &[[f32; 2]], p1: [f32; 2], p2: [f32; 2], t: f64) -> Option<f64> {
+    let total = polyline_length_m(vertices);
+    if total <= 0.0 {
+        return None;
+    }
+    let mut before = 0.0;
+    for w in vertices.windows(2) {
+        let len = haversine_m(w[0], w[1]);
+        if w[0] == p1 && w[1] == p2 {
+            return Some(((before + t * len) / total).clamp(0.0, 1.0));
+        }
+        before += len;
+    }
+    None
+}
+
+/// A way is treated as an area when its stitched geometry is a closed ring.
+pub fn is_closed(vertices: &[[f32; 2]]) -> bool {
+    vertices.len() >= 4 && vertices[0] == vertices[vertices.len() - 1]
+}
+
+/// Join way geometries into closed rings, as multipolygon relations split their boundaries
+/// over several open member ways: closed parts are rings as-is, open parts are chained at shared
+/// endpoints (reversed where needed). Chains that never close are dropped.
+pub fn assemble_rings(parts: &[&[[f32; 2]]]) -> Vec<Vec<[f32; 2]>> {
+    let mut rings = Vec::new();
+    let mut open: Vec<&[[f32; 2]]> = Vec::new();
+    for &part in parts {
+        if is_closed(part) {
+            rings.push(part.to_vec());
+        } else if part.len() >= 2 {
+            open.push(part);
+        }
+    }
+    while let Some(first) = open.pop() {
+        let mut ring = first.to_vec();
+        while ring.len() < 4 || ring[0] != ring[ring.len() - 1] {
+            let end = ring[ring.len() - 1];
+            let Some(i) = open.iter().position(|p| p[0] == end || p[p.len() - 1] == end) else {
+                break;
+            };
+            let next = open.swap_remove(i);
+            if next[0] == end {
+                ring.extend_from_slice(&next[1..]);
+            } else {
+                ring.extend(next.iter().rev().skip(1));
+            }
+        }
+        if is_closed(&ring) {
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+/// Mean of a way's vertices (the closing vertex of a ring is counted once).
+pub fn centroid(vertices: &[[f32; 2]]) -> [f32; 2] {
+    let points = if is_closed(vertices) { &vertices[..vertices.len() - 1] } else { vertices };
+    let (lat, lon) = points.iter().fold((0.0f64, 0.0f64), |(lat, lon), v| (lat + v[0] as f64, lon + v[1] as f64));
+    let n = points.len().max(1) as f64;
+    [(lat / n) as f32, (lon / n) as f32]
+}
+
+/// Google encoded polyline (precision 5) of `[lat, lon]` vertices.
+pub fn encode_polyline(vertices: &[[f64; 2]]) -> String {
+    let mut out = String::new();
+    let mut previous = [0i64; 2];
+    for vertex in vertices {
+        for (axis, &value) in vertex.iter().enumerate() {
+            let scaled = (value * 1e5).round() as i64;
+            let delta = scaled - previous[axis];
+            previous[axis] = scaled;
+            let mut zigzag = ((delta << 1) ^ (delta >> 63)) as u64;
+            while zigzag >= 0x20 {
+                out.push((((zigzag & 0x1f) | 0x20) as u8 + 63) as char);
+                zigzag >>= 5;
+            }
+            out.push((zigzag as u8 + 63) as char);
+        }
+    }
+    out
+}
+
+/// Even-odd point-in-polygon test for a closed `[lat, lon]` ring.
+pub fn point_in_ring(point: [f32; 2], ring: &[[f32; 2]]) -> bool {
+    let (py, px) = (point[0] as f64, point[1] as f64);
+    let mut inside = false;
+    for w in ring.windows(2) {
+        let (y1, x1) = (w[0][0] as f64, w[0][1] as f64);
+        let (y2, x2) = (w[1][0] as f64, w[1][1] as f64);
+        if (y1 > py) != (y2 > py) && px < x1 + (py - y1) * (x2 - x1) / (y2 - y1) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Polygon(s) from GeoJSON for `[filters] clip_polygon` and `POST /api/query/polygon`. Each polygon is an
+/// outer ring followed by its holes, stored as `[lat, lon]`.
+#[derive(Debug)]
+pub struct ClipPolygon {
+    polygons: Vec<Vec<Vec<[f32; 2]>>>,
+    /// `[min_lat, min_lon, max_lat, max_lon]` quick-reject box
+    bbox: [f32; 4],
+}
+
+impl ClipPolygon {
+    /// Accepts a Polygon, MultiPolygon, Feature or FeatureCollection of those.
+    pub fn from_geojson_file(path: &std::path::Path) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read clip polygon: {:?}", path))?;
+        let value: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse clip polygon (GeoJSON): {:?}", path))?;
+        Self::from_geojson(&value).with_context(|| format!("clip polygon {:?}", path))
+    }
+
+    /// Same inputs as `from_geojson_file`, already parsed.
+    pub fn from_geojson(value: &serde_json::Value) -> anyhow::Result<Self> {
+        let mut polygons = Vec::new();
+        collect_polygons(value, &mut polygons);
+        if polygons.is_empty() {
+            anyhow::bail!("GeoJSON contains no Polygon or MultiPolygon geometry");
+        }
+        let mut bbox = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+        for v in polygons.iter().filter_map(|p| p.first()).flatten() {
+            bbox = [bbox[0].min(v[0]), bbox[1].min(v[1]), bbox[2].max(v[0]), bbox[3].max(v[1])];
+        }
+        Ok(ClipPolygon { polygons, bbox })
+    }
+
+    /// `[min_lat, min_lon, max_lat, max_lon]` of the outer rings
+    pub fn bbox(&self) -> [f32; 4] {
+        self.bbox
+    }
+
+    pub fn contains(&self, point: [f32; 2]) -> bool {
+        if point[0] < self.bbox[0] || point[1] < self.bbox[1] || point[0] > self.bbox[2] || point[1] > self.bbox[3] {
+            return false;
+        }
+        self.polygons.iter().any(|rings| match rings.split_first() {
+            Some((outer, holes)) => point_in_ring(point, outer) && !holes.iter().any(|h| point_in_ring(point, h)),
+            None => false,
+        })
+    }
+
+    /// Whether the segment `a`-`b` lies inside the polygon or crosses any of its rings (a
+    /// segment spanning a hole without an endpoint inside the polygon still counts).
+    pub fn intersects_segment(&self, a: [f32; 2], b: [f32; 2]) -> bool {
+        if a[0].max(b[0]) < self.bbox[0] || a[1].max(b[1]) < self.bbox[1] || a[0].min(b[0]) > self.bbox[2] || a[1].min(b[1]) > self.bbox[3] {
+            return false;
+        }
+        self.contains(a) || self.contains(b) || self.polygons.iter().flatten()
+            .any(|ring| ring.windows(2).any(|edge| segments_intersect(a, b, edge[0], edge[1])))
+    }
+}
+
+/// Whether the closed segments `a`-`b` and `c`-`d` share a point (touching and collinear
+/// overlap included).
+fn segments_intersect(a: [f32; 2], b: [f32; 2], c: [f32; 2], d: [f32; 2]) -> bool {
+    let orient = |p: [f32; 2], q: [f32; 2], r: [f32; 2]| {
+        (q[0] as f64 - p[0] as f64) * (r[1] as f64 - p[1] as f64) - (q[1] as f64 - p[1] as f64) * (r[0] as f64 - p[0] as f64)
+    };
+    let boxes_overlap = a[0].min(b[0]) <= c[0].max(d[0]) && c[0].min(d[0]) <= a[0].max(b[0])
+        && a[1].min(b[1]) <= c[1].max(d[1]) && c[1].min(d[1]) <= a[1].max(b[1]);
+    boxes_overlap
+        && orient(a, b, c) * orient(a, b, d) <= 0.0
+        && orient(c, d, a) * orient(c, d, b) <= 0.0
+}
+
+/// Pass-3 spatial filter from `[filters] clip_polygon` and `include_circles`: a point is kept
+/// when any configured region contains it.
+#[derive(Debug)]
+pub struct ClipRegions {
+    polygon: Option<ClipPolygon>,
+    circles: Vec<IncludeCircle>,
+}
+
+impl ClipRegions {
+    /// `None` when neither a polygon nor circles are configured.
+    pub fn from_filters(filters: &Filters) -> anyhow::Result<Option<Self>> {
+        for c in &filters.include_circles {
+            anyhow::ensure!(c.radius_m > 0.0 && c.lat.abs() <= 90.0 && c.lon.abs() <= 180.0,
+                "invalid include_circles entry {:?}: needs lat within ±90, lon within ±180 and a positive radius_m", c);
+        }
+        let polygon = filters.clip_polygon.as_deref().map(ClipPolygon::from_geojson_file).transpose()?;
+        if polygon.is_none() && filters.include_circles.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ClipRegions { polygon, circles: filters.include_circles.clone() }))
+    }
+
+    pub fn contains(&self, point: [f32; 2]) -> bool {
+        self.polygon.as_ref().is_some_and(|p| p.contains(point)) || self.circles.iter().any(|c| {
+            // a degree of latitude is never shorter than ~110.5 km: cheap reject before haversine
+            (point[0] as f64 - c.lat).abs() * 110_500.0 <= c.radius_m
+                && haversine_m(point, [c.lat as f32, c.lon as f32]) <= c.radius_m
+        })
+    }
+}
+
+fn collect_polygons(value: &serde_json::Value, out: &mut Vec<Vec<Vec<[f32; 2]>>>) {
+    let parse_rings = |rings: &serde_json::Value| -> Vec<Vec<[f32; 2]>> {
+        rings.as_array().into_iter().flatten().map(|ring| {
+            ring.as_array().into_iter().flatten().filter_map(|pos| {
+                let lon = pos.get(0)?.as_f64()?;
+                let lat = pos.get(1)?.as_f64()?;
+                Some([lat as f32, lon as f32])
+            }).collect()
+        }).collect()
+    };
+    match value.get("type").and_then(|t| t.as_str()) {
+        Some("FeatureCollection") => {
+            for feature in value.get("features").and_then(|f| f.as_array()).into_iter().flatten() {
+                collect_polygons(feature, out);
+            }
+        }
+        Some("Feature") => {
+            if let Some(geometry) = value.get("geometry") {
+                collect_polygons(geometry, out);
+            }
+        }
+        Some("Polygon") => {
+            if let Some(coords) = value.get("coordinates") {
+                out.push(parse_rings(coords));
+            }
+        }
+        Some("MultiPolygon") => {
+            for polygon in value.get("coordinates").and_then(|c| c.as_array()).into_iter().flatten() {
+                out.push(parse_rings(polygon));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Area of a closed ring in square metres (shoelace formula on a local equirectangular projection).
+pub fn ring_area_m2(vertices: &[[f32; 2]]) -> f64 {
+    if vertices.len() < 3 {
+        return 0.0;
+    }
+    let lat0 = vertices.iter().map(|v| v[0] as f64).sum::<f64>() / vertices.len() as f64;
+    let k = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+    let kx = k * lat0.to_radians().cos();
+    let mut twice_area = 0.0;
+    for w in vertices.windows(2) {
+        let (x1, y1) = (w[0][1] as f64 * kx, w[0][0] as f64 * k);
+        let (x2, y2) = (w[1][1] as f64 * kx, w[1][0] as f64 * k);
+        twice_area += x1 * y2 - x2 * y1;
+    }
+    (twice_area / 2.0).abs()
+}
+
+/// Douglas-Peucker simplification of a `[lat, lon]` polyline; `tolerance_m` is the maximum
+/// perpendicular deviation (metres, local equirectangular projection) a dropped vertex may have.
+/// Endpoints are always kept.
+pub fn simplify(points: &[[f32; 2]], tolerance_m: f64) -> Vec<[f32; 2]> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    let k = EARTH_RADIUS_M * std::f64::consts::PI / 180.0;
+    let kx = k * (points[0][0] as f64).to_radians().cos();
+    let project = |p: [f32; 2]| (p[1] as f64 * kx, p[0] as f64 * k);
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+    let mut stack = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let (ax, ay) = project(points[first]);
+        let (bx, by) = project(points[last]);
+        let (dx, dy) = (bx - ax, by - ay);
+        let seg_len2 = dx * dx + dy * dy;
+
+        let mut max_dist = 0.0;
+        let mut max_idx = first;
+        for (i, &p) in points.iter().enumerate().take(last).skip(first + 1) {
+            let (px, py) = project(p);
+            let dist = if seg_len2 == 0.0 {
+                ((px - ax).powi(2) + (py - ay).powi(2)).sqrt()
+            } else {
+                (dy * px - dx * py + bx * ay - by * ax).abs() / seg_len2.sqrt()
+            };
+            if dist > max_dist {
+                max_dist = dist;
+                max_idx = i;
+            }
+        }
+        if max_dist > tolerance_m {
+            keep[max_idx] = true;
+            stack.push((first, max_idx));
+            stack.push((max_idx, last));
+        }
+    }
+
+    points.iter().zip(keep).filter(|(_, k)| *k).map(|(p, _)| *p).collect()
+}
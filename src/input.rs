@@ -0,0 +1,116 @@
+//! Resolve `--input` arguments into local PBF files.
+//!
+//! `osmpbf` reads the file once per preprocessing pass, so inputs that are not plain local
+//! files (stdin `-` or an `http(s)://` URL) are first staged into the cache directory.
+use crate::config::Config;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Stage every input and return local paths, preserving order.
+pub fn stage_inputs(config: &Config, inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    inputs.iter().enumerate().map(|(i, input)| stage_input(config, input, i)).collect()
+}
+
+fn stage_input(config: &Config, input: &Path, index: usize) -> Result<PathBuf> {
+    let input_str = input.to_string_lossy();
+    if input_str == "-" {
+        let dest = config.storage.cache_dir.join(format!("input-{}-stdin.osm.pbf", index));
+        info!("Buffering PBF from stdin to {:?}...", dest);
+        let mut out = BufWriter::new(File::create(&dest).with_context(|| format!("Failed to create {:?}", dest))?);
+        let copied = std::io::copy(&mut std::io::stdin().lock(), &mut out).context("Failed to read PBF from stdin")?;
+        out.flush()?;
+        info!("Buffered {} bytes from stdin.", copied);
+        return Ok(dest);
+    }
+
+    if input_str.starts_with("http://") || input_str.starts_with("https://") {
+        let name = input_str.rsplit('/').next().filter(|n| !n.is_empty()).unwrap_or("download.osm.pbf");
+        let dest = config.storage.cache_dir.join(format!("input-{}-{}", index, name));
+        download(config, &input_str, &dest)?;
+        return Ok(dest);
+    }
+
+    Ok(input.to_path_buf())
+}
+
+/// Download with bounded retries. Data goes to `<dest>.part`, which later attempts resume with
+/// an HTTP `Range` request when the server supports it; the file is only renamed to `dest` once
+/// its size matches the announced length, and is removed if every attempt fails.
+fn download(config: &Config, url: &str, dest: &Path) -> Result<()> {
+    let timeout = std::time::Duration::from_secs(config.storage.download_timeout_secs);
+    let agent = ureq::AgentBuilder::new().timeout_connect(timeout).timeout_read(timeout).build();
+    let part = PathBuf::from(format!("{}.part", dest.display()));
+    let _ = std::fs::remove_file(&part);
+
+    let attempts = config.storage.download_retries + 1;
+    let mut last_error = None;
+    for attempt in 1..=attempts {
+        if attempt > 1 {
+            let backoff = std::time::Duration::from_secs(1 << (attempt - 2).min(5));
+            warn!("Download attempt {}/{} failed ({:#}); retrying in {:?}", attempt - 1, attempts, last_error.as_ref().unwrap(), backoff);
+            std::thread::sleep(backoff);
+        }
+        match download_attempt(&agent, url, &part) {
+            Ok(()) => {
+                std::fs::rename(&part, dest).with_context(|| format!("Failed to move {:?} to {:?}", part, dest))?;
+                return Ok(());
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    let _ = std::fs::remove_file(&part);
+    Err(last_error.unwrap().context(format!("Failed to download {} after {} attempts", url, attempts)))
+}
+
+fn download_attempt(agent: &ureq::Agent, url: &str, part: &Path) -> Result<()> {
+    let offset = std::fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    let mut request = agent.get(url);
+    if offset > 0 {
+        request = request.set("Range", &format!("bytes={}-", offset));
+    }
+    let response = match request.call() {
+        Err(ureq::Error::Status(416, _)) if offset > 0 => {
+            // stale partial file larger than the resource: start over on the next attempt
+            std::fs::remove_file(part)?;
+            anyhow::bail!("server rejected resume range at byte {}", offset);
+        }
+        other => other.with_context(|| format!("Failed to download {}", url))?,
+    };
+
+    // 206: the server honoured the range, so append; anything else restarts from scratch
+    let resumed = offset > 0 && response.status() == 206;
+    let expected_total = if resumed {
+        response.header("Content-Range").and_then(|r| r.rsplit('/').next()).and_then(|t| t.parse::<u64>().ok())
+    } else {
+        response.header("Content-Length").and_then(|l| l.parse::<u64>().ok())
+    };
+    if resumed {
+        info!("Resuming download of {} at byte {}...", url, offset);
+    } else {
+        info!("Downloading {} to {:?}...", url, part);
+    }
+
+    let file = std::fs::OpenOptions::new().create(true).write(true).append(resumed).truncate(!resumed).open(part)
+        .with_context(|| format!("Failed to create {:?}", part))?;
+    let mut out = BufWriter::new(file);
+    let copied = std::io::copy(&mut response.into_reader(), &mut out).with_context(|| format!("Failed while downloading {}", url))?;
+    out.flush()?;
+
+    let size = std::fs::metadata(part)?.len();
+    if let Some(expected) = expected_total {
+        if size != expected {
+            anyhow::bail!("downloaded {} bytes but Content-Length announced {}", size, expected);
+        }
+    }
+    info!("Downloaded {} bytes ({} in this attempt).", size, copied);
+    Ok(())
+}
+
+/// Staged inputs are rewritten on every run, so their mtime is meaningless for change detection.
+pub fn is_staged(config: &Config, path: &Path) -> bool {
+    path.starts_with(&config.storage.cache_dir)
+        && path.file_name().is_some_and(|n| n.to_string_lossy().starts_with("input-"))
+}
@@ -1,9 +1,15 @@
 mod config;
 mod model;
 mod preprocessor;
+mod coords;
 mod api;
+mod input;
+mod geometry;
+mod proto;
+mod tag_export;
+mod synthetic;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use std::fs::File;
 use anyhow::{Result, Context};
@@ -13,17 +19,57 @@ use tracing_subscriber;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to the TOML configuration file
-    #[arg(short, long, default_value = "config.toml")]
-    config: PathBuf,
+    /// Path(s) to TOML configuration files; may be repeated, later files override earlier ones.
+    /// Defaults to `config.toml`, or environment variables only when that file is absent
+    #[arg(short, long)]
+    config: Vec<PathBuf>,
 
-    /// Path to the OSM PBF file
+    /// Path(s) to the OSM PBF file; repeat to merge several extracts into one dataset.
+    /// `-` reads from stdin and `http(s)://` URLs are downloaded into the cache directory first
     #[arg(short, long)]
-    input: PathBuf,
+    input: Vec<PathBuf>,
 
     /// Path to the cache directory (overrides config)
     #[arg(short, long)]
     cache: Option<PathBuf>,
+
+    /// Write a JSON preprocessing summary (pass timings, memory, counts) to this path
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the string table and tag-set table (no geometry) for external indexing, then exit
+    ExportTags {
+        /// Output directory for `strings.*` and `tag_sets.*`
+        #[arg(long, default_value = "tags-export")]
+        out: PathBuf,
+        #[arg(long, value_enum, default_value_t = tag_export::ExportFormat::Csv)]
+        format: tag_export::ExportFormat,
+    },
+    /// Re-encode the existing cache at another zstd level (no PBF input needed), then exit
+    Recompress {
+        /// zstd level (1-22; higher = smaller, slower)
+        #[arg(long, value_parser = clap::value_parser!(i32).range(1..=22))]
+        level: i32,
+    },
+    /// Synthesize a reproducible dataset (random points and short ways with tags from `[filters]`)
+    /// instead of reading `--input`, write it as the cache and serve it
+    Generate {
+        /// Number of features (each way becomes several segments)
+        #[arg(long)]
+        count: usize,
+        /// south,west,north,east
+        #[arg(long, required = true, value_delimiter = ',', allow_negative_numbers = true)]
+        bbox: Vec<f64>,
+        /// RNG seed; the same seed, arguments and config give the same dataset
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+    },
 }
 
 #[tokio::main]
@@ -32,20 +78,13 @@ async fn main() -> Result<()> {
     
     tracing_subscriber::fmt::init();
     
-    let num_threads = std::thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(16);
-    
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(num_threads)
-        .build_global()
-        .ok();
-
-    info!("Application starting (using {} threads)", num_threads);
+    // Preprocessing runs in its own rayon pool (see `[runtime] preprocess_threads`); the global
+    // pool is left at rayon's defaults.
+    info!("Application starting");
 
     let args = Args::parse();
     
-    let mut config = config::Config::from_file(&args.config)?;
+    let mut config = config::Config::from_files(&args.config)?;
     if let Some(cache_override) = args.cache {
         config.storage.cache_dir = cache_override;
     }
@@ -56,6 +95,10 @@ async fn main() -> Result<()> {
             .with_context(|| format!("Failed to create cache directory: {:?}", config.storage.cache_dir))?;
     }
 
+    if let Some(Command::Recompress { level }) = &args.command {
+        return preprocessor::recompress_cache(&config, *level);
+    }
+
     info!("Loading data...");
 
     // Optional CPU sampling profiler — configurable via `config.toml` ([profiling])
@@ -83,7 +126,27 @@ async fn main() -> Result<()> {
         }
     } else { None };
 
-    let cache = preprocessor::load_or_preprocess(&config, &args.input)?;
+    let loaded = if let Some(Command::Generate { count, bbox, seed }) = &args.command {
+        synthetic::generate(&config, *count, bbox, *seed)
+            .and_then(|cache_data| preprocessor::store_generated(&config, cache_data))
+    } else if args.input.is_empty() {
+        Err(anyhow::anyhow!("no --input given"))
+    } else {
+        input::stage_inputs(&config, &args.input)
+            .and_then(|inputs| preprocessor::load_or_preprocess(&config, &inputs, args.report.as_deref()))
+    };
+    // allow_empty only covers data that is not there yet; corrupt caches, failed downloads and
+    // the abort policies of preprocessing still fail startup
+    let no_data = !matches!(args.command, Some(Command::Generate { .. }))
+        && (args.input.is_empty() || preprocessor::data_missing(&config, &args.input));
+    let cache = match loaded {
+        Ok(cache) => cache,
+        Err(e) if config.runtime.allow_empty && no_data => {
+            tracing::warn!("!!! No data could be loaded ({:#}). Starting with an EMPTY dataset because [runtime] allow_empty = true; every query will return zero results. !!!", e);
+            preprocessor::LoadedCache::empty()
+        }
+        Err(e) => return Err(e),
+    };
 
     // If profiling was enabled, write a flamegraph of the preprocessing stage
     if let Some(guard) = maybe_prof {
@@ -111,6 +174,11 @@ async fn main() -> Result<()> {
         info!("Loaded {} elements.", elements.len());
     }
 
+    if let Some(Command::ExportTags { out, format }) = &args.command {
+        let preprocessor::LoadedCache::Owned { tag_sets, interner, .. } = &cache;
+        return tag_export::export_tags(interner, tag_sets, out, *format);
+    }
+
     // Log current RSS (Linux `/proc/self/status` VmRSS) to make it easy to verify memory usage
     if let Ok(s) = std::fs::read_to_string("/proc/self/status") {
         for line in s.lines() {
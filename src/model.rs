@@ -15,7 +15,10 @@ pub struct Element {
 
 use parking_lot::RwLock;
 use dashmap::DashMap;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Set once the linear-scan fallback of `StringInterner::find` has been reported
+static LINEAR_FIND_WARNED: AtomicBool = AtomicBool::new(false);
 
 /// A memory-efficient string interner using a single contiguous string pool for reverse lookups.
 ///
@@ -30,6 +33,10 @@ impl Default for Pool {
     fn default() -> Self { Pool(String::new()) }
 }
 
+impl Pool {
+    pub fn as_str(&self) -> &str { &self.0 }
+}
+
 
 #[derive(Debug, Default)]
 pub struct StringInterner {
@@ -37,6 +44,9 @@ pub struct StringInterner {
     pub pool: RwLock<Pool>,
     pub offsets: RwLock<Vec<u32>>,
     pub lengths: RwLock<Vec<u32>>,
+    /// Ids sorted by their string: runtime-only string -> id index used by `find` when `map`
+    /// was dropped (4 bytes per string instead of a `HashMap` entry)
+    pub sorted_ids: RwLock<Vec<u32>>,
 }
 
 impl Clone for StringInterner {
@@ -46,6 +56,7 @@ impl Clone for StringInterner {
             pool: RwLock::new(self.pool.read().clone()),
             offsets: RwLock::new(self.offsets.read().clone()),
             lengths: RwLock::new(self.lengths.read().clone()),
+            sorted_ids: RwLock::new(self.sorted_ids.read().clone()),
         }
     }
 }
@@ -53,12 +64,20 @@ impl Clone for StringInterner {
 // Serializable helper used for serde (keeps disk format unchanged)
 #[derive(Serialize, Deserialize)]
 struct SerializableStringInterner {
+    #[serde(serialize_with = "serialize_sorted_map")]
     map: HashMap<String, u32>,
     pool: String,
     offsets: Vec<u32>,
     lengths: Vec<u32>,
 }
 
+/// Write `map` in key order so equal interners serialize to equal bytes (same wire format)
+fn serialize_sorted_map<S: serde::Serializer>(map: &HashMap<String, u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut entries: Vec<(&String, &u32)> = map.iter().collect();
+    entries.sort_unstable();
+    serializer.collect_map(entries)
+}
+
 impl serde::Serialize for StringInterner {
     fn serialize<S>(&self, serializer: S) -> Result<<S as serde::Serializer>::Ok, <S as serde::Serializer>::Error>
     where
@@ -85,6 +104,7 @@ impl<'de> serde::Deserialize<'de> for StringInterner {
             pool: RwLock::new(Pool(ssi.pool)),
             offsets: RwLock::new(ssi.offsets),
             lengths: RwLock::new(ssi.lengths),
+            sorted_ids: RwLock::default(),
         })
     }
 }
@@ -127,18 +147,70 @@ impl StringInterner {
         id
     }
 
-    /// Return an owned `String` for the given id (keeps API unchanged).
-    pub fn lookup(&self, id: u32) -> Option<String> {
+    /// Rebuild with ids assigned in string order, keeping only strings present in `map` (racing
+    /// `ConcurrentInterner` inserts can leave unused ids). Also returns an old id -> new id table.
+    pub fn sorted(&self) -> (StringInterner, Vec<u32>) {
+        let map = self.map.read();
+        let mut entries: Vec<(&String, u32)> = map.iter().map(|(s, &id)| (s, id)).collect();
+        entries.sort_unstable();
+        let mut remap = vec![u32::MAX; self.offsets.read().len()];
+        let sorted = StringInterner::default();
+        for (s, old_id) in entries {
+            remap[old_id as usize] = sorted.get_or_intern(s);
+        }
+        (sorted, remap)
+    }
+
+    /// Build `sorted_ids` so `find` can binary-search after `map` has been dropped.
+    pub fn build_sorted_index(&self) {
+        let offsets = self.offsets.read();
+        let lengths = self.lengths.read();
+        let pool = &self.pool.read().0;
+        let string_of = |id: u32| pool_entry(pool, &offsets, &lengths, id).unwrap_or("");
+        let mut ids: Vec<u32> = (0..offsets.len() as u32).collect();
+        ids.sort_unstable_by(|&a, &b| string_of(a).cmp(string_of(b)));
+        *self.sorted_ids.write() = ids;
+    }
+
+    /// Find the id of an interned string. Uses `map` when it was kept, then the `sorted_ids`
+    /// index, and finally a linear scan over the pool (see `[runtime] drop_interner_map`).
+    pub fn find(&self, s: &str) -> Option<u32> {
+        {
+            let map = self.map.read();
+            if !map.is_empty() {
+                return map.get(s).copied();
+            }
+        }
         let offsets = self.offsets.read();
-        let idx = id as usize;
-        let start = *offsets.get(idx)? as usize;
-        let len = *self.lengths.read().get(idx)? as usize;
+        let lengths = self.lengths.read();
+        let pool = &self.pool.read().0;
+        let string_of = |id: u32| pool_entry(pool, &offsets, &lengths, id);
+        let sorted_ids = self.sorted_ids.read();
+        if !sorted_ids.is_empty() {
+            return sorted_ids.binary_search_by(|&id| string_of(id).unwrap_or("").cmp(s)).ok().map(|i| sorted_ids[i]);
+        }
+        if !offsets.is_empty() && !LINEAR_FIND_WARNED.swap(true, Ordering::Relaxed) {
+            tracing::warn!("string -> id lookups fall back to a linear pool scan; keep the interner map or enable [runtime] string_index");
+        }
+        (0..offsets.len() as u32).find(|&id| string_of(id) == Some(s))
+    }
 
-        let pool_str = &self.pool.read().0;
-        Some(pool_str[start..start + len].to_string())
+    /// Return an owned `String` for the given id (keeps API unchanged).
+    /// Returns `None` instead of panicking when a corrupt cache has an out-of-range
+    /// offset/length or one that splits a UTF-8 sequence.
+    pub fn lookup(&self, id: u32) -> Option<String> {
+        pool_entry(&self.pool.read().0, &self.offsets.read(), &self.lengths.read(), id).map(|s| s.to_string())
     }
 }
 
+/// The pool slice of string `id`; `None` for a missing offset/length or a range that is out of
+/// bounds or splits a UTF-8 sequence (corrupt cache).
+fn pool_entry<'p>(pool: &'p str, offsets: &[u32], lengths: &[u32], id: u32) -> Option<&'p str> {
+    let start = *offsets.get(id as usize)? as usize;
+    let len = *lengths.get(id as usize)? as usize;
+    pool.get(start..start.checked_add(len)?)
+}
+
 /// Concurrent interner used during preprocessing to avoid heavy locking.
 /// Converted to `StringInterner` after preprocessing completes.
 #[derive(Debug, Default)]
@@ -190,7 +262,7 @@ impl ConcurrentInterner {
             map.insert(entry.0, entry.1);
         }
 
-        StringInterner { map: RwLock::new(map), pool: RwLock::new(Pool(pool)), offsets: RwLock::new(offsets), lengths: RwLock::new(lengths) }
+        StringInterner { map: RwLock::new(map), pool: RwLock::new(Pool(pool)), offsets: RwLock::new(offsets), lengths: RwLock::new(lengths), sorted_ids: RwLock::default() }
     }
 
     /// Non-consuming conversion (useful when `ConcurrentInterner` is held in an `Arc`)
@@ -216,7 +288,7 @@ impl ConcurrentInterner {
             map.insert(entry.key().clone(), *entry.value());
         }
 
-        StringInterner { map: RwLock::new(map), pool: RwLock::new(Pool(pool)), offsets: RwLock::new(offsets), lengths: RwLock::new(lengths) }
+        StringInterner { map: RwLock::new(map), pool: RwLock::new(Pool(pool)), offsets: RwLock::new(offsets), lengths: RwLock::new(lengths), sorted_ids: RwLock::default() }
     }
 }
 
@@ -242,7 +314,7 @@ impl<T: InternerLike + ?Sized> InternerLike for std::sync::Arc<T> {
 /// Flattened representation of tag-sets to reduce per-Vec overhead
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct FlatTagSets {
-    /// packed pairs: high 32 bits = key id, low 32 bits = value id
+    /// packed pairs (`pack_kv`): high 32 bits = key id, low 32 bits = value id
     pub data: Vec<u64>,
     /// start index for each tag-set inside `data`
     pub offsets: Vec<u32>,
@@ -250,20 +322,312 @@ pub struct FlatTagSets {
     pub lengths: Vec<u32>,
 }
 
+/// Pack an interned `(key id, value id)` tag pair into one `u64` (key in the high 32 bits), the
+/// layout of `FlatTagSets::data`.
+pub fn pack_kv(key_id: u32, value_id: u32) -> u64 {
+    (u64::from(key_id) << 32) | u64::from(value_id)
+}
+
+/// Inverse of `pack_kv`: `(key id, value id)`.
+pub fn unpack_kv(packed: u64) -> (u32, u32) {
+    ((packed >> 32) as u32, packed as u32)
+}
+
 impl FlatTagSets {
     pub fn get(&self, idx: usize) -> Option<&[u64]> {
         let off = *self.offsets.get(idx)? as usize;
         let len = *self.lengths.get(idx)? as usize;
-        Some(&self.data[off..off + len])
+        self.data.get(off..off.checked_add(len)?)
+    }
+}
+
+/// Feature counts for a dataset. Ways are exploded into one `Element` per segment, so
+/// `segments` overstates the number of real features; `ways`/`nodes` count distinct ids.
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+pub struct DatasetCounts {
+    /// Total stored `Element`s (node points + way segments)
+    pub elements: usize,
+    /// Way segments only
+    pub segments: usize,
+    pub ways: usize,
+    pub nodes: usize,
+}
+
+impl DatasetCounts {
+    pub fn from_elements(elements: &[Element]) -> Self {
+        let mut way_ids: rustc_hash::FxHashSet<u64> = rustc_hash::FxHashSet::default();
+        let mut node_ids: rustc_hash::FxHashSet<u64> = rustc_hash::FxHashSet::default();
+        let mut segments = 0;
+        for e in elements {
+            if e.coordinates[0] == e.coordinates[1] {
+                node_ids.insert(e.id);
+            } else {
+                way_ids.insert(e.id);
+                segments += 1;
+            }
+        }
+        DatasetCounts { elements: elements.len(), segments, ways: way_ids.len(), nodes: node_ids.len() }
+    }
+}
+
+/// `(relation id, tag_set_id, [(member way id, role id)])` collected in pass 3
+pub type RelationRecord = (u64, u32, Vec<(u64, u32)>);
+
+/// Relations kept during preprocessing (see `[filters] relation_types`) with their way members.
+/// Sorted by relation id; member lists are flattened like `FlatTagSets`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RelationTable {
+    pub ids: Vec<u64>,
+    pub tag_set_ids: Vec<u32>,
+    pub offsets: Vec<u32>,
+    pub lengths: Vec<u32>,
+    /// member way ids, in relation order
+    pub members: Vec<u64>,
+    /// interned role string of each member (e.g. `outer`, `inner`, `forward`)
+    pub roles: Vec<u32>,
+}
+
+impl RelationTable {
+    /// Build from `(relation id, tag_set_id, [(way id, role id)])` records in any order.
+    pub fn from_records(mut records: Vec<RelationRecord>) -> Self {
+        records.sort_by_key(|r| r.0);
+        records.dedup_by_key(|r| r.0);
+        let mut table = RelationTable::default();
+        for (id, tag_set_id, members) in records {
+            table.ids.push(id);
+            table.tag_set_ids.push(tag_set_id);
+            table.offsets.push(table.members.len() as u32);
+            table.lengths.push(members.len() as u32);
+            for (way_id, role) in members {
+                table.members.push(way_id);
+                table.roles.push(role);
+            }
+        }
+        table
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Index of the relation with the given id.
+    pub fn find(&self, id: u64) -> Option<usize> {
+        self.ids.binary_search(&id).ok()
+    }
+
+    /// Member way ids and role ids of the relation at `idx`.
+    pub fn members(&self, idx: usize) -> (&[u64], &[u32]) {
+        let off = self.offsets[idx] as usize;
+        let len = self.lengths[idx] as usize;
+        (&self.members[off..off + len], &self.roles[off..off + len])
+    }
+}
+
+/// Reverse of `RelationTable` membership: way id -> ids of the relations it belongs to.
+/// Built at startup; pairs are sorted by way id.
+#[derive(Debug, Default)]
+pub struct WayRelations {
+    way_ids: Vec<u64>,
+    relation_ids: Vec<u64>,
+}
+
+impl WayRelations {
+    pub fn build(relations: &RelationTable) -> Self {
+        let mut pairs: Vec<(u64, u64)> = Vec::with_capacity(relations.members.len());
+        for (idx, &relation_id) in relations.ids.iter().enumerate() {
+            pairs.extend(relations.members(idx).0.iter().map(|&way_id| (way_id, relation_id)));
+        }
+        // a way listed twice in one relation (e.g. both directions of a route) is reported once
+        pairs.sort_unstable();
+        pairs.dedup();
+        let (way_ids, relation_ids) = pairs.into_iter().unzip();
+        WayRelations { way_ids, relation_ids }
+    }
+
+    /// Relation ids (ascending) that have `way_id` as a member.
+    pub fn get(&self, way_id: u64) -> &[u64] {
+        let start = self.way_ids.partition_point(|&w| w < way_id);
+        let end = self.way_ids.partition_point(|&w| w <= way_id);
+        &self.relation_ids[start..end]
+    }
+}
+
+/// Coarse spatial index written next to the cache as `bbox_index.bin` (`[storage] bbox_index`).
+/// Elements are stored grouped by grid cell, and each cell records where its run lies, so a
+/// loader can later read only the cells overlapping a region.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct BBoxIndex {
+    /// Cell size in degrees: row = floor(lat / cell_deg), col = floor(lon / cell_deg)
+    pub cell_deg: f64,
+    /// `source_hash` of the cache this index describes
+    pub source_hash: u64,
+    pub cells: Vec<BBoxCell>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct BBoxCell {
+    pub row: i32,
+    pub col: i32,
+    /// Element index range `first..first + count` in the stored element list
+    pub first: u64,
+    pub count: u64,
+    /// Byte range of those elements in the decompressed (bincode) cache stream
+    pub byte_start: u64,
+    pub byte_end: u64,
+}
+
+/// An `Element` whose id is a dense index into `CacheData::id_table` (4 bytes smaller per segment
+/// in the cache file; expanded back to `Element` on load)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CompactElement {
+    pub id: u32,
+    pub coordinates: [[f32; 2]; 2],
+    pub tag_set_id: u32,
+}
+
+/// Remap element ids to dense u32 indices. Returns `None` (leaving `elements` untouched) when
+/// there are more distinct ids than fit in a u32.
+pub fn compact_elements(elements: &[Element]) -> Option<(Vec<CompactElement>, Vec<u64>)> {
+    let mut id_table: Vec<u64> = elements.iter().map(|e| e.id).collect();
+    id_table.sort_unstable();
+    id_table.dedup();
+    if id_table.len() > u32::MAX as usize {
+        return None;
     }
+    let compact = elements.iter().map(|e| CompactElement {
+        id: id_table.binary_search(&e.id).unwrap_or_default() as u32,
+        coordinates: e.coordinates,
+        tag_set_id: e.tag_set_id,
+    }).collect();
+    Some((compact, id_table))
+}
+
+/// Inverse of `compact_elements`: restore the original OSM ids.
+pub fn expand_elements(compact: &[CompactElement], id_table: &[u64]) -> Vec<Element> {
+    compact.iter().map(|e| Element {
+        id: id_table.get(e.id as usize).copied().unwrap_or_default(),
+        coordinates: e.coordinates,
+        tag_set_id: e.tag_set_id,
+    }).collect()
 }
 
 /// The structure saved to the cache file
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CacheData {
+    /// Empty when the cache was written with `[runtime] compact_ids` (see `compact_elements`)
     pub elements: Vec<Element>,
+    /// Elements with dense u32 ids (`[runtime] compact_ids`), expanded through `id_table` on load
+    pub compact_elements: Vec<CompactElement>,
+    /// Dense id -> original OSM id
+    pub id_table: Vec<u64>,
     pub tag_sets: FlatTagSets,
     pub interner: StringInterner,
+    pub relations: RelationTable,
+    /// H3 cell of each element's midpoint (`[runtime] h3_resolution`), in element order;
+    /// empty when disabled
+    pub h3_cells: Vec<u64>,
     /// Store a hash of the config AND input file metadata to know when to re-preprocess
     pub source_hash: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Interner over `pool` with the given raw `(offset, length)` entries, as a corrupt cache
+    /// could deserialize them.
+    fn interner(pool: &str, entries: &[(u32, u32)]) -> StringInterner {
+        StringInterner {
+            pool: RwLock::new(Pool(pool.to_string())),
+            offsets: RwLock::new(entries.iter().map(|e| e.0).collect()),
+            lengths: RwLock::new(entries.iter().map(|e| e.1).collect()),
+            ..StringInterner::default()
+        }
+    }
+
+    #[test]
+    fn lookup_valid_entry() {
+        let interner = interner("amenitycafé", &[(0, 7), (7, 5)]);
+        assert_eq!(interner.lookup(0).as_deref(), Some("amenity"));
+        assert_eq!(interner.lookup(1).as_deref(), Some("café"));
+    }
+
+    #[test]
+    fn lookup_out_of_range_offset() {
+        let interner = interner("amenity", &[(100, 3)]);
+        assert_eq!(interner.lookup(0), None);
+    }
+
+    #[test]
+    fn lookup_length_past_end_of_pool() {
+        let interner = interner("amenity", &[(u32::MAX, u32::MAX), (3, u32::MAX)]);
+        assert_eq!(interner.lookup(0), None);
+        assert_eq!(interner.lookup(1), None);
+    }
+
+    #[test]
+    fn lookup_non_char_boundary() {
+        // 'é' is two bytes at 3..5
+        let interner = interner("café", &[(0, 4), (4, 1)]);
+        assert_eq!(interner.lookup(0), None);
+        assert_eq!(interner.lookup(1), None);
+    }
+
+    #[test]
+    fn lookup_unknown_id() {
+        let interner = interner("amenity", &[(0, 7)]);
+        assert_eq!(interner.lookup(1), None);
+        assert_eq!(interner.lookup(u32::MAX), None);
+    }
+
+    #[test]
+    fn find_with_mismatched_offsets_and_lengths() {
+        // one more offset than lengths: the extra id has no string
+        for sorted_index in [false, true] {
+            let interner = interner("amenitycafe", &[(0, 7), (7, 4)]);
+            interner.offsets.write().push(0);
+            if sorted_index {
+                interner.build_sorted_index();
+            }
+            assert_eq!(interner.find("amenity"), Some(0));
+            assert_eq!(interner.find("cafe"), Some(1));
+            assert_eq!(interner.find("bench"), None);
+        }
+        let interner = interner("amenity", &[(0, 7)]);
+        interner.lengths.write().push(3);
+        interner.build_sorted_index();
+        assert_eq!(interner.find("amenity"), Some(0));
+    }
+
+    #[test]
+    fn compact_elements_round_trip() {
+        let element = |id: u64, tag_set_id: u32| Element { id, coordinates: [[52.5, 13.4], [52.6, 13.5]], tag_set_id };
+        // segments of one way share an id; ids above u32::MAX must survive the remapping
+        let elements = vec![element(u64::MAX, 0), element(1, 1), element(u32::MAX as u64 + 1, 2), element(1, 3), element(11_000_000_000, 4)];
+        let (compact, id_table) = compact_elements(&elements).unwrap();
+        assert_eq!(id_table, vec![1, u32::MAX as u64 + 1, 11_000_000_000, u64::MAX]);
+        assert_eq!(compact[1].id, compact[3].id);
+        let expanded = expand_elements(&compact, &id_table);
+        assert_eq!(expanded.len(), elements.len());
+        for (a, b) in expanded.iter().zip(&elements) {
+            assert_eq!((a.id, a.coordinates, a.tag_set_id), (b.id, b.coordinates, b.tag_set_id));
+        }
+    }
+
+    #[test]
+    fn pack_kv_round_trip() {
+        for (key, value) in [(0, 0), (u32::MAX, 0), (0, u32::MAX), (u32::MAX, u32::MAX)] {
+            assert_eq!(unpack_kv(pack_kv(key, value)), (key, value));
+        }
+        assert_eq!(pack_kv(1, 2), (1 << 32) | 2);
+    }
+
+    #[test]
+    fn tag_set_out_of_range() {
+        let tag_sets = FlatTagSets { data: vec![pack_kv(0, 1)], offsets: vec![0, 5, u32::MAX], lengths: vec![1, 1, u32::MAX] };
+        assert_eq!(tag_sets.get(0), Some(&[pack_kv(0, 1)][..]));
+        assert_eq!(tag_sets.get(1), None);
+        assert_eq!(tag_sets.get(2), None);
+        assert_eq!(tag_sets.get(3), None);
+    }
+}
@@ -1,48 +1,226 @@
-use crate::config::Config;
-use crate::model::{Element, StringInterner, CacheData, ConcurrentInterner, InternerLike};
+use crate::config::{BadCoordsPolicy, Config, DuplicateKeyPolicy, InvalidUtf8Policy, TagSetOverflowPolicy};
+use std::borrow::Cow;
+use crate::coords::NodeCoords;
+use crate::model::{pack_kv, unpack_kv, Element, StringInterner, CacheData, ConcurrentInterner, InternerLike, RelationRecord, RelationTable};
 use anyhow::{Result, Context};
-use std::collections::HashSet;
-use rustc_hash::FxHashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
-use tracing::info;
+use tracing::{info, warn};
+use serde::Serialize;
 use roaring::RoaringTreemap;
 use dashmap::DashMap;
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
 
 use std::sync::Arc;
+use rayon::prelude::*;
 use std::sync::atomic::{AtomicUsize, AtomicU32, Ordering};
 
 
 /// Result of loading/preprocessing — currently always an owned in-memory cache.
 pub enum LoadedCache {
-    Owned { elements: Vec<Element>, tag_sets: crate::model::FlatTagSets, interner: StringInterner },
+    Owned { elements: Vec<Element>, tag_sets: crate::model::FlatTagSets, interner: StringInterner, relations: RelationTable, h3_cells: Vec<u64>, source_hash: u64 },
 }
 
-pub fn load_or_preprocess(config: &Config, pbf_path: &Path) -> Result<LoadedCache> {
-    let source_hash = calculate_source_hash(config, pbf_path)?;
+impl LoadedCache {
+    /// A dataset with no elements (used when `[runtime] allow_empty` lets the server start
+    /// before any data is staged).
+    pub fn empty() -> Self {
+        LoadedCache::Owned {
+            elements: Vec::new(),
+            tag_sets: crate::model::FlatTagSets::default(),
+            interner: StringInterner::default(),
+            relations: RelationTable::default(),
+            h3_cells: Vec::new(),
+            source_hash: 0,
+        }
+    }
+}
+
+/// Machine-readable summary of a preprocessing run, written when `--report <path>` is given.
+#[derive(Debug, Serialize, Default)]
+pub struct PreprocessReport {
+    pub inputs: Vec<String>,
+    pub passes: Vec<PassReport>,
+    pub counts: crate::model::DatasetCounts,
+    pub tag_sets: usize,
+    pub strings: usize,
+    /// Filter match rates: pass 1 counts primary-key hits, pass 3 extracted elements
+    pub pass1_matches: MatchRate,
+    pub pass3_matches: MatchRate,
+}
+
+/// Scanned vs matched nodes and ways of one pass (summed over all inputs). A very low rate
+/// usually means a filter typo or an extract that doesn't fit the filters.
+#[derive(Debug, Serialize, Default, Clone, Copy)]
+pub struct MatchRate {
+    pub nodes_scanned: usize,
+    pub nodes_matched: usize,
+    pub ways_scanned: usize,
+    pub ways_matched: usize,
+}
+
+impl MatchRate {
+    fn log(&self, pass: &str) {
+        let percent = |matched: usize, scanned: usize| if scanned == 0 { 0.0 } else { matched as f64 * 100.0 / scanned as f64 };
+        info!("  {} match rate: nodes {}/{} ({:.3}%), ways {}/{} ({:.3}%)", pass,
+            self.nodes_matched, self.nodes_scanned, percent(self.nodes_matched, self.nodes_scanned),
+            self.ways_matched, self.ways_scanned, percent(self.ways_matched, self.ways_scanned));
+    }
+}
+
+/// Counters behind a `MatchRate`, shared by a pass's worker threads.
+#[derive(Default)]
+struct MatchCounters {
+    nodes_scanned: AtomicUsize,
+    nodes_matched: AtomicUsize,
+    ways_scanned: AtomicUsize,
+    ways_matched: AtomicUsize,
+}
+
+impl MatchCounters {
+    fn node(&self, matched: bool) {
+        self.nodes_scanned.fetch_add(1, Ordering::Relaxed);
+        if matched {
+            self.nodes_matched.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn way(&self, matched: bool) {
+        self.ways_scanned.fetch_add(1, Ordering::Relaxed);
+        if matched {
+            self.ways_matched.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> MatchRate {
+        MatchRate {
+            nodes_scanned: self.nodes_scanned.load(Ordering::Relaxed),
+            nodes_matched: self.nodes_matched.load(Ordering::Relaxed),
+            ways_scanned: self.ways_scanned.load(Ordering::Relaxed),
+            ways_matched: self.ways_matched.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PassReport {
+    pub name: &'static str,
+    pub seconds: f64,
+    /// Resident memory at the end of the pass
+    pub rss_mb: Option<u64>,
+    /// Peak resident memory of the process so far (VmHWM)
+    pub peak_rss_mb: Option<u64>,
+}
+
+/// Read a `kB` field (e.g. `VmRSS`, `VmHWM`) from `/proc/self/status`, in MB.
+fn proc_status_mb(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with(field) && l[field.len()..].starts_with(':'))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+impl PreprocessReport {
+    /// Record a finished pass and log its memory figures.
+    fn pass(&mut self, name: &'static str, started: std::time::Instant) {
+        let pass = PassReport {
+            name,
+            seconds: started.elapsed().as_secs_f64(),
+            rss_mb: proc_status_mb("VmRSS"),
+            peak_rss_mb: proc_status_mb("VmHWM"),
+        };
+        if let (Some(rss), Some(peak)) = (pass.rss_mb, pass.peak_rss_mb) {
+            info!("  Memory after {}: {} MB resident, {} MB peak", name, rss, peak);
+        }
+        self.passes.push(pass);
+    }
+}
+
+fn read_cache(path: &Path) -> Result<CacheData> {
+    let reader = BufReader::new(File::open(path).with_context(|| format!("Failed to open cache: {:?}", path))?);
+    let mut decoder = zstd::stream::read::Decoder::new(reader)?;
+    bincode::deserialize_from(&mut decoder).with_context(|| format!("Failed to decode cache: {:?}", path))
+}
+
+/// Re-encode an existing `data.bin.zst` at another zstd level without touching the PBF inputs.
+/// The new file is written next to the old one, decoded again to check that `source_hash`
+/// survived, and only then renamed over the original.
+pub fn recompress_cache(config: &Config, level: i32) -> Result<()> {
+    let cache_file = config.storage.cache_dir.join("data.bin.zst");
+    let tmp_file = config.storage.cache_dir.join("data.bin.zst.tmp");
+    let before = std::fs::metadata(&cache_file).with_context(|| format!("No cache to recompress at {:?}", cache_file))?.len();
+
+    info!("Recompressing {:?} at zstd level {}...", cache_file, level);
+    let t = std::time::Instant::now();
+    let cache_data = read_cache(&cache_file)?;
+    let source_hash = cache_data.source_hash;
+    {
+        let writer = BufWriter::new(File::create(&tmp_file).with_context(|| format!("Failed to create {:?}", tmp_file))?);
+        let mut encoder = zstd::stream::write::Encoder::new(writer, level)?;
+        bincode::serialize_into(&mut encoder, &cache_data)?;
+        encoder.finish()?.flush()?;
+    }
+    drop(cache_data);
+
+    let roundtrip_hash = read_cache(&tmp_file)?.source_hash;
+    if roundtrip_hash != source_hash {
+        let _ = std::fs::remove_file(&tmp_file);
+        anyhow::bail!("recompressed cache has source_hash {:016x}, expected {:016x}", roundtrip_hash, source_hash);
+    }
+    std::fs::rename(&tmp_file, &cache_file).with_context(|| format!("Failed to replace {:?}", cache_file))?;
+    let after = std::fs::metadata(&cache_file)?.len();
+    info!("Recompressed cache: {} -> {} bytes ({:.2?})", before, after, t.elapsed());
+    Ok(())
+}
+
+/// Write generated data (`generate` subcommand) as the cache at the configured zstd level and
+/// return it loaded, bypassing PBF input entirely.
+pub fn store_generated(config: &Config, cache_data: CacheData) -> Result<LoadedCache> {
+    let cache_file = config.storage.cache_dir.join("data.bin.zst");
+    let t = std::time::Instant::now();
+    {
+        let writer = BufWriter::new(File::create(&cache_file).with_context(|| format!("Failed to create {:?}", cache_file))?);
+        let mut encoder = zstd::stream::write::Encoder::new(writer, config.storage.zstd_level as i32)?;
+        bincode::serialize_into(&mut encoder, &cache_data)?;
+        encoder.finish()?.flush()?;
+    }
+    info!("Generated cache written to {:?} ({} elements, {:.2?})", cache_file, cache_data.elements.len(), t.elapsed());
+
+    prepare_runtime_interner(config, &cache_data.interner);
+    Ok(LoadedCache::Owned {
+        elements: cache_data.elements,
+        tag_sets: cache_data.tag_sets,
+        interner: cache_data.interner,
+        relations: cache_data.relations,
+        h3_cells: cache_data.h3_cells,
+        source_hash: cache_data.source_hash,
+    })
+}
+
+pub fn load_or_preprocess(config: &Config, pbf_paths: &[PathBuf], report_path: Option<&Path>) -> Result<LoadedCache> {
+    let source_hash = calculate_source_hash(config, pbf_paths)?;
     let cache_file_zst = config.storage.cache_dir.join("data.bin.zst");
 
 
     // Only use the compressed zst cache (legacy uncompressed cache support removed)
     if cache_file_zst.exists() {
-        let file = File::open(&cache_file_zst)?;
-        let reader = BufReader::new(file);
-        let mut decoder = zstd::stream::read::Decoder::new(reader)?;
-        let cache_data_res: Result<CacheData, _> = bincode::deserialize_from(&mut decoder);
-        if let Ok(mut cache_data) = cache_data_res {
+        if let Ok(cache_data) = read_cache(&cache_file_zst) {
             if cache_data.source_hash == source_hash {
                 info!("Loading data from cache: {:?}", cache_file_zst);
 
                 // optionally clear the runtime-only interner HashMap to save RAM (controlled by config)
-                if config.runtime.drop_interner_map {
-                    cache_data.interner.map.write().clear();
-                }
+                prepare_runtime_interner(config, &cache_data.interner);
 
-                return Ok(LoadedCache::Owned { elements: cache_data.elements, tag_sets: cache_data.tag_sets, interner: cache_data.interner });
+                let elements = if cache_data.compact_elements.is_empty() {
+                    cache_data.elements
+                } else {
+                    crate::model::expand_elements(&cache_data.compact_elements, &cache_data.id_table)
+                };
+                return Ok(LoadedCache::Owned { elements, tag_sets: cache_data.tag_sets, interner: cache_data.interner, relations: cache_data.relations, h3_cells: cache_data.h3_cells, source_hash });
             }
         }
 
@@ -50,54 +228,432 @@ pub fn load_or_preprocess(config: &Config, pbf_path: &Path) -> Result<LoadedCach
     }
 
     // Write compressed cache to the new zst path
-    match preprocess(config, pbf_path, source_hash, &cache_file_zst) {
-        Ok((elements, tag_sets, mut interner)) => {
+    match preprocess(config, pbf_paths, source_hash, &cache_file_zst, report_path) {
+        Ok((elements, tag_sets, interner, relations, h3_cells)) => {
 
 
-            if config.runtime.drop_interner_map {
-                interner.map.write().clear();
-            }
+            prepare_runtime_interner(config, &interner);
 
-            Ok(LoadedCache::Owned { elements, tag_sets, interner })
+            Ok(LoadedCache::Owned { elements, tag_sets, interner, relations, h3_cells, source_hash })
         }
         Err(e) => Err(e),
     }
 }
 
-fn calculate_source_hash(config: &Config, pbf_path: &Path) -> Result<u64> {
+/// Drop the interner map if configured and, when it was dropped, build the compact sorted
+/// string -> id index used by server-side tag filters.
+fn prepare_runtime_interner(config: &Config, interner: &StringInterner) {
+    if config.runtime.drop_interner_map {
+        interner.map.write().clear();
+        if config.runtime.string_index {
+            interner.build_sorted_index();
+        }
+    }
+}
+
+/// No `data.bin.zst` yet and a local input that does not exist (not staged yet): the one load
+/// failure `[runtime] allow_empty` may turn into an empty dataset. Stdin and URL inputs never
+/// count as missing.
+pub fn data_missing(config: &Config, inputs: &[PathBuf]) -> bool {
+    let is_local = |path: &Path| {
+        let path = path.to_string_lossy();
+        path != "-" && !path.starts_with("http://") && !path.starts_with("https://")
+    };
+    !config.storage.cache_dir.join("data.bin.zst").exists() && inputs.iter().any(|p| is_local(p) && !p.exists())
+}
+
+fn calculate_source_hash(config: &Config, pbf_paths: &[PathBuf]) -> Result<u64> {
     let mut s = DefaultHasher::new();
     config.filters.primary_keys.hash(&mut s);
     config.filters.attribute_keys.hash(&mut s);
-    
-    let metadata = std::fs::metadata(pbf_path)
-        .with_context(|| format!("Failed to get metadata for PBF: {:?}", pbf_path))?;
-    
-    if let Ok(abs_path) = pbf_path.canonicalize() {
-        abs_path.to_string_lossy().hash(&mut s);
-    } else {
-        pbf_path.to_string_lossy().hash(&mut s);
+    config.filters.key_aliases.hash(&mut s);
+    config.filters.min_tag_count.hash(&mut s);
+    config.filters.max_tags_per_element.hash(&mut s);
+    config.filters.on_duplicate_key.hash(&mut s);
+    config.runtime.simplify_tolerance_m.to_bits().hash(&mut s);
+    config.filters.relation_types.hash(&mut s);
+    config.filters.max_tag_sets.hash(&mut s);
+    config.runtime.compact_ids.hash(&mut s);
+    config.filters.on_tag_set_overflow.hash(&mut s);
+    config.filters.on_invalid_utf8.hash(&mut s);
+    config.filters.on_bad_coords.hash(&mut s);
+    config.filters.require_attribute_way_nodes.hash(&mut s);
+    config.runtime.h3_resolution.hash(&mut s);
+    // enabling the Parquet output re-runs preprocessing so the files actually get written
+    config.storage.emit_parquet.hash(&mut s);
+    config.storage.deterministic.hash(&mut s);
+    config.storage.bbox_index.hash(&mut s);
+    config.storage.bbox_index_cell_deg.to_bits().hash(&mut s);
+    if let Some(clip) = &config.filters.clip_polygon {
+        hash_file_contents(clip, &mut s)
+            .with_context(|| format!("Failed to read clip polygon: {:?}", clip))?;
     }
-    
-    metadata.len().hash(&mut s);
-    if let Ok(modified) = metadata.modified() {
-        modified.hash(&mut s);
+    for c in &config.filters.include_circles {
+        (c.lat.to_bits(), c.lon.to_bits(), c.radius_m.to_bits()).hash(&mut s);
+    }
+
+    for pbf_path in pbf_paths {
+        let metadata = std::fs::metadata(pbf_path)
+            .with_context(|| format!("Failed to get metadata for PBF: {:?}", pbf_path))?;
+
+        if let Ok(abs_path) = pbf_path.canonicalize() {
+            abs_path.to_string_lossy().hash(&mut s);
+        } else {
+            pbf_path.to_string_lossy().hash(&mut s);
+        }
+
+        metadata.len().hash(&mut s);
+        if crate::input::is_staged(config, pbf_path) {
+            // stdin/URL inputs are re-staged every run: hash the content instead of the mtime
+            hash_file_contents(pbf_path, &mut s)?;
+        } else if let Ok(modified) = metadata.modified() {
+            modified.hash(&mut s);
+        }
     }
     
     Ok(s.finish())
 }
 
-fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &Path) -> Result<(Vec<Element>, crate::model::FlatTagSets, StringInterner)> {
-    use osmpbf::{ElementReader, Element as OsmElement};
-    info!("Starting Optimized PBF preprocessing: {:?}", pbf_path);
-    // Pass 1: Identify "Required" Nodes
-    info!("Pass 1: Identifying required node IDs...");
-    let t1 = std::time::Instant::now();
+fn hash_file_contents(path: &Path, hasher: &mut DefaultHasher) -> Result<()> {
+    use std::io::Read;
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write(&buf[..n]);
+    }
+    Ok(())
+}
+
+/// Rough in-memory cost of one pass-2 coordinate entry (DashMap peak), used for `coords_spill_min_mb`
+const COORD_MAP_BYTES_PER_NODE: u64 = 32;
+
+/// Round-trip error (metres) above which an f32-stored coordinate is reported as lossy
+const LOSSY_COORD_THRESHOLD_M: f64 = 1.0;
+
+/// Convert an `osmpbf` f64 coordinate to the f32 storage format, counting conversions whose
+/// round-trip error exceeds `LOSSY_COORD_THRESHOLD_M`.
+fn to_stored_coord(lat: f64, lon: f64, lossy: &AtomicUsize) -> (f32, f32) {
+    let (lat32, lon32) = (lat as f32, lon as f32);
+    let dlat_m = (lat - lat32 as f64).abs() * 111_320.0;
+    let dlon_m = (lon - lon32 as f64).abs() * 111_320.0 * lat.to_radians().cos();
+    if dlat_m.hypot(dlon_m) > LOSSY_COORD_THRESHOLD_M {
+        lossy.fetch_add(1, Ordering::Relaxed);
+    }
+    (lat32, lon32)
+}
+
+/// Coordinate validation and f32 conversion shared by passes 2 and 3, with counters for the
+/// end-of-run report.
+struct CoordChecks {
+    on_bad_coords: BadCoordsPolicy,
+    /// Conversions whose f32 error exceeded `LOSSY_COORD_THRESHOLD_M`
+    lossy: AtomicUsize,
+    /// Coordinates outside ±90° latitude / ±180° longitude
+    bad: AtomicUsize,
+}
+
+impl CoordChecks {
+    fn new(config: &Config) -> Self {
+        CoordChecks { on_bad_coords: config.filters.on_bad_coords, lossy: AtomicUsize::new(0), bad: AtomicUsize::new(0) }
+    }
+
+    /// Validate and convert a node position. Out-of-range coordinates are counted and then
+    /// clamped or dropped (`error` drops them too and aborts once pass 3 finishes); a dropped
+    /// way node leaves its segments without coordinates, so they are skipped.
+    fn store(&self, lat: f64, lon: f64) -> Option<(f32, f32)> {
+        if (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon) {
+            return Some(to_stored_coord(lat, lon, &self.lossy));
+        }
+        self.bad.fetch_add(1, Ordering::Relaxed);
+        match self.on_bad_coords {
+            BadCoordsPolicy::Clamp if !lat.is_nan() && !lon.is_nan() => {
+                Some(to_stored_coord(lat.clamp(-90.0, 90.0), lon.clamp(-180.0, 180.0), &self.lossy))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Concurrent tag-set interning: DashMap + atomic counter (avoids a single RwLock<Vec<...>>).
+/// Shared by every input file processed in pass 3.
+#[derive(Default)]
+struct TagSetInterner {
+    map: DashMap<Vec<(u32, u32)>, u32>,
+    reverse: DashMap<u32, Vec<(u32, u32)>>,
+    counter: AtomicU32,
+    /// `[filters] max_tag_sets` (0 = unlimited). Checked without locking, so concurrent
+    /// inserts may overshoot it by a few sets.
+    max_tag_sets: usize,
+    /// Number of lookups that fell back to the shared misc (empty) set
+    overflowed: AtomicUsize,
+}
+
+impl TagSetInterner {
+    fn new(max_tag_sets: usize) -> Self {
+        Self { max_tag_sets, ..Default::default() }
+    }
+
+    fn get_or_intern(&self, tags: Vec<(u32, u32)>) -> u32 {
+        if let Some(id) = self.map.get(&tags) {
+            return *id;
+        }
+        if self.max_tag_sets > 0 && !tags.is_empty() && self.counter.load(Ordering::Relaxed) as usize >= self.max_tag_sets {
+            if self.overflowed.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("max_tag_sets={} reached; further new tag sets map to a shared misc set", self.max_tag_sets);
+            }
+            return self.get_or_intern(Vec::new());
+        }
+        let id = self.counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(prev) = self.map.insert(tags.clone(), id) {
+            return prev;
+        }
+        self.reverse.insert(id, tags);
+        id
+    }
+}
+
+/// Key filters applied to an element's tags in pass 3.
+struct TagFilter<'a> {
+    /// Canonical names (after `key_aliases`)
+    primary_keys: HashSet<&'a str>,
+    attribute_keys: HashSet<&'a str>,
+    /// alias -> canonical key
+    key_aliases: HashMap<&'a str, &'a str>,
+    /// `attribute_keys = ["*"]`: keep every tag of a qualifying element
+    all_attributes: bool,
+    /// Maximum number of pairs kept per element (0 = unlimited)
+    max_tags_per_element: usize,
+    /// Number of elements whose tag list was truncated by `max_tags_per_element`
+    truncated: AtomicUsize,
+    on_duplicate_key: DuplicateKeyPolicy,
+    /// Number of elements carrying the same key more than once
+    duplicates: AtomicUsize,
+    on_invalid_utf8: InvalidUtf8Policy,
+    /// Number of tags with a key or value that is not valid UTF-8
+    invalid_utf8: AtomicUsize,
+}
+
+impl<'a> TagFilter<'a> {
+    fn new(config: &'a Config) -> Self {
+        Self {
+            primary_keys: config.filters.canonical_keys(&config.filters.primary_keys),
+            attribute_keys: config.filters.canonical_keys(&config.filters.attribute_keys),
+            key_aliases: config.filters.key_aliases.iter().map(|(alias, key)| (alias.as_str(), key.as_str())).collect(),
+            all_attributes: config.filters.attribute_keys.iter().any(|k| k == "*"),
+            max_tags_per_element: config.filters.max_tags_per_element,
+            truncated: AtomicUsize::new(0),
+            on_duplicate_key: config.filters.on_duplicate_key,
+            duplicates: AtomicUsize::new(0),
+            on_invalid_utf8: config.filters.on_invalid_utf8,
+            invalid_utf8: AtomicUsize::new(0),
+        }
+    }
+
+    /// Decode raw string-table tags. `osmpbf`'s `tags()` silently stops at the first invalid
+    /// UTF-8 string, so every element kind (dense nodes included) is decoded here under `on_invalid_utf8`
+    /// (`error` behaves like `skip` during the pass and aborts once it finishes).
+    fn decode<'t>(&self, raw: impl Iterator<Item = (u32, u32)>, table: &'t [Vec<u8>]) -> Vec<(Cow<'t, str>, Cow<'t, str>)> {
+        let mut tags = Vec::new();
+        for (k, v) in raw {
+            let (Some(k), Some(v)) = (table.get(k as usize), table.get(v as usize)) else { continue };
+            match (std::str::from_utf8(k), std::str::from_utf8(v)) {
+                (Ok(k), Ok(v)) => tags.push((Cow::Borrowed(k), Cow::Borrowed(v))),
+                _ => {
+                    self.invalid_utf8.fetch_add(1, Ordering::Relaxed);
+                    if self.on_invalid_utf8 == InvalidUtf8Policy::Replace {
+                        tags.push((String::from_utf8_lossy(k), String::from_utf8_lossy(v)));
+                    }
+                }
+            }
+        }
+        tags
+    }
+
+    /// `decode` for a dense node, whose tags index the string table of its block.
+    fn decode_dense<'t>(&self, node: &osmpbf::DenseNode, strings: &'t [Vec<u8>]) -> Vec<(Cow<'t, str>, Cow<'t, str>)> {
+        self.decode(node.raw_tags().map(|(k, v)| (k as u32, v as u32)), strings)
+    }
+
+    /// Intern the primary + attribute tags of one element. Returns `None` when the element
+    /// carries no primary key (and therefore is not part of the dataset).
+    /// Aliased keys are renamed to their canonical key first; only a repeated original key
+    /// counts as a duplicate.
+    fn extract<'t>(&self, tags: impl Iterator<Item = (&'t str, &'t str)>, interner: &impl InternerLike) -> Option<Vec<(u32, u32)>>
+    where
+        'a: 't,
+    {
+        // (is_primary, original key, key, value) in original order; interning happens after truncation
+        let mut matched: Vec<(bool, &'t str, &'t str, &'t str)> = Vec::new();
+        let mut has_primary = false;
+        for (original, v) in tags {
+            let k = self.key_aliases.get(original).copied().unwrap_or(original);
+            if self.primary_keys.contains(k) {
+                has_primary = true;
+                matched.push((true, original, k, v));
+            } else if self.all_attributes || self.attribute_keys.contains(k) {
+                matched.push((false, original, k, v));
+            }
+        }
+        if !has_primary {
+            return None;
+        }
+
+        // OSM disallows duplicate keys but broken data has them: keep exactly one per key
+        let has_duplicates = (1..matched.len()).any(|i| matched[..i].iter().any(|m| m.1 == matched[i].1));
+        if has_duplicates {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            let keep_first = self.on_duplicate_key == DuplicateKeyPolicy::First;
+            matched = matched.iter().enumerate()
+                .filter(|&(i, m)| if keep_first {
+                    !matched[..i].iter().any(|o| o.1 == m.1)
+                } else {
+                    !matched[i + 1..].iter().any(|o| o.1 == m.1)
+                })
+                .map(|(_, &m)| m)
+                .collect();
+        }
+
+        // a key next to one of its aliases (`name` + `name:en` -> `name`) is valid data, not a
+        // duplicate: the canonical key wins, otherwise the first alias
+        let has_alias_collisions = (1..matched.len()).any(|i| matched[..i].iter().any(|m| m.2 == matched[i].2));
+        if has_alias_collisions {
+            matched = matched.iter().enumerate()
+                .filter(|&(i, m)| if matched.iter().any(|o| o.2 == m.2 && o.1 == o.2) {
+                    m.1 == m.2
+                } else {
+                    !matched[..i].iter().any(|o| o.2 == m.2)
+                })
+                .map(|(_, &m)| m)
+                .collect();
+        }
+
+        if self.max_tags_per_element > 0 && matched.len() > self.max_tags_per_element {
+            // keep primaries first so truncation never drops the tag that qualified the element
+            matched.sort_by_key(|&(is_primary, _, _, _)| !is_primary);
+            matched.truncate(self.max_tags_per_element);
+            self.truncated.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Some(matched.into_iter().map(|(_, _, k, v)| (interner.get_or_intern(k), interner.get_or_intern(v))).collect())
+    }
+}
+
+/// Run `f` while a helper thread logs the growth of `counter` every `interval_secs`
+/// (0 = no logging). Gives a steady throughput readout that shows when a pass stalls on I/O.
+fn with_progress<T>(interval_secs: u64, label: &str, unit: &str, counter: &AtomicUsize, f: impl FnOnce() -> T) -> T {
+    if interval_secs == 0 {
+        return f();
+    }
+    let done = parking_lot::Mutex::new(false);
+    let wake = parking_lot::Condvar::new();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let interval = std::time::Duration::from_secs(interval_secs);
+            let started = std::time::Instant::now();
+            let mut last = 0;
+            let mut finished = done.lock();
+            while !*finished {
+                if !wake.wait_for(&mut finished, interval).timed_out() {
+                    continue;
+                }
+                let total = counter.load(Ordering::Relaxed);
+                info!("  [{}] {:.1}M {} ({:.0}/s, avg {:.0}/s)", label, total as f64 / 1e6, unit,
+                    (total - last) as f64 / interval.as_secs_f64(), total as f64 / started.elapsed().as_secs_f64());
+                last = total;
+            }
+        });
+        let result = f();
+        *done.lock() = true;
+        wake.notify_all();
+        result
+    })
+}
+
+/// Short name of an input for progress lines.
+fn file_label(path: &Path) -> String {
+    path.file_name().map_or_else(|| path.display().to_string(), |n| n.to_string_lossy().into_owned())
+}
+
+/// One input PBF. With `[storage] mmap_input` the file is mapped once and every pass reads the
+/// same mapping instead of reopening and re-reading the file through its own buffer.
+struct PbfInput<'a> {
+    path: &'a Path,
+    map: Option<memmap2::Mmap>,
+}
+
+impl<'a> PbfInput<'a> {
+    fn open(config: &Config, path: &'a Path) -> Result<Self> {
+        let map = if config.storage.mmap_input {
+            let file = File::open(path).with_context(|| format!("Failed to open PBF: {:?}", path))?;
+            // SAFETY: inputs are read-only for the whole run; truncating one underneath us is
+            // outside what preprocessing supports either way
+            let map = unsafe { memmap2::Mmap::map(&file) }.with_context(|| format!("Failed to map PBF: {:?}", path))?;
+            #[cfg(unix)]
+            let _ = map.advise(memmap2::Advice::Sequential);
+            Some(map)
+        } else {
+            None
+        };
+        Ok(PbfInput { path, map })
+    }
+
+    fn read(&self) -> Result<Box<dyn std::io::Read + Send + '_>> {
+        Ok(match &self.map {
+            Some(map) => Box::new(&map[..]),
+            None => Box::new(BufReader::new(File::open(self.path).with_context(|| format!("Failed to open PBF: {:?}", self.path))?)),
+        })
+    }
+
+    fn reader(&self) -> Result<osmpbf::ElementReader<Box<dyn std::io::Read + Send + '_>>> {
+        Ok(osmpbf::ElementReader::new(self.read()?))
+    }
 
+    /// Like `ElementReader::par_map_reduce`, but `map_op` also gets the raw string table of the
+    /// element's block: dense nodes expose none of their own, and their `tags()` silently stops
+    /// at the first string that is not valid UTF-8.
+    fn par_map_reduce_raw<T: Send>(
+        &self,
+        map_op: impl for<'b> Fn(osmpbf::Element<'b>, &'b [Vec<u8>]) -> T + Sync + Send,
+        identity: impl Fn() -> T + Sync + Send,
+        reduce_op: impl Fn(T, T) -> T + Sync + Send,
+    ) -> Result<T> {
+        use rayon::iter::{ParallelBridge, ParallelIterator};
+        osmpbf::BlobReader::new(self.read()?)
+            .par_bridge()
+            .map(|blob| match blob?.decode()? {
+                osmpbf::BlobDecode::OsmData(block) => {
+                    let strings = block.raw_stringtable();
+                    Ok(block.elements().map(|element| map_op(element, strings)).fold(identity(), &reduce_op))
+                }
+                _ => Ok(identity()),
+            })
+            .reduce(|| Ok(identity()), |a, b| Ok(reduce_op(a?, b?)))
+            .map_err(|e: osmpbf::Error| anyhow::anyhow!("PBF Error: {:?}", e))
+    }
+}
+
+/// Pass 1: Identify "Required" Nodes (nodes of primary-tagged ways + primary-tagged nodes)
+fn collect_required_nodes(config: &Config, input: &PbfInput, matches: &MatchCounters) -> Result<RoaringTreemap> {
+    use osmpbf::Element as OsmElement;
+    let pbf_path = input.path;
     let node_count = AtomicUsize::new(0);
-    let primary_keys_set: HashSet<&str> = config.filters.primary_keys.iter().map(|s| s.as_str()).collect();
+    // keys are compared after `key_aliases`, like in pass 3
+    let primary_keys_set = config.filters.canonical_keys(&config.filters.primary_keys);
+    let is_primary = |k: &str| primary_keys_set.contains(config.filters.canonical_key(k));
+    // ways whose nodes are required; with require_attribute_way_nodes also ways carrying only
+    // attribute keys (`"*"` would match every tagged way, so it is not expanded here)
+    let mut way_keys_set = primary_keys_set.clone();
+    if config.filters.require_attribute_way_nodes {
+        way_keys_set.extend(config.filters.canonical_keys(&config.filters.attribute_keys).into_iter().filter(|k| *k != "*"));
+    }
 
-    let reader = ElementReader::from_path(pbf_path)?;
-    let required_nodes: RoaringTreemap = reader.par_map_reduce(
+    let reader = input.reader()?;
+    let label = format!("pass1 {}", file_label(pbf_path));
+    let required_nodes: RoaringTreemap = with_progress(config.runtime.progress_interval_secs, &label, "nodes", &node_count, || reader.par_map_reduce(
         |element| {
             let mut local_required = RoaringTreemap::new();
             let mut local_count = 0;
@@ -105,7 +661,9 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
             match element {
                 OsmElement::Way(way) => {
                     // avoid allocating a HashMap for every way -- just check the tags iterator
-                    if way.tags().any(|(k, _)| primary_keys_set.contains(k)) {
+                    let matched = way.tags().any(|(k, _)| way_keys_set.contains(config.filters.canonical_key(k)));
+                    matches.way(matched);
+                    if matched {
                         for node_id in way.refs() {
                             local_required.insert(node_id as u64);
                         }
@@ -113,13 +671,17 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
                 }
                 OsmElement::Node(node) => {
                     local_count += 1;
-                    if node.tags().any(|(k, _)| primary_keys_set.contains(k)) {
+                    let matched = node.tags().any(|(k, _)| is_primary(k));
+                    matches.node(matched);
+                    if matched {
                         local_required.insert(node.id() as u64);
                     }
                 }
                 OsmElement::DenseNode(node) => {
                     local_count += 1;
-                    if node.tags().any(|(k, _)| primary_keys_set.contains(k)) {
+                    let matched = node.tags().any(|(k, _)| is_primary(k));
+                    matches.node(matched);
+                    if matched {
                         local_required.insert(node.id() as u64);
                     }
                 }
@@ -140,19 +702,21 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
             a |= b;
             a
         },
-    ).map_err(|e| anyhow::anyhow!("PBF Error: {:?}", e))?;
+    )).map_err(|e| anyhow::anyhow!("PBF Error: {:?}", e))?;
 
-    info!("Identified {} unique nodes required for filtered data. (pass1: {:.2?})", required_nodes.len(), t1.elapsed());
+    Ok(required_nodes)
+}
 
-    // Pass 2: Collect Coordinates for Required Nodes only
-    info!("Pass 2: Collecting coordinates for {} required nodes...", required_nodes.len());
-    let t2 = std::time::Instant::now();
-    let node_coords = Arc::new(dashmap::DashMap::with_capacity(required_nodes.len() as usize));
+/// Pass 2: Collect Coordinates for Required Nodes only. Returns the number of coordinates stored.
+fn collect_coordinates(config: &Config, input: &PbfInput, required_nodes: &RoaringTreemap, node_coords: &NodeCoords, coord_checks: &CoordChecks) -> Result<usize> {
+    use osmpbf::Element as OsmElement;
+    let pbf_path = input.path;
     let node_count_pass2 = AtomicUsize::new(0);
     let coords_stored = AtomicUsize::new(0);
     
-    let reader_pass2 = ElementReader::from_path(pbf_path)?;
-    reader_pass2.par_map_reduce(
+    let reader_pass2 = input.reader()?;
+    let label = format!("pass2 {}", file_label(pbf_path));
+    with_progress(config.runtime.progress_interval_secs, &label, "nodes", &node_count_pass2, || reader_pass2.par_map_reduce(
         |element| {
             let mut local_count = 0;
             match element {
@@ -160,16 +724,20 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
                     local_count += 1;
                     let id = node.id() as u64;
                     if required_nodes.contains(id) {
-                        node_coords.insert(id, (node.lat() as f32, node.lon() as f32));
-                        coords_stored.fetch_add(1, Ordering::Relaxed);
+                        if let Some(coord) = coord_checks.store(node.lat(), node.lon()) {
+                            node_coords.insert(id, coord);
+                            coords_stored.fetch_add(1, Ordering::Relaxed);
+                        }
                     }
                 }
                 OsmElement::DenseNode(node) => {
                     local_count += 1;
                     let id = node.id() as u64;
                     if required_nodes.contains(id) {
-                        node_coords.insert(id, (node.lat() as f32, node.lon() as f32));
-                        coords_stored.fetch_add(1, Ordering::Relaxed);
+                        if let Some(coord) = coord_checks.store(node.lat(), node.lon()) {
+                            node_coords.insert(id, coord);
+                            coords_stored.fetch_add(1, Ordering::Relaxed);
+                        }
                     }
                 }
                 _ => {}
@@ -184,150 +752,123 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
         },
         || (),
         |_, _| (),
-    ).map_err(|e| anyhow::anyhow!("PBF Error: {:?}", e))?;
-    
-    let final_coords_stored = coords_stored.load(Ordering::Relaxed) as u64;
-    info!("Coordinate collection complete. Loaded {} coordinates (expected {}). (pass2: {:.2?})", final_coords_stored, required_nodes.len(), t2.elapsed());
-    if final_coords_stored < required_nodes.len() {
-        info!("  WARNING: {} required nodes were NOT found in the PBF file.", required_nodes.len() - final_coords_stored);
-    }
+    )).map_err(|e| anyhow::anyhow!("PBF Error: {:?}", e))?;
 
-    // Compact node coordinate store into a FxHashMap to reduce memory overhead and speed reads
-    let mut compact_coords: FxHashMap<u64, (f32, f32)> = FxHashMap::default();
-    compact_coords.reserve(final_coords_stored as usize);
-    for entry in node_coords.iter() {
-        compact_coords.insert(*entry.key(), *entry.value());
-    }
-    // Shadow the previous `node_coords` with a compact, read-only Arc<FxHashMap<..>> used by pass 3
-    let node_coords = Arc::new(compact_coords);
-
-    // Pass 3: Extract and Filter
-    info!("Pass 3: Final extraction and tag interning...");
-    let t3 = std::time::Instant::now();
-    // Use a concurrent interner during parallel processing to avoid heavy locking
-    let interner = Arc::new(ConcurrentInterner::new());
-    let primary_keys_set: HashSet<&str> = config.filters.primary_keys.iter().map(|s| s.as_str()).collect();
-    let attribute_keys_set: HashSet<&str> = config.filters.attribute_keys.iter().map(|s| s.as_str()).collect();
-    
-    // Concurrent tag-set interning: DashMap + atomic counter (avoids a single RwLock<Vec<...>>)
-    let tag_set_map: DashMap<Vec<(u32, u32)>, u32> = DashMap::new();
-    let tag_set_reverse: DashMap<u32, Vec<(u32, u32)>> = DashMap::new();
-    let tag_set_counter = AtomicU32::new(0);
-
-    let get_tag_set_id = |tags: Vec<(u32, u32)>| -> u32 {
-        if let Some(id) = tag_set_map.get(&tags) {
-            return *id;
-        }
-        let id = tag_set_counter.fetch_add(1, Ordering::Relaxed);
-        if let Some(prev) = tag_set_map.insert(tags.clone(), id) {
-            return prev;
-        }
-        tag_set_reverse.insert(id, tags);
-        id
-    };
+    Ok(coords_stored.load(Ordering::Relaxed))
+}
 
-    let reader_pass3 = ElementReader::from_path(pbf_path)?;
+/// Pass 3: Extract and Filter one input file against the shared interners.
+/// Returns the extracted elements and the number of way segments skipped for missing coordinates.
+#[allow(clippy::too_many_arguments)]
+fn extract_elements(
+    config: &Config,
+    input: &PbfInput,
+    tag_filter: &TagFilter,
+    node_coords: &NodeCoords,
+    interner: &ConcurrentInterner,
+    tag_sets: &TagSetInterner,
+    coord_checks: &CoordChecks,
+    relations: &parking_lot::Mutex<Vec<RelationRecord>>,
+    clip: Option<&crate::geometry::ClipRegions>,
+    matches: &MatchCounters,
+) -> Result<(Vec<Element>, usize)> {
+    use osmpbf::Element as OsmElement;
+    let pbf_path = input.path;
     let segments_skipped = AtomicUsize::new(0);
-    
-    let mut elements = reader_pass3.par_map_reduce(
-        |element| {
+    let simplify_tolerance_m = config.runtime.simplify_tolerance_m;
+    let relation_types: HashSet<&str> = config.filters.relation_types.iter().map(|s| s.as_str()).collect();
+    let progress_interval_secs = config.runtime.progress_interval_secs;
+    let scanned = AtomicUsize::new(0);
+    let label = format!("pass3 {}", file_label(pbf_path));
+
+    let elements = with_progress(progress_interval_secs, &label, "elements", &scanned, || input.par_map_reduce_raw(
+        |element, strings| {
             let mut local_elements = Vec::new();
             let mut local_skips = 0;
+            if progress_interval_secs > 0 {
+                scanned.fetch_add(1, Ordering::Relaxed);
+            }
 
             match element {
                 OsmElement::Node(node) => {
-                    let mut extracted_tags = Vec::new();
-                    let mut has_primary = false;
-                    for (k, v) in node.tags() {
-                        if primary_keys_set.contains(k) {
-                            has_primary = true;
-                            let kid = interner.get_or_intern(k);
-                            let vid = interner.get_or_intern(v);
-                            extracted_tags.push((kid, vid));
-                        } else if attribute_keys_set.contains(k) {
-                            let kid = interner.get_or_intern(k);
-                            let vid = interner.get_or_intern(v);
-                            extracted_tags.push((kid, vid));
-                        }
-                    }
-
-                    if has_primary {
+                    let tags = tag_filter.decode(node.raw_tags(), node.raw_stringtable());
+                    let extracted = tag_filter.extract(tags.iter().map(|(k, v)| (k.as_ref(), v.as_ref())), interner)
+                        .filter(|_| clip.is_none_or(|c| c.contains([node.lat() as f32, node.lon() as f32])));
+                    let coord = extracted.as_ref().and_then(|_| coord_checks.store(node.lat(), node.lon()));
+                    matches.node(coord.is_some());
+                    if let (Some(extracted_tags), Some((lat, lon))) = (extracted, coord) {
                         // Concurrent-friendly tag-set interning (reduced contention)
-                        let tag_set_id = get_tag_set_id(extracted_tags);
+                        let tag_set_id = tag_sets.get_or_intern(extracted_tags);
 
                         local_elements.push(Element {
                             id: node.id() as u64,
-                            coordinates: [[node.lat() as f32, node.lon() as f32], [node.lat() as f32, node.lon() as f32]],
+                            coordinates: [[lat, lon], [lat, lon]],
                             tag_set_id,
                         });
                     }
                 }
                 OsmElement::DenseNode(node) => {
-                    let mut extracted_tags = Vec::new();
-                    let mut has_primary = false;
-                    for (k, v) in node.tags() {
-                        if primary_keys_set.contains(k) {
-                            has_primary = true;
-                            let kid = interner.get_or_intern(k);
-                            let vid = interner.get_or_intern(v);
-                            extracted_tags.push((kid, vid));
-                        } else if attribute_keys_set.contains(k) {
-                            let kid = interner.get_or_intern(k);
-                            let vid = interner.get_or_intern(v);
-                            extracted_tags.push((kid, vid));
-                        }
-                    }
-
-                    if has_primary {
+                    let tags = tag_filter.decode_dense(&node, strings);
+                    let extracted = tag_filter.extract(tags.iter().map(|(k, v)| (k.as_ref(), v.as_ref())), interner)
+                        .filter(|_| clip.is_none_or(|c| c.contains([node.lat() as f32, node.lon() as f32])));
+                    let coord = extracted.as_ref().and_then(|_| coord_checks.store(node.lat(), node.lon()));
+                    matches.node(coord.is_some());
+                    if let (Some(extracted_tags), Some((lat, lon))) = (extracted, coord) {
                         // Concurrent-friendly tag-set interning (reduced contention)
-                        let tag_set_id = get_tag_set_id(extracted_tags);
+                        let tag_set_id = tag_sets.get_or_intern(extracted_tags);
 
                         local_elements.push(Element {
                             id: node.id() as u64,
-                            coordinates: [[node.lat() as f32, node.lon() as f32], [node.lat() as f32, node.lon() as f32]],
+                            coordinates: [[lat, lon], [lat, lon]],
                             tag_set_id,
                         });
                     }
                 }
                 OsmElement::Way(way) => {
-                    let mut extracted_tags = Vec::new();
-                    let mut has_primary = false;
-                    for (k, v) in way.tags() {
-                        if primary_keys_set.contains(k) {
-                            has_primary = true;
-                            let kid = interner.get_or_intern(k);
-                            let vid = interner.get_or_intern(v);
-                            extracted_tags.push((kid, vid));
-                        } else if attribute_keys_set.contains(k) {
-                            let kid = interner.get_or_intern(k);
-                            let vid = interner.get_or_intern(v);
-                            extracted_tags.push((kid, vid));
-                        }
+                    let tags = tag_filter.decode(way.raw_tags(), way.raw_stringtable());
+                    let extracted = tag_filter.extract(tags.iter().map(|(k, v)| (k.as_ref(), v.as_ref())), interner);
+                    if extracted.is_none() {
+                        matches.way(false);
                     }
-
-                    if has_primary {
+                    if let Some(extracted_tags) = extracted {
+                        let way_coords: Vec<Option<[f32; 2]>> = way.refs()
+                            .map(|node_id| node_coords.get(node_id as u64).map(|(lat, lon)| [lat, lon]))
+                            .collect();
+                        if let Some(clip) = clip {
+                            if !way_coords.iter().flatten().any(|&p| clip.contains(p)) {
+                                matches.way(false);
+                                return local_elements;
+                            }
+                        }
+                        matches.way(true);
                         // Concurrent-friendly tag-set interning (reduced contention)
-                        let tag_set_id = get_tag_set_id(extracted_tags);
+                        let tag_set_id = tag_sets.get_or_intern(extracted_tags);
 
-                        let way_nodes: Vec<_> = way.refs().collect();
+                        local_skips += way_coords.windows(2).filter(|w| w[0].is_none() || w[1].is_none()).count();
+
+                        // Emit segments for each run of consecutive nodes with known coordinates,
+                        // optionally simplified (Douglas-Peucker) to reduce the segment count
                         let mut segments_added = 0;
-                        for i in 0..way_nodes.len().saturating_sub(1) {
-                            if let (Some(c1), Some(c2)) = 
-                                (node_coords.get(&(way_nodes[i] as u64)), node_coords.get(&(way_nodes[i+1] as u64))) 
-                            {
-                                let (lat1, lon1) = *c1;
-                                let (lat2, lon2) = *c2;
+                        for run in way_coords.split(|c| c.is_none()) {
+                            let run: Vec<[f32; 2]> = run.iter().flatten().copied().collect();
+                            if run.len() < 2 {
+                                continue;
+                            }
+                            let run = if simplify_tolerance_m > 0.0 {
+                                crate::geometry::simplify(&run, simplify_tolerance_m)
+                            } else {
+                                run
+                            };
+                            for w in run.windows(2) {
                                 local_elements.push(Element {
                                     id: way.id() as u64,
-                                    coordinates: [[lat1, lon1], [lat2, lon2]],
+                                    coordinates: [w[0], w[1]],
                                     tag_set_id,
                                 });
                                 segments_added += 1;
-                            } else {
-                                local_skips += 1;
                             }
                         }
-                        if segments_added == 0 && !way_nodes.is_empty() {
+                        if segments_added == 0 && !way_coords.is_empty() {
                             // This is a warning sign - we have a tagged way but couldn't find its nodes
                             // Often happens if the PBF is an extract that doesn't include "uninteresting" nodes
                             // but those nodes are still needed for way geometry.
@@ -335,7 +876,19 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
                         }
                     }
                 }
-                _ => {}
+                OsmElement::Relation(relation) => {
+                    let tags = tag_filter.decode(relation.raw_tags(), relation.raw_stringtable());
+                    if tags.iter().any(|(k, v)| k == "type" && relation_types.contains(v.as_ref())) {
+                        // relations are rare: keep all of their tags, and their way members
+                        let tags = tags.iter().map(|(k, v)| (interner.get_or_intern(k), interner.get_or_intern(v))).collect();
+                        let tag_set_id = tag_sets.get_or_intern(tags);
+                        let members = relation.members()
+                            .filter(|m| matches!(m.member_type, osmpbf::RelMemberType::Way))
+                            .map(|m| (m.member_id as u64, interner.get_or_intern(m.role().unwrap_or(""))))
+                            .collect();
+                        relations.lock().push((relation.id() as u64, tag_set_id, members));
+                    }
+                }
             }
             if local_skips > 0 {
                 segments_skipped.fetch_add(local_skips, Ordering::Relaxed);
@@ -347,26 +900,383 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
             a.append(&mut b);
             a
         },
-    ).map_err(|e| anyhow::anyhow!("PBF Error: {:?}", e))?;
+    ))?;
+
+    Ok((elements, segments_skipped.load(Ordering::Relaxed)))
+}
+
+/// Drop elements whose tag set has fewer than `min_tag_count` pairs, then re-index the
+/// remaining tag sets so no unused sets are kept in the cache.
+fn drop_sparse_elements(elements: &mut Vec<Element>, tag_sets: &mut crate::model::FlatTagSets, relations: &mut RelationTable, min_tag_count: usize) {
+    let before = elements.len();
+    elements.retain(|e| tag_sets.lengths.get(e.tag_set_id as usize).is_some_and(|&len| len as usize >= min_tag_count));
+
+    let mut used = vec![false; tag_sets.offsets.len()];
+    for e in elements.iter() {
+        used[e.tag_set_id as usize] = true;
+    }
+    // relation tag sets are not subject to the threshold
+    for &id in &relations.tag_set_ids {
+        used[id as usize] = true;
+    }
+
+    let mut remap = vec![u32::MAX; used.len()];
+    let mut compacted = crate::model::FlatTagSets::default();
+    for (old_id, &is_used) in used.iter().enumerate() {
+        if !is_used {
+            continue;
+        }
+        remap[old_id] = compacted.offsets.len() as u32;
+        let pairs = tag_sets.get(old_id).unwrap_or(&[]);
+        compacted.offsets.push(compacted.data.len() as u32);
+        compacted.lengths.push(pairs.len() as u32);
+        compacted.data.extend_from_slice(pairs);
+    }
+    for e in elements.iter_mut() {
+        e.tag_set_id = remap[e.tag_set_id as usize];
+    }
+    for id in relations.tag_set_ids.iter_mut() {
+        *id = remap[*id as usize];
+    }
+
+    info!("min_tag_count={}: dropped {} elements and {} tag sets", min_tag_count, before - elements.len(), tag_sets.offsets.len() - compacted.offsets.len());
+    *tag_sets = compacted;
+}
+
+/// `[storage] deterministic`: put elements, strings and tag sets into a canonical order so the same
+/// inputs and config always produce a byte-identical cache. The parallel passes otherwise emit
+/// elements, and hand out string and tag-set ids, in scheduling order.
+fn canonicalize(elements: &mut [Element], tag_sets: &mut crate::model::FlatTagSets, interner: &mut StringInterner, relations: &mut RelationTable) {
+    // Stable sort by id only: a way's segments must stay in order for `WayIndex` to stitch them.
+    // Nodes and ways sharing an id are told apart by the node's degenerate segment.
+    elements.par_sort_by_key(|e| (e.id, e.coordinates[0] != e.coordinates[1]));
+
+    let (sorted, string_remap) = interner.sorted();
+    *interner = sorted;
+    let remap_pair = |packed: u64| {
+        let (key_id, value_id) = unpack_kv(packed);
+        pack_kv(string_remap[key_id as usize], string_remap[value_id as usize])
+    };
+    for role in relations.roles.iter_mut() {
+        *role = string_remap[*role as usize];
+    }
+
+    // tag sets in first-use order (elements, then relations); unused ids are dropped
+    let mut remap = vec![u32::MAX; tag_sets.offsets.len()];
+    let mut canonical = crate::model::FlatTagSets::default();
+    for id in elements.iter_mut().map(|e| &mut e.tag_set_id).chain(relations.tag_set_ids.iter_mut()) {
+        let old_id = *id as usize;
+        if remap[old_id] == u32::MAX {
+            remap[old_id] = canonical.offsets.len() as u32;
+            let pairs = tag_sets.get(old_id).unwrap_or(&[]);
+            canonical.offsets.push(canonical.data.len() as u32);
+            canonical.lengths.push(pairs.len() as u32);
+            canonical.data.extend(pairs.iter().map(|&p| remap_pair(p)));
+        }
+        *id = remap[old_id];
+    }
+    *tag_sets = canonical;
+}
+
+/// `[storage] bbox_index`: regroup elements by the grid cell of each run's first point, where a
+/// run is a node or a way's consecutive segments (kept together and in order for `WayIndex`).
+/// Stable, so runs keep their relative order within a cell. Briefly holds a second copy of the
+/// elements. Returns `(row, col, first, count)` per cell in storage order.
+fn sort_by_grid_cell(elements: &mut Vec<Element>, cell_deg: f64) -> Vec<(i32, i32, usize, usize)> {
+    let cell_of = |p: [f32; 2]| ((p[0] as f64 / cell_deg).floor() as i32, (p[1] as f64 / cell_deg).floor() as i32);
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for i in 1..=elements.len() {
+        if i == elements.len() || elements[i].id != elements[start].id {
+            runs.push((cell_of(elements[start].coordinates[0]), start, i));
+            start = i;
+        }
+    }
+    runs.sort_by_key(|&(cell, _, _)| cell);
+
+    let mut sorted = Vec::with_capacity(elements.len());
+    let mut cells: Vec<(i32, i32, usize, usize)> = Vec::new();
+    for ((row, col), start, end) in runs {
+        if cells.last().is_none_or(|c| (c.0, c.1) != (row, col)) {
+            cells.push((row, col, sorted.len(), 0));
+        }
+        sorted.extend_from_slice(&elements[start..end]);
+        if let Some(cell) = cells.last_mut() {
+            cell.3 += end - start;
+        }
+    }
+    *elements = sorted;
+    cells
+}
+
+/// Write `bbox_index.bin` for a cache just written. Byte ranges follow bincode's layout of
+/// `CacheData`: the element list comes first (after an empty `elements` list with `compact_ids`).
+fn write_bbox_index(config: &Config, cache_data: &CacheData, grid_cells: &[(i32, i32, usize, usize)]) -> Result<()> {
+    let (base, element_size) = match cache_data.compact_elements.first() {
+        // length prefixes of the empty `elements` and of `compact_elements`
+        Some(first) => (16, bincode::serialized_size(first)?),
+        None => (8, cache_data.elements.first().map_or(Ok(0), bincode::serialized_size)?),
+    };
+    let cells = grid_cells.iter().map(|&(row, col, first, count)| crate::model::BBoxCell {
+        row,
+        col,
+        first: first as u64,
+        count: count as u64,
+        byte_start: base + first as u64 * element_size,
+        byte_end: base + (first + count) as u64 * element_size,
+    }).collect();
+    let index = crate::model::BBoxIndex { cell_deg: config.storage.bbox_index_cell_deg, source_hash: cache_data.source_hash, cells };
+    let path = config.storage.cache_dir.join("bbox_index.bin");
+    let file = File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+    bincode::serialize_into(BufWriter::new(file), &index)?;
+    info!("bbox index written to {:?} ({} cells)", path, index.cells.len());
+    Ok(())
+}
+
+/// Elements, tag sets, interner, relations and per-element H3 cells of a preprocessing run
+type Preprocessed = (Vec<Element>, crate::model::FlatTagSets, StringInterner, RelationTable, Vec<u64>);
+
+/// Run preprocessing inside a dedicated rayon pool so the passes' `par_map_reduce` work does not
+/// share (or depend on) the global pool used by the rest of the process.
+fn preprocess(config: &Config, pbf_paths: &[PathBuf], source_hash: u64, cache_file: &Path, report_path: Option<&Path>) -> Result<Preprocessed> {
+    let num_threads = match config.runtime.preprocess_threads {
+        0 => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(16),
+        n => n,
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(|i| format!("preprocess-{}", i))
+        .build()
+        .context("Failed to build preprocessing thread pool")?;
+    info!("Preprocessing with a dedicated pool of {} threads", num_threads);
+    pool.install(|| preprocess_in_pool(config, pbf_paths, source_hash, cache_file, report_path))
+}
+
+/// Blocks read by `sample_keys` (cheap: only the start of the file is decoded)
+const KEY_SAMPLE_BLOCKS: usize = 8;
+/// Keys reported by `sample_keys`
+const KEY_SAMPLE_SIZE: usize = 20;
+
+/// Most frequent tag keys in the first few blocks of `pbf_path`, for diagnosing filters that
+/// match nothing. Read errors just yield an empty sample.
+fn sample_keys(pbf_path: &Path) -> Vec<String> {
+    use osmpbf::{BlobDecode, BlobReader, Element as OsmElement};
+    let Ok(reader) = BlobReader::from_path(pbf_path) else { return Vec::new() };
+    let mut counts: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    let mut count = |key: &str| *counts.entry(key.to_string()).or_default() += 1;
+    let blocks = reader.filter_map(|blob| match blob.ok()?.decode().ok()? {
+        BlobDecode::OsmData(block) => Some(block),
+        _ => None,
+    });
+    for block in blocks.take(KEY_SAMPLE_BLOCKS) {
+        for element in block.elements() {
+            match element {
+                OsmElement::Node(n) => n.tags().for_each(|(k, _)| count(k)),
+                OsmElement::DenseNode(n) => n.tags().for_each(|(k, _)| count(k)),
+                OsmElement::Way(w) => w.tags().for_each(|(k, _)| count(k)),
+                OsmElement::Relation(r) => r.tags().for_each(|(k, _)| count(k)),
+            }
+        }
+    }
+    let mut keys: Vec<(String, usize)> = counts.into_iter().collect();
+    keys.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    keys.into_iter().take(KEY_SAMPLE_SIZE).map(|(k, _)| k).collect()
+}
+
+/// Optional per-pass pool (`pass2_threads`/`pass3_threads`); `None` runs the pass in the
+/// enclosing preprocessing pool.
+fn pass_pool(threads: usize, pass: &'static str) -> Result<Option<rayon::ThreadPool>> {
+    if threads == 0 {
+        return Ok(None);
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |i| format!("{}-{}", pass, i))
+        .build()
+        .with_context(|| format!("Failed to build {} thread pool", pass))?;
+    info!("  {} runs in its own pool of {} threads", pass, threads);
+    Ok(Some(pool))
+}
+
+fn install<R: Send>(pool: Option<&rayon::ThreadPool>, f: impl FnOnce() -> R + Send) -> R {
+    match pool {
+        Some(pool) => pool.install(f),
+        None => f(),
+    }
+}
+
+fn preprocess_in_pool(config: &Config, pbf_paths: &[PathBuf], source_hash: u64, cache_file: &Path, report_path: Option<&Path>) -> Result<Preprocessed> {
+    info!("Starting Optimized PBF preprocessing: {:?}", pbf_paths);
+    // Pass 1: Identify "Required" Nodes (union across all inputs so ways spanning extracts resolve)
+    info!("Pass 1: Identifying required node IDs...");
+    let t1 = std::time::Instant::now();
+    let inputs: Vec<PbfInput> = pbf_paths.iter().map(|p| PbfInput::open(config, p)).collect::<Result<_>>()?;
+    if config.storage.mmap_input {
+        info!("  Inputs are memory-mapped once and shared by all passes");
+    }
+    let mut required_nodes = RoaringTreemap::new();
+    let pass1_matches = MatchCounters::default();
+    for input in &inputs {
+        required_nodes |= collect_required_nodes(config, input, &pass1_matches)?;
+    }
+
+    info!("Identified {} unique nodes required for filtered data. (pass1: {:.2?})", required_nodes.len(), t1.elapsed());
+    let mut report = PreprocessReport { inputs: pbf_paths.iter().map(|p| p.display().to_string()).collect(), ..Default::default() };
+    report.pass("pass1", t1);
+    report.pass1_matches = pass1_matches.snapshot();
+    report.pass1_matches.log("pass1");
+
+    // Pass 2: Collect Coordinates for Required Nodes only (merged across all inputs)
+    info!("Pass 2: Collecting coordinates for {} required nodes...", required_nodes.len());
+    let t2 = std::time::Instant::now();
+    let spill_estimate_mb = required_nodes.len() * COORD_MAP_BYTES_PER_NODE / (1024 * 1024);
+    let node_coords = if config.storage.coords_spill && spill_estimate_mb >= config.storage.coords_spill_min_mb {
+        let spill_path = config.storage.cache_dir.join("coords.spill");
+        info!("  Spilling coordinates to {:?} (in-memory map estimated at {} MB)", spill_path, spill_estimate_mb);
+        NodeCoords::spilled(&spill_path, &required_nodes)?
+    } else {
+        NodeCoords::in_memory(required_nodes.len() as usize)
+    };
+    let coord_checks = CoordChecks::new(config);
+    let pass2_pool = pass_pool(config.runtime.pass2_threads, "pass2")?;
+    install(pass2_pool.as_ref(), || -> Result<()> {
+        for input in &inputs {
+            collect_coordinates(config, input, &required_nodes, &node_coords, &coord_checks)?;
+        }
+        Ok(())
+    })?;
+    drop(pass2_pool);
+    
+    // Inputs may overlap, so count distinct coordinates rather than insertions
+    let final_coords_stored = node_coords.count() as u64;
+    info!("Coordinate collection complete. Loaded {} coordinates (expected {}). (pass2: {:.2?})", final_coords_stored, required_nodes.len(), t2.elapsed());
+    report.pass("pass2", t2);
+    if final_coords_stored < required_nodes.len() {
+        info!("  WARNING: {} required nodes were NOT found in the PBF file.", required_nodes.len() - final_coords_stored);
+    }
+
+    // Compact the in-memory coordinate store into a FxHashMap to reduce memory overhead and
+    // speed reads (a spilled store is used as is)
+    let node_coords = node_coords.compact();
+
+    // Pass 3: Extract and Filter. Inputs are processed concurrently against the shared
+    // concurrent interner + tag-set map (both are lock-free-ish and safe to share).
+    info!("Pass 3: Final extraction and tag interning...");
+    let t3 = std::time::Instant::now();
+    // Use a concurrent interner during parallel processing to avoid heavy locking
+    let interner = Arc::new(ConcurrentInterner::new());
+    let tag_filter = TagFilter::new(config);
+    let tag_sets = TagSetInterner::new(config.filters.max_tag_sets);
+    let relation_records = parking_lot::Mutex::new(Vec::new());
+    let clip = crate::geometry::ClipRegions::from_filters(&config.filters)?;
+    if let Some(path) = &config.filters.clip_polygon {
+        info!("  Clipping to polygon {:?}", path);
+    }
+    if !config.filters.include_circles.is_empty() {
+        info!("  Clipping to {} include circle(s)", config.filters.include_circles.len());
+    }
+
+    let pass3_matches = MatchCounters::default();
+    let pass3_pool = pass_pool(config.runtime.pass3_threads, "pass3")?;
+    let per_file: Vec<(Vec<Element>, usize)> = install(pass3_pool.as_ref(), || inputs
+        .par_iter()
+        .map(|input| extract_elements(config, input, &tag_filter, &node_coords, &interner, &tag_sets, &coord_checks, &relation_records, clip.as_ref(), &pass3_matches))
+        .collect::<Result<Vec<_>>>())?;
+    drop(pass3_pool);
+
+    if config.runtime.free_coords_after_pass3 {
+        // otherwise these live until the end of this function, i.e. through serialization
+        let rss_before = proc_status_mb("VmRSS");
+        drop(node_coords);
+        drop(required_nodes);
+        drop(inputs);
+        if let (Some(before), Some(after)) = (rss_before, proc_status_mb("VmRSS")) {
+            info!("  free_coords_after_pass3: RSS {} MB -> {} MB", before, after);
+        }
+    }
+
+    let mut final_skips = 0;
+    let mut elements: Vec<Element> = Vec::with_capacity(per_file.iter().map(|(e, _)| e.len()).sum());
+    for (mut file_elements, skips) in per_file {
+        elements.append(&mut file_elements);
+        final_skips += skips;
+    }
 
     info!("Extraction complete. Matched {} total elements. (pass3: {:.2?})", elements.len(), t3.elapsed());
-    let final_skips = segments_skipped.load(Ordering::Relaxed);
+    report.pass("pass3", t3);
+    report.pass3_matches = pass3_matches.snapshot();
+    report.pass3_matches.log("pass3");
+    if elements.is_empty() {
+        let seen = pbf_paths.first().map(|p| sample_keys(p)).unwrap_or_default();
+        let message = format!(
+            "No element matched primary_keys {:?}; check them against the data. Most common keys in the first blocks of the input: {:?}",
+            config.filters.primary_keys, seen
+        );
+        if config.filters.error_on_empty {
+            anyhow::bail!(message);
+        }
+        warn!("{}", message);
+    }
+    let mut relations = RelationTable::from_records(relation_records.into_inner());
+    if !config.filters.relation_types.is_empty() {
+        info!("  Kept {} relations of types {:?}", relations.len(), config.filters.relation_types);
+    }
+    let counts = crate::model::DatasetCounts::from_elements(&elements);
+    report.counts = counts;
+    info!("  {} elements = {} way segments from {} distinct ways + {} distinct nodes", counts.elements, counts.segments, counts.ways, counts.nodes);
     if final_skips > 0 {
         info!("  WARNING: {} way segments were skipped due to missing node coordinates.", final_skips);
     }
+    let bad_coords = coord_checks.bad.load(Ordering::Relaxed);
+    if bad_coords > 0 {
+        if config.filters.on_bad_coords == BadCoordsPolicy::Error {
+            anyhow::bail!("{} nodes have out-of-range coordinates (on_bad_coords = \"error\")", bad_coords);
+        }
+        warn!("{} nodes had out-of-range coordinates (handled with {:?})", bad_coords, config.filters.on_bad_coords);
+    }
+    let lossy = coord_checks.lossy.load(Ordering::Relaxed);
+    if lossy > 0 {
+        warn!(lossy_coordinates = lossy, threshold_m = LOSSY_COORD_THRESHOLD_M,
+            "{} coordinates lost more than {} m of precision when stored as f32", lossy, LOSSY_COORD_THRESHOLD_M);
+    }
+    let invalid_utf8 = tag_filter.invalid_utf8.load(Ordering::Relaxed);
+    if invalid_utf8 > 0 {
+        if config.filters.on_invalid_utf8 == InvalidUtf8Policy::Error {
+            anyhow::bail!("{} tags are not valid UTF-8 (on_invalid_utf8 = \"error\")", invalid_utf8);
+        }
+        info!("  WARNING: {} tags were not valid UTF-8 (handled with {:?})", invalid_utf8, config.filters.on_invalid_utf8);
+    }
+    let duplicates = tag_filter.duplicates.load(Ordering::Relaxed);
+    if duplicates > 0 {
+        if config.filters.on_duplicate_key == DuplicateKeyPolicy::Error {
+            anyhow::bail!("{} elements carry duplicate tag keys (on_duplicate_key = \"error\")", duplicates);
+        }
+        info!("  WARNING: {} elements carried duplicate tag keys (kept {:?} occurrence)", duplicates, config.filters.on_duplicate_key);
+    }
+    let overflowed = tag_sets.overflowed.load(Ordering::Relaxed);
+    if overflowed > 0 {
+        if config.filters.on_tag_set_overflow == TagSetOverflowPolicy::Error {
+            anyhow::bail!("tag set cap max_tag_sets={} exceeded (on_tag_set_overflow = \"error\")", config.filters.max_tag_sets);
+        }
+        info!("  WARNING: {} elements were assigned the shared misc tag set (max_tag_sets={})", overflowed, config.filters.max_tag_sets);
+    }
+    let truncated = tag_filter.truncated.load(Ordering::Relaxed);
+    if truncated > 0 {
+        info!("  {} elements had their tags truncated to max_tags_per_element={}", truncated, config.filters.max_tags_per_element);
+    }
 
     // Materialize final tag-sets into a flattened, compact representation
-    let tag_set_count = tag_set_counter.load(Ordering::Relaxed) as usize;
+    let tag_set_count = tag_sets.counter.load(Ordering::Relaxed) as usize;
     let mut flat_data: Vec<u64> = Vec::new();
     let mut offsets: Vec<u32> = Vec::with_capacity(tag_set_count);
     let mut lengths: Vec<u32> = Vec::with_capacity(tag_set_count);
 
     for i in 0..(tag_set_count as u32) {
         offsets.push(flat_data.len() as u32);
-        if let Some(v) = tag_set_reverse.get(&i) {
+        if let Some(v) = tag_sets.reverse.get(&i) {
             lengths.push(v.len() as u32);
             for (k, val) in v.iter() {
-                flat_data.push(((*k as u64) << 32) | (*val as u64));
+                flat_data.push(pack_kv(*k, *val));
             }
         } else {
             lengths.push(0);
@@ -374,6 +1284,9 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
     }
 
     let mut final_tag_sets = crate::model::FlatTagSets { data: flat_data, offsets, lengths };
+    if config.filters.min_tag_count > 0 {
+        drop_sparse_elements(&mut elements, &mut final_tag_sets, &mut relations, config.filters.min_tag_count);
+    }
     info!("Total unique tag sets: {}", final_tag_sets.offsets.len());
 
     // Reduce memory before serializing
@@ -383,11 +1296,26 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
     final_tag_sets.lengths.shrink_to_fit();
 
     // Convert concurrent interner into the serializable `StringInterner`
-    let final_interner = match Arc::try_unwrap(interner) {
+    let mut final_interner = match Arc::try_unwrap(interner) {
         Ok(ci) => ci.into_string_interner(),
         Err(ci_arc) => ci_arc.to_string_interner(),
     };
 
+    if config.storage.deterministic {
+        let t_sort = std::time::Instant::now();
+        canonicalize(&mut elements, &mut final_tag_sets, &mut final_interner, &mut relations);
+        info!("  deterministic: canonical element/string/tag-set order ({:.2?})", t_sort.elapsed());
+    }
+    let grid_cells = if config.storage.bbox_index {
+        anyhow::ensure!(config.storage.bbox_index_cell_deg > 0.0, "bbox_index_cell_deg must be positive");
+        let t_sort = std::time::Instant::now();
+        let cells = sort_by_grid_cell(&mut elements, config.storage.bbox_index_cell_deg);
+        info!("  bbox_index: elements grouped into {} cells of {}° ({:.2?})", cells.len(), config.storage.bbox_index_cell_deg, t_sort.elapsed());
+        cells
+    } else {
+        Vec::new()
+    };
+
     // Save to cache (move values into the cache object to avoid cloning large vectors)
     info!("Saving optimized cache to disk (zstd compressed)...");
     let t_cache = std::time::Instant::now();
@@ -395,10 +1323,35 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
     let writer = BufWriter::new(file);
     let mut encoder = zstd::stream::write::Encoder::new(writer, config.storage.zstd_level as i32)?; // configurable zstd level
 
+    let h3_cells = match config.runtime.h3_resolution {
+        Some(resolution) => {
+            info!("  Computing H3 cells at resolution {}", resolution);
+            h3_cells(&elements, resolution)?
+        }
+        None => Vec::new(),
+    };
+
+    let (elements, compact_elements, id_table) = match config.runtime.compact_ids.then(|| crate::model::compact_elements(&elements)).flatten() {
+        Some((compact, id_table)) => {
+            info!("  compact_ids: {} distinct ids remapped to u32", id_table.len());
+            (Vec::new(), compact, id_table)
+        }
+        None => {
+            if config.runtime.compact_ids {
+                warn!("compact_ids: too many distinct ids for u32, storing full ids");
+            }
+            (elements, Vec::new(), Vec::new())
+        }
+    };
+
     let mut cache_data = CacheData {
         elements,
+        compact_elements,
+        id_table,
         tag_sets: final_tag_sets,
         interner: final_interner,
+        relations,
+        h3_cells,
         source_hash,
     };
 
@@ -408,11 +1361,37 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
 
 
     info!("Cache saved successfully. (serialize: {:.2?})", t_cache.elapsed());
+    report.pass("serialize", t_cache);
+
+    if config.storage.bbox_index {
+        write_bbox_index(config, &cache_data, &grid_cells)?;
+    }
+
+    if let Some(path) = report_path {
+        report.tag_sets = cache_data.tag_sets.offsets.len();
+        report.strings = cache_data.interner.offsets.read().len();
+        let file = File::create(path).with_context(|| format!("Failed to create report file: {:?}", path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &report)?;
+        info!("Preprocessing report written to {:?}", path);
+    }
 
     // Take the values back out to return them (no extra cloning)
-    let elements = std::mem::take(&mut cache_data.elements);
+    let elements = if cache_data.compact_elements.is_empty() {
+        std::mem::take(&mut cache_data.elements)
+    } else {
+        crate::model::expand_elements(&std::mem::take(&mut cache_data.compact_elements), &cache_data.id_table)
+    };
     let tag_sets = std::mem::take(&mut cache_data.tag_sets);
     let interner = std::mem::take(&mut cache_data.interner);
+    let relations = std::mem::take(&mut cache_data.relations);
+    let h3_cells = std::mem::take(&mut cache_data.h3_cells);
+
+    if let Some(dir) = &config.storage.emit_parquet {
+        let t_parquet = std::time::Instant::now();
+        crate::tag_export::export_elements(&elements, dir)?;
+        crate::tag_export::export_tags(&interner, &tag_sets, dir, crate::tag_export::ExportFormat::Parquet)?;
+        info!("Parquet written to {:?} ({:.2?})", dir, t_parquet.elapsed());
+    }
 
     if config.runtime.drop_interner_map {
         // free the interner HashMap keys (these duplicate the `pool` contents and are not
@@ -420,8 +1399,283 @@ fn preprocess(config: &Config, pbf_path: &Path, source_hash: u64, cache_file: &P
         interner.map.write().clear();
     }
 
-    Ok((elements, tag_sets, interner))
+    Ok((elements, tag_sets, interner, relations, h3_cells))
+}
+
+/// H3 cell of each element's midpoint (a node's own position), in element order.
+pub(crate) fn h3_cells(elements: &[Element], resolution: u8) -> Result<Vec<u64>> {
+    let resolution = h3o::Resolution::try_from(resolution)
+        .with_context(|| format!("Invalid h3_resolution {} (expected 0-15)", resolution))?;
+    Ok(elements.par_iter().map(|e| {
+        let [p1, p2] = e.coordinates;
+        let (lat, lon) = ((p1[0] as f64 + p2[0] as f64) / 2.0, (p1[1] as f64 + p2[1] as f64) / 2.0);
+        // coordinates come from the PBF and are always finite; 0 marks an unusable one
+        h3o::LatLng::new(lat, lon).map_or(0, |ll| u64::from(ll.to_cell(resolution)))
+    }).collect())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(filters: &str) -> Config {
+        toml::from_str(&format!(
+            "[filters]\nprimary_keys = [\"highway\"]\n{}\n[storage]\ncache_dir = \"/tmp\"\n[server]\nhost = \"127.0.0.1\"\nport = 0\n",
+            filters
+        )).unwrap()
+    }
+
+    fn varint(out: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            out.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn field(out: &mut Vec<u8>, number: u64, bytes: &[u8]) {
+        varint(out, number << 3 | 2);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn packed(values: &[u64]) -> Vec<u8> {
+        let mut out = Vec::new();
+        values.iter().for_each(|&v| varint(&mut out, v));
+        out
+    }
+
+    /// A one-block PBF (raw, uncompressed blob) holding a single dense node with `keys_vals`
+    /// indexing `strings`.
+    fn dense_node_pbf(strings: &[&[u8]], keys_vals: &[u64]) -> Vec<u8> {
+        let mut table = Vec::new();
+        strings.iter().for_each(|s| field(&mut table, 1, s));
+        let mut dense = Vec::new();
+        field(&mut dense, 1, &packed(&[2])); // id 1, zigzag-encoded
+        field(&mut dense, 8, &packed(&[0]));
+        field(&mut dense, 9, &packed(&[0]));
+        field(&mut dense, 10, &packed(keys_vals));
+        let mut group = Vec::new();
+        field(&mut group, 2, &dense);
+        let mut block = Vec::new();
+        field(&mut block, 1, &table);
+        field(&mut block, 2, &group);
+
+        let mut blob = Vec::new();
+        field(&mut blob, 1, &block);
+        varint(&mut blob, 2 << 3);
+        varint(&mut blob, block.len() as u64);
+        let mut header = Vec::new();
+        field(&mut header, 1, b"OSMData");
+        varint(&mut header, 3 << 3);
+        varint(&mut header, blob.len() as u64);
+
+        let mut pbf = (header.len() as u32).to_be_bytes().to_vec();
+        pbf.extend(header);
+        pbf.extend(blob);
+        pbf
+    }
+
+    fn mapped(bytes: &[u8]) -> memmap2::Mmap {
+        let mut map = memmap2::MmapMut::map_anon(bytes.len()).unwrap();
+        map.copy_from_slice(bytes);
+        map.make_read_only().unwrap()
+    }
+
+    #[test]
+    fn invalid_utf8_policy_applies_to_dense_nodes() {
+        // the invalid value comes first: `DenseNode::tags()` would stop there and lose `highway`
+        let pbf = dense_node_pbf(&[b"", b"name", b"caf\xe9", b"highway", b"bench"], &[1, 2, 3, 4, 0]);
+        let expected = |name: Option<&str>| {
+            let mut tags: Vec<(String, String)> = name.map(|v| ("name".to_string(), v.to_string())).into_iter().collect();
+            tags.push(("highway".to_string(), "bench".to_string()));
+            tags
+        };
+        for (policy, tags) in [("skip", expected(None)), ("replace", expected(Some("caf\u{FFFD}"))), ("error", expected(None))] {
+            let config = test_config(&format!("on_invalid_utf8 = \"{}\"", policy));
+            let filter = TagFilter::new(&config);
+            let input = PbfInput { path: Path::new("dense.osm.pbf"), map: Some(mapped(&pbf)) };
+            let decoded = input.par_map_reduce_raw(
+                |element, strings| match element {
+                    osmpbf::Element::DenseNode(node) => filter.decode_dense(&node, strings).into_iter()
+                        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                        .collect(),
+                    _ => Vec::new(),
+                },
+                Vec::new,
+                |mut a, mut b| {
+                    a.append(&mut b);
+                    a
+                },
+            ).unwrap();
+            assert_eq!(decoded, tags, "on_invalid_utf8 = {}", policy);
+            assert_eq!(filter.invalid_utf8.load(Ordering::Relaxed), 1, "on_invalid_utf8 = {}", policy);
+        }
+    }
+
+    #[test]
+    fn data_missing_only_without_cache_and_staged_input() {
+        let dir = std::env::temp_dir().join(format!("overpass-minimal-data-missing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = test_config("");
+        config.storage.cache_dir = dir.clone();
+        let unstaged = dir.join("unstaged.osm.pbf");
+        assert!(data_missing(&config, std::slice::from_ref(&unstaged)));
+        assert!(!data_missing(&config, &[PathBuf::from("-")]));
+        assert!(!data_missing(&config, &[PathBuf::from("https://example.com/extract.osm.pbf")]));
+        std::fs::write(dir.join("data.bin.zst"), b"").unwrap();
+        assert!(!data_missing(&config, &[unstaged]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// `TagFilter::extract` on `tags`, resolved back to strings.
+    fn extract(filter: &TagFilter, tags: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        let interner = StringInterner::default();
+        let extracted = filter.extract(tags.iter().copied(), &interner)?;
+        Some(extracted.into_iter().map(|(k, v)| (interner.lookup(k).unwrap(), interner.lookup(v).unwrap())).collect())
+    }
+
+    fn pairs(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn alias_next_to_canonical_key_is_not_a_duplicate() {
+        let config = test_config("attribute_keys = [\"name\"]\non_duplicate_key = \"error\"\n[filters.key_aliases]\n\"name:en\" = \"name\"\n\"name:int\" = \"name\"");
+        let filter = TagFilter::new(&config);
+        // the canonical key wins wherever it appears
+        for tags in [[("name:en", "Main Street"), ("name", "Hauptstraße")], [("name", "Hauptstraße"), ("name:en", "Main Street")]] {
+            let extracted = extract(&filter, &[&[("highway", "residential")][..], &tags[..]].concat()).unwrap();
+            assert_eq!(extracted, pairs(&[("highway", "residential"), ("name", "Hauptstraße")]));
+        }
+        // two aliases without the canonical key: the first one
+        let extracted = extract(&filter, &[("highway", "residential"), ("name:int", "Main"), ("name:en", "Main Street")]).unwrap();
+        assert_eq!(extracted, pairs(&[("highway", "residential"), ("name", "Main")]));
+        assert_eq!(filter.duplicates.load(Ordering::Relaxed), 0);
+
+        extract(&filter, &[("highway", "residential"), ("name", "a"), ("name", "b")]).unwrap();
+        assert_eq!(filter.duplicates.load(Ordering::Relaxed), 1);
+    }
 
+    #[test]
+    fn extract_matches_aliases_and_wildcard() {
+        let config = test_config("attribute_keys = [\"phone\"]\n[filters.key_aliases]\n\"contact:phone\" = \"phone\"\nroad = \"highway\"");
+        let filter = TagFilter::new(&config);
+        assert_eq!(extract(&filter, &[("name", "x"), ("phone", "1")]), None);
+        // an aliased primary key qualifies the element and is stored under its canonical name
+        assert_eq!(extract(&filter, &[("road", "residential"), ("contact:phone", "123"), ("name", "x")]), Some(pairs(&[("highway", "residential"), ("phone", "123")])));
 
+        let config = test_config("attribute_keys = [\"*\"]");
+        let filter = TagFilter::new(&config);
+        let tags = [("highway", "residential"), ("name", "x"), ("surface", "asphalt")];
+        assert_eq!(extract(&filter, &tags), Some(pairs(&tags)));
+        assert_eq!(extract(&filter, &[("name", "x")]), None);
+    }
+
+    #[test]
+    fn truncation_keeps_primaries_first() {
+        let config = test_config("attribute_keys = [\"*\"]\nmax_tags_per_element = 2");
+        let filter = TagFilter::new(&config);
+        let extracted = extract(&filter, &[("name", "x"), ("surface", "asphalt"), ("highway", "residential"), ("lanes", "2")]);
+        assert_eq!(extracted, Some(pairs(&[("highway", "residential"), ("name", "x")])));
+        assert_eq!(extract(&filter, &[("highway", "residential"), ("name", "x")]), Some(pairs(&[("highway", "residential"), ("name", "x")])));
+        assert_eq!(filter.truncated.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn duplicate_key_policies() {
+        let tags = [("highway", "residential"), ("name", "first"), ("name", "last")];
+        for (policy, name) in [("first", "first"), ("last", "last"), ("error", "last")] {
+            let config = test_config(&format!("attribute_keys = [\"name\"]\non_duplicate_key = \"{}\"", policy));
+            let filter = TagFilter::new(&config);
+            assert_eq!(extract(&filter, &tags), Some(pairs(&[("highway", "residential"), ("name", name)])), "{}", policy);
+            assert_eq!(filter.duplicates.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[test]
+    fn bad_coord_policies() {
+        for policy in ["skip", "clamp", "error"] {
+            let checks = CoordChecks::new(&test_config(&format!("on_bad_coords = \"{}\"", policy)));
+            assert_eq!(checks.store(52.5, 13.25), Some((52.5, 13.25)));
+            assert_eq!(checks.store(-90.0, 180.0), Some((-90.0, 180.0)));
+            let clamped = checks.store(91.0, -181.0);
+            assert_eq!(clamped, (policy == "clamp").then_some((90.0, -180.0)), "{}", policy);
+            assert_eq!(checks.store(f64::NAN, 0.0), None, "{}", policy);
+            assert_eq!(checks.bad.load(Ordering::Relaxed), 2, "{}", policy);
+        }
+    }
+
+    /// `FlatTagSets` over the given pair lists.
+    fn flat_tag_sets(sets: &[&[u64]]) -> crate::model::FlatTagSets {
+        let mut tag_sets = crate::model::FlatTagSets::default();
+        for pairs in sets {
+            tag_sets.offsets.push(tag_sets.data.len() as u32);
+            tag_sets.lengths.push(pairs.len() as u32);
+            tag_sets.data.extend_from_slice(pairs);
+        }
+        tag_sets
+    }
+
+    #[test]
+    fn drop_sparse_elements_reindexes_tag_sets() {
+        let element = |id: u64, tag_set_id: u32| Element { id, coordinates: [[0.0, 0.0], [0.0, 0.0]], tag_set_id };
+        let mut elements = vec![element(1, 0), element(2, 1), element(3, 2), element(4, 1)];
+        let mut tag_sets = flat_tag_sets(&[&[pack_kv(0, 1)], &[pack_kv(0, 2), pack_kv(3, 4)], &[pack_kv(0, 5), pack_kv(3, 6)], &[pack_kv(7, 8)]]);
+        // relation tag sets are kept even below the threshold
+        let mut relations = RelationTable { tag_set_ids: vec![3], ..RelationTable::default() };
+        drop_sparse_elements(&mut elements, &mut tag_sets, &mut relations, 2);
+
+        assert_eq!(elements.iter().map(|e| (e.id, e.tag_set_id)).collect::<Vec<_>>(), [(2, 0), (3, 1), (4, 0)]);
+        assert_eq!(tag_sets.offsets.len(), 3);
+        assert_eq!(tag_sets.get(0), Some(&[pack_kv(0, 2), pack_kv(3, 4)][..]));
+        assert_eq!(tag_sets.get(1), Some(&[pack_kv(0, 5), pack_kv(3, 6)][..]));
+        assert_eq!(relations.tag_set_ids, [2]);
+        assert_eq!(tag_sets.get(2), Some(&[pack_kv(7, 8)][..]));
+    }
+
+    /// `(id, segment, tags)` of one extracted element
+    type Extracted<'a> = (u64, [[f32; 2]; 2], &'a [(&'a str, &'a str)]);
+
+    /// Elements, tag sets and strings as they would come out of pass 3, with ids handed out in
+    /// `order`
+    fn scheduled_output(order: &[Extracted]) -> (Vec<Element>, crate::model::FlatTagSets, StringInterner) {
+        let interner = StringInterner::default();
+        let mut sets: Vec<Vec<u64>> = Vec::new();
+        let elements = order.iter().map(|&(id, coordinates, tags)| {
+            let pairs: Vec<u64> = tags.iter().map(|&(k, v)| pack_kv(interner.get_or_intern(k), interner.get_or_intern(v))).collect();
+            let tag_set_id = sets.iter().position(|s| *s == pairs).unwrap_or_else(|| {
+                sets.push(pairs);
+                sets.len() - 1
+            }) as u32;
+            Element { id, coordinates, tag_set_id }
+        }).collect();
+        let sets: Vec<&[u64]> = sets.iter().map(Vec::as_slice).collect();
+        (elements, flat_tag_sets(&sets), interner)
+    }
+
+    #[test]
+    fn canonicalize_is_independent_of_scheduling() {
+        let cafe: &[(&str, &str)] = &[("amenity", "cafe"), ("name", "Zum Hirsch")];
+        let road: &[(&str, &str)] = &[("highway", "residential")];
+        let way = |i: f32| [[1.0 + i, 2.0], [1.0 + i + 1.0, 2.0]];
+        let node = [[5.0, 6.0], [5.0, 6.0]];
+        let a = [(7, way(0.0), road), (3, node, cafe), (7, way(1.0), road), (7, way(2.0), road)];
+        let b = [(3, node, cafe), (7, way(0.0), road), (7, way(1.0), road), (7, way(2.0), road)];
+
+        let canonical = |order: &[Extracted]| {
+            let (mut elements, mut tag_sets, mut interner) = scheduled_output(order);
+            canonicalize(&mut elements, &mut tag_sets, &mut interner, &mut RelationTable::default());
+            let strings: Vec<String> = (0..interner.offsets.read().len() as u32).map(|id| interner.lookup(id).unwrap()).collect();
+            let elements: Vec<(u64, [[f32; 2]; 2], u32)> = elements.iter().map(|e| (e.id, e.coordinates, e.tag_set_id)).collect();
+            (elements, tag_sets.data, tag_sets.offsets, strings)
+        };
+        assert_eq!(canonical(&a), canonical(&b));
+        let (elements, _, _, strings) = canonical(&a);
+        // ids ascending, a way's segments still in order
+        assert_eq!(elements.iter().map(|e| (e.0, e.1)).collect::<Vec<_>>(), [(3, node), (7, way(0.0)), (7, way(1.0)), (7, way(2.0))]);
+        let mut sorted = strings.clone();
+        sorted.sort();
+        assert_eq!(strings, sorted);
+    }
+}
@@ -0,0 +1,72 @@
+//! Minimal protobuf wire-format encoder for the schema in `proto/query.proto`.
+//! The schema is tiny and write-only, so we encode it by hand instead of pulling in a codegen step.
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+
+    pub fn uint64(&mut self, field: u32, v: u64) {
+        if v != 0 {
+            self.key(field, WIRE_VARINT);
+            self.varint(v);
+        }
+    }
+
+    pub fn double(&mut self, field: u32, v: f64) {
+        if v != 0.0 {
+            self.key(field, WIRE_FIXED64);
+            self.buf.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    pub fn bytes(&mut self, field: u32, v: &[u8]) {
+        self.key(field, WIRE_LEN);
+        self.varint(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn string(&mut self, field: u32, v: &str) {
+        self.bytes(field, v.as_bytes());
+    }
+
+    pub fn packed_uint32(&mut self, field: u32, values: &[u32]) {
+        if values.is_empty() {
+            return;
+        }
+        let mut packed = Encoder::new();
+        for &v in values {
+            packed.varint(v as u64);
+        }
+        self.bytes(field, &packed.buf);
+    }
+
+    pub fn message(&mut self, field: u32, msg: Encoder) {
+        self.bytes(field, &msg.buf);
+    }
+}
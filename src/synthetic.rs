@@ -0,0 +1,122 @@
+//! Deterministic synthetic datasets for tests and benchmarks (`generate` subcommand): random
+//! points and short ways inside a bbox with a few random tags, built straight into `CacheData`
+//! without reading any PBF.
+use crate::config::Config;
+use crate::model::{pack_kv, unpack_kv, CacheData, Element, FlatTagSets, RelationTable, StringInterner};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Values drawn for primary keys
+const PRIMARY_VALUES: [&str; 10] = ["yes", "residential", "service", "footway", "cafe", "restaurant", "bench", "park", "school", "parking"];
+/// Values drawn for attribute keys
+const ATTRIBUTE_VALUES: [&str; 6] = ["asphalt", "gravel", "yes", "no", "1", "-1"];
+/// Share of generated features that are ways (the rest are nodes)
+const WAY_SHARE: f64 = 0.3;
+/// Maximum segments per way and step length between way nodes, in degrees (~50 m)
+const MAX_WAY_SEGMENTS: usize = 5;
+const WAY_STEP_DEG: f64 = 0.0005;
+
+/// SplitMix64: tiny, seedable and stable across platforms and builds.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Generate `count` features (nodes, and ways exploded into segments like pass 3 does) inside
+/// `bbox` = `[south, west, north, east]`. Keys come from `[filters]`; the same arguments and
+/// config always produce the same dataset.
+pub fn generate(config: &Config, count: usize, bbox: &[f64], seed: u64) -> Result<CacheData> {
+    let &[south, west, north, east] = bbox else {
+        anyhow::bail!("--bbox takes south,west,north,east");
+    };
+    if bbox.iter().any(|v| !v.is_finite()) || south >= north || west >= east {
+        anyhow::bail!("--bbox must be finite with south < north and west < east");
+    }
+    let primary_keys: Vec<&str> = if config.filters.primary_keys.is_empty() {
+        vec!["amenity"]
+    } else {
+        config.filters.primary_keys.iter().map(|k| k.as_str()).collect()
+    };
+    let attribute_keys: Vec<&str> = config.filters.attribute_keys.iter().map(|k| k.as_str()).filter(|k| *k != "*").collect();
+
+    let mut rng = SplitMix64(seed);
+    let interner = StringInterner::default();
+    let mut tag_set_ids: HashMap<Vec<u64>, u32> = HashMap::new();
+    let mut tag_sets = FlatTagSets::default();
+    let mut elements = Vec::with_capacity(count * 2);
+    let (mut next_node_id, mut next_way_id) = (1u64, 1u64);
+    let pack = |k: &str, v: &str| pack_kv(interner.get_or_intern(k), interner.get_or_intern(v));
+
+    for _ in 0..count {
+        let mut pairs = vec![pack(primary_keys[rng.below(primary_keys.len())], PRIMARY_VALUES[rng.below(PRIMARY_VALUES.len())])];
+        if !attribute_keys.is_empty() {
+            for _ in 0..rng.below(3) {
+                let pair = pack(attribute_keys[rng.below(attribute_keys.len())], ATTRIBUTE_VALUES[rng.below(ATTRIBUTE_VALUES.len())]);
+                // one value per key, like the extraction enforces
+                if !pairs.iter().any(|&p| unpack_kv(p).0 == unpack_kv(pair).0) {
+                    pairs.push(pair);
+                }
+            }
+        }
+        let tag_set_id = *tag_set_ids.entry(pairs).or_insert_with_key(|pairs| {
+            tag_sets.offsets.push(tag_sets.data.len() as u32);
+            tag_sets.lengths.push(pairs.len() as u32);
+            tag_sets.data.extend_from_slice(pairs);
+            tag_sets.offsets.len() as u32 - 1
+        });
+
+        let mut point = [south + rng.unit() * (north - south), west + rng.unit() * (east - west)];
+        if rng.unit() >= WAY_SHARE {
+            let p = [point[0] as f32, point[1] as f32];
+            elements.push(Element { id: next_node_id, coordinates: [p, p], tag_set_id });
+            next_node_id += 1;
+            continue;
+        }
+        for _ in 0..=rng.below(MAX_WAY_SEGMENTS) {
+            let next = [
+                (point[0] + (rng.unit() * 2.0 - 1.0) * WAY_STEP_DEG).clamp(south, north),
+                (point[1] + (rng.unit() * 2.0 - 1.0) * WAY_STEP_DEG).clamp(west, east),
+            ];
+            let coordinates = [[point[0] as f32, point[1] as f32], [next[0] as f32, next[1] as f32]];
+            elements.push(Element { id: next_way_id, coordinates, tag_set_id });
+            point = next;
+        }
+        next_way_id += 1;
+    }
+
+    let h3_cells = match config.runtime.h3_resolution {
+        Some(resolution) => crate::preprocessor::h3_cells(&elements, resolution)?,
+        None => Vec::new(),
+    };
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    ("synthetic", count, seed, &primary_keys, &attribute_keys).hash(&mut hasher);
+    bbox.iter().for_each(|v| v.to_bits().hash(&mut hasher));
+
+    Ok(CacheData {
+        elements,
+        compact_elements: Vec::new(),
+        id_table: Vec::new(),
+        tag_sets,
+        interner,
+        relations: RelationTable::default(),
+        h3_cells,
+        source_hash: hasher.finish(),
+    })
+}
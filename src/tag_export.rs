@@ -0,0 +1,159 @@
+use crate::model::{unpack_kv, FlatTagSets, StringInterner};
+use anyhow::{Context, Result};
+use crate::model::Element;
+use parquet::data_type::{ByteArray, ByteArrayType, FloatType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+/// Write the string table (`id, string`) and the tag-set table (`tag_set_id, key_id, value_id`)
+/// into `out_dir` as `strings.<ext>` and `tag_sets.<ext>`, without any geometry.
+pub fn export_tags(interner: &StringInterner, tag_sets: &FlatTagSets, out_dir: &Path, format: ExportFormat) -> Result<()> {
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create export directory: {:?}", out_dir))?;
+
+    let string_count = interner.offsets.read().len() as u32;
+    let strings: Vec<(u32, String)> = (0..string_count)
+        .map(|id| (id, interner.lookup(id).unwrap_or_default()))
+        .collect();
+    let mut rows: Vec<[u32; 3]> = Vec::with_capacity(tag_sets.data.len());
+    for set_id in 0..tag_sets.offsets.len() {
+        for &packed in tag_sets.get(set_id).unwrap_or(&[]) {
+            let (key_id, value_id) = unpack_kv(packed);
+            rows.push([set_id as u32, key_id, value_id]);
+        }
+    }
+
+    match format {
+        ExportFormat::Csv => {
+            write_strings_csv(&out_dir.join("strings.csv"), &strings)?;
+            write_tag_sets_csv(&out_dir.join("tag_sets.csv"), &rows)?;
+        }
+        ExportFormat::Parquet => {
+            write_strings_parquet(&out_dir.join("strings.parquet"), &strings)?;
+            write_tag_sets_parquet(&out_dir.join("tag_sets.parquet"), &rows)?;
+        }
+    }
+    info!("Exported {} strings and {} tag pairs ({} tag sets) to {:?}", strings.len(), rows.len(), tag_sets.offsets.len(), out_dir);
+    Ok(())
+}
+
+/// Rows per Parquet row group in `elements.parquet`
+const ELEMENT_ROW_GROUP: usize = 1 << 20;
+
+/// Write `elements.parquet` (`id, lat1, lon1, lat2, lon2, type, tag_set_id`) into `out_dir`;
+/// `tag_set_id` joins against `tag_sets.parquet` from `export_tags`.
+pub fn export_elements(elements: &[Element], out_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create export directory: {:?}", out_dir))?;
+    let schema = Arc::new(parse_message_type(
+        "message elements { REQUIRED INT64 id (UINT_64); REQUIRED FLOAT lat1; REQUIRED FLOAT lon1; REQUIRED FLOAT lat2; \
+         REQUIRED FLOAT lon2; REQUIRED BYTE_ARRAY type (UTF8); REQUIRED INT32 tag_set_id (UINT_32); }",
+    )?);
+    let path = out_dir.join("elements.parquet");
+    let mut writer = SerializedFileWriter::new(create(&path)?, schema, Arc::new(WriterProperties::builder().build()))?;
+    for chunk in elements.chunks(ELEMENT_ROW_GROUP) {
+        let mut row_group = writer.next_row_group()?;
+        if let Some(mut col) = row_group.next_column()? {
+            let ids: Vec<i64> = chunk.iter().map(|e| e.id as i64).collect();
+            col.typed::<Int64Type>().write_batch(&ids, None, None)?;
+            col.close()?;
+        }
+        for (point, axis) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            if let Some(mut col) = row_group.next_column()? {
+                let values: Vec<f32> = chunk.iter().map(|e| e.coordinates[point][axis]).collect();
+                col.typed::<FloatType>().write_batch(&values, None, None)?;
+                col.close()?;
+            }
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let types: Vec<ByteArray> = chunk.iter()
+                .map(|e| ByteArray::from(if e.coordinates[0] == e.coordinates[1] { "node" } else { "way" }))
+                .collect();
+            col.typed::<ByteArrayType>().write_batch(&types, None, None)?;
+            col.close()?;
+        }
+        if let Some(mut col) = row_group.next_column()? {
+            let tag_set_ids: Vec<i32> = chunk.iter().map(|e| e.tag_set_id as i32).collect();
+            col.typed::<Int32Type>().write_batch(&tag_set_ids, None, None)?;
+            col.close()?;
+        }
+        row_group.close()?;
+    }
+    writer.close()?;
+    info!("Exported {} elements to {:?}", elements.len(), path);
+    Ok(())
+}
+
+fn create(path: &Path) -> Result<File> {
+    File::create(path).with_context(|| format!("Failed to create export file: {:?}", path))
+}
+
+fn write_strings_csv(path: &Path, strings: &[(u32, String)]) -> Result<()> {
+    let mut out = BufWriter::new(create(path)?);
+    writeln!(out, "id,string")?;
+    for (id, s) in strings {
+        // always quote: values may contain commas, quotes or newlines
+        writeln!(out, "{},\"{}\"", id, s.replace('"', "\"\""))?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_tag_sets_csv(path: &Path, rows: &[[u32; 3]]) -> Result<()> {
+    let mut out = BufWriter::new(create(path)?);
+    writeln!(out, "tag_set_id,key_id,value_id")?;
+    for [set_id, key_id, value_id] in rows {
+        writeln!(out, "{},{},{}", set_id, key_id, value_id)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_strings_parquet(path: &Path, strings: &[(u32, String)]) -> Result<()> {
+    let schema = Arc::new(parse_message_type("message strings { REQUIRED INT32 id (UINT_32); REQUIRED BYTE_ARRAY string (UTF8); }")?);
+    let mut writer = SerializedFileWriter::new(create(path)?, schema, Arc::new(WriterProperties::builder().build()))?;
+    let mut row_group = writer.next_row_group()?;
+    if let Some(mut col) = row_group.next_column()? {
+        let ids: Vec<i32> = strings.iter().map(|(id, _)| *id as i32).collect();
+        col.typed::<Int32Type>().write_batch(&ids, None, None)?;
+        col.close()?;
+    }
+    if let Some(mut col) = row_group.next_column()? {
+        let values: Vec<ByteArray> = strings.iter().map(|(_, s)| ByteArray::from(s.as_str())).collect();
+        col.typed::<ByteArrayType>().write_batch(&values, None, None)?;
+        col.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+fn write_tag_sets_parquet(path: &Path, rows: &[[u32; 3]]) -> Result<()> {
+    let schema = Arc::new(parse_message_type(
+        "message tag_sets { REQUIRED INT32 tag_set_id (UINT_32); REQUIRED INT32 key_id (UINT_32); REQUIRED INT32 value_id (UINT_32); }",
+    )?);
+    let mut writer = SerializedFileWriter::new(create(path)?, schema, Arc::new(WriterProperties::builder().build()))?;
+    let mut row_group = writer.next_row_group()?;
+    for column in 0..3 {
+        if let Some(mut col) = row_group.next_column()? {
+            let values: Vec<i32> = rows.iter().map(|r| r[column] as i32).collect();
+            col.typed::<Int32Type>().write_batch(&values, None, None)?;
+            col.close()?;
+        }
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}